@@ -0,0 +1,264 @@
+// Embed the git commit and build date, for `churn --version`, and write the
+// man page to `$OUT_DIR/churn.1` and the completion scripts for
+// `churn --generate-completion` to `$OUT_DIR/churn.{bash,zsh,fish}`.
+
+use std::path::Path;
+use std::process::Command;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+fn main() {
+    let sha = Command::new("git")
+        .args(["rev-parse", "--short", "HEAD"])
+        .output()
+        .ok()
+        .filter(|output| output.status.success())
+        .and_then(|output| String::from_utf8(output.stdout).ok())
+        .map(|sha| sha.trim().to_string())
+        .unwrap_or_else(|| "unknown".to_string());
+    println!("cargo:rustc-env=CHURN_GIT_SHA={}", sha);
+
+    // Reproducible builds set SOURCE_DATE_EPOCH so the date doesn't change
+    // from one build to the next.
+    let epoch = std::env::var("SOURCE_DATE_EPOCH").ok()
+        .and_then(|s| s.parse().ok())
+        .unwrap_or_else(|| {
+            SystemTime::now().duration_since(UNIX_EPOCH).map(|d| d.as_secs()).unwrap_or(0)
+        });
+    println!("cargo:rustc-env=CHURN_BUILD_DATE={}", format_date(epoch));
+
+    let usage = std::fs::read_to_string("src/usage.txt").unwrap();
+    let out_dir = std::env::var("OUT_DIR").unwrap();
+    std::fs::write(Path::new(&out_dir).join("churn.1"),
+                   man_page(&usage, &format_date(epoch))).unwrap();
+    let options = usage_options(&usage);
+    std::fs::write(Path::new(&out_dir).join("churn.bash"), bash_completion(&options)).unwrap();
+    std::fs::write(Path::new(&out_dir).join("churn.zsh"), zsh_completion(&options)).unwrap();
+    std::fs::write(Path::new(&out_dir).join("churn.fish"), fish_completion(&options)).unwrap();
+
+    println!("cargo:rerun-if-env-changed=SOURCE_DATE_EPOCH");
+    println!("cargo:rerun-if-changed=.git/HEAD");
+    println!("cargo:rerun-if-changed=.git/refs");
+    println!("cargo:rerun-if-changed=src/usage.txt");
+    println!("cargo:rerun-if-changed=build.rs");
+}
+
+const DESCRIPTION: &str = "\
+churn counts how many different versions of each file exist in the history
+of a git repository, and lists the files in HEAD with the most versions
+first. Files that change all the time are often the ones most in need of
+attention.
+
+Defaults for any option can be set in a .churn.toml file at the top of the
+repository, in ~/.config/churn/config.toml, or in environment variables
+named after the option, like CHURN_MIN_CHURN.";
+
+const EXAMPLES: &[(&str, &str)] = &[
+    ("churn", "List every file in the current repository, most churned first."),
+    ("churn --since 2024-01-01 --min-churn 5",
+     "Only count changes made this year, and leave out files changed fewer than five times."),
+    ("churn --group-by-extension ~/src/project",
+     "Total churn per file extension in another repository."),
+    ("churn --format pairs | sort -k2 -n",
+     "Tab-separated output for scripts."),
+];
+
+/// Write a man page from the docopt usage text, which is the one place all
+/// the options are described.
+fn man_page(usage: &str, date: &str) -> String {
+    let version = std::env::var("CARGO_PKG_VERSION").unwrap();
+    let mut page = format!(".TH CHURN 1 {} \"churn {}\"\n", date, version);
+    page += ".SH NAME\nchurn \\- count how many versions exist of each file in a git repository\n";
+
+    let (synopsis, options) = usage.split_at(usage.find("Options:").unwrap());
+    page += ".SH SYNOPSIS\n.nf\n";
+    for line in synopsis.trim().lines() {
+        let line = line.trim().trim_start_matches("usage:").trim().replace("gitlog", "churn");
+        page += &format!("{}\n", roff_escape(&line));
+    }
+    page += ".fi\n";
+
+    page += ".SH DESCRIPTION\n";
+    for paragraph in DESCRIPTION.split("\n\n") {
+        page += &format!(".PP\n{}\n", roff_escape(paragraph));
+    }
+
+    page += ".SH OPTIONS\n";
+    for line in options.lines().skip(1) {
+        if line.trim().is_empty() {
+            continue;
+        }
+        if line.starts_with("    -") {
+            // The option and its description are separated by two spaces.
+            let line = line.trim();
+            let (spec, description) = match line.find("  ") {
+                Some(i) => (&line[..i], line[i..].trim()),
+                None => (line, ""),
+            };
+            page += &format!(".TP\n.B {}\n", roff_escape(spec));
+            if !description.is_empty() {
+                page += &format!("{}\n", roff_escape(description));
+            }
+        } else {
+            page += &format!("{}\n", roff_escape(line.trim()));
+        }
+    }
+
+    page += ".SH EXAMPLES\n";
+    for &(command, explanation) in EXAMPLES {
+        page += &format!(".TP\n.B {}\n{}\n", roff_escape(command), roff_escape(explanation));
+    }
+    page
+}
+
+/// An option from the usage text, for the completion scripts.
+struct UsageOption {
+    /// Like `-h` or `--help`.
+    names: Vec<String>,
+    /// The name of its argument, like `ref` for `--ref <ref>`, if it takes one.
+    value: Option<String>,
+    /// Whether it can be given more than once, like `[--grep <pattern>]...`.
+    repeated: bool,
+    /// The description up to the first `;`, on one line.
+    summary: String,
+}
+
+/// Read the options from the docopt usage text, the same lines the man page
+/// is made from.
+fn usage_options(usage: &str) -> Vec<UsageOption> {
+    let (synopsis, options) = usage.split_at(usage.find("Options:").unwrap());
+    let mut parsed: Vec<UsageOption> = vec![];
+    for line in options.lines().skip(1) {
+        if !line.starts_with("    -") {
+            if let Some(option) = parsed.last_mut() {
+                option.summary += " ";
+                option.summary += line.trim();
+            }
+            continue;
+        }
+        let line = line.trim();
+        let (spec, description) = match line.find("  ") {
+            Some(i) => (&line[..i], line[i..].trim()),
+            None => (line, ""),
+        };
+        let mut names = vec![];
+        let mut value = None;
+        for word in spec.split([',', ' ', '=']).filter(|w| !w.is_empty()) {
+            if word.starts_with('<') {
+                value = Some(word.trim_matches(|c| c == '<' || c == '>').to_string());
+            } else {
+                names.push(word.to_string());
+            }
+        }
+        let repeated = names.iter().any(|name| {
+            synopsis.match_indices(&format!("[{} ", name)).any(|(i, _)| {
+                let end = i + synopsis[i..].find(']').unwrap() + 1;
+                synopsis[end..].starts_with("...")
+            })
+        });
+        parsed.push(UsageOption { names, value, repeated, summary: description.to_string() });
+    }
+    for option in &mut parsed {
+        let summary = option.summary.split(';').next().unwrap();
+        let summary = summary.split("[default:").next().unwrap();
+        option.summary = summary.split_whitespace().collect::<Vec<_>>().join(" ");
+    }
+    parsed
+}
+
+/// Complete option names, file names after an option that takes a value,
+/// and directories anywhere else.
+fn bash_completion(options: &[UsageOption]) -> String {
+    let names = |with_value_only: bool| {
+        options.iter()
+            .filter(|option| !with_value_only || option.value.is_some())
+            .flat_map(|option| option.names.iter().map(String::as_str))
+            .collect::<Vec<_>>()
+    };
+    format!("\
+_churn() {{
+    local cur=\"${{COMP_WORDS[COMP_CWORD]}}\" prev=\"${{COMP_WORDS[COMP_CWORD-1]}}\"
+    case \"$prev\" in
+        {})
+            COMPREPLY=($(compgen -f -- \"$cur\"))
+            return
+            ;;
+    esac
+    if [[ \"$cur\" == -* ]]; then
+        COMPREPLY=($(compgen -W \"{}\" -- \"$cur\"))
+    else
+        COMPREPLY=($(compgen -d -- \"$cur\"))
+    fi
+}}
+complete -F _churn churn
+", names(true).join("|"), names(false).join(" "))
+}
+
+/// The same for zsh, where the descriptions are shown next to the options.
+fn zsh_completion(options: &[UsageOption]) -> String {
+    let mut script = "#compdef churn\n\n_arguments \\\n".to_string();
+    for option in options {
+        // Square brackets and colons mean something in an _arguments spec.
+        let summary = option.summary.replace('\'', "'\\''").replace('[', "\\[")
+            .replace(']', "\\]").replace(':', "\\:");
+        let value = option.value.as_ref().map_or(String::new(), |value| {
+            format!(":{}:_files", value)
+        });
+        let repeated = if option.repeated { "*" } else { "" };
+        for name in &option.names {
+            script += &format!("  '{}{}[{}]{}' \\\n", repeated, name, summary, value);
+        }
+    }
+    script += "  '*:directory:_directories'\n";
+    script
+}
+
+/// The same for fish, which completes file names by itself.
+fn fish_completion(options: &[UsageOption]) -> String {
+    let mut script = String::new();
+    for option in options {
+        script += "complete -c churn";
+        for name in &option.names {
+            match name.strip_prefix("--") {
+                Some(long) => script += &format!(" -l {}", long),
+                None => script += &format!(" -s {}", name.trim_start_matches('-')),
+            }
+        }
+        if option.value.is_some() {
+            script += " -r";
+        }
+        let summary = option.summary.replace('\\', "\\\\").replace('\'', "\\'");
+        script += &format!(" -d '{}'\n", summary);
+    }
+    script
+}
+
+/// Escape text for roff: backslashes and hyphens, and lines that would
+/// otherwise start with a control character.
+fn roff_escape(text: &str) -> String {
+    text.lines()
+        .map(|line| {
+            let line = line.replace('\\', "\\e").replace('-', "\\-");
+            if line.starts_with('.') || line.starts_with('\'') {
+                format!("\\&{}", line)
+            } else {
+                line
+            }
+        })
+        .collect::<Vec<_>>()
+        .join("\n")
+}
+
+/// Format seconds since the epoch as `YYYY-MM-DD`, in UTC.
+fn format_date(epoch: u64) -> String {
+    // Howard Hinnant's civil_from_days.
+    let z = (epoch / 86400) as i64 + 719468;
+    let era = z.div_euclid(146097);
+    let doe = z - era * 146097;
+    let yoe = (doe - doe / 1460 + doe / 36524 - doe / 146096) / 365;
+    let doy = doe - (365 * yoe + yoe / 4 - yoe / 100);
+    let mp = (5 * doy + 2) / 153;
+    let day = doy - (153 * mp + 2) / 5 + 1;
+    let month = if mp < 10 { mp + 3 } else { mp - 9 };
+    let year = yoe + era * 400 + if month <= 2 { 1 } else { 0 };
+    format!("{:04}-{:02}-{:02}", year, month, day)
+}
@@ -0,0 +1,94 @@
+// `--show-file-type`: sort files into kinds like source, test and doc by
+// their paths.
+
+use churn::pattern::glob_match;
+use std::path::Path;
+
+/// What a file is if no rule matches it.
+const DEFAULT_TYPE: &str = "source";
+
+/// The built-in rules. `generated` comes first, since generated files can
+/// look like anything else.
+const BUILT_IN: &[(&str, &[&str])] = &[
+    ("generated", &["**/generated/**", "**/*.generated.*"]),
+    ("test", &["**/*_test.rs", "**/tests/**", "**/spec/**"]),
+    ("doc", &["**/README*", "**/docs/**", "**/*.md"]),
+    // Only at the top of the repository; JSON and YAML deeper down are as
+    // likely to be data.
+    ("config", &["Cargo.toml", "*.json", "*.yaml"]),
+];
+
+/// Rules for telling what type of file a path is: a type name and the glob
+/// patterns for it, tried in order. The first type with a matching pattern
+/// wins.
+#[derive(Clone, Debug)]
+pub struct FileTypes {
+    rules: Vec<(String, Vec<String>)>,
+}
+
+impl Default for FileTypes {
+    fn default() -> FileTypes {
+        let rules = BUILT_IN.iter()
+            .map(|&(name, patterns)| {
+                (name.to_string(), patterns.iter().map(|p| p.to_string()).collect())
+            })
+            .collect();
+        FileTypes { rules }
+    }
+}
+
+impl FileTypes {
+    /// Read rules from the TOML file at `path`, which has a `[[types]]`
+    /// table for each type, like
+    ///
+    /// ```toml
+    /// [[types]]
+    /// name = "test"
+    /// patterns = ["**/*_test.rs", "test/**"]
+    /// ```
+    ///
+    /// These are tried first, in order, and replace the built-in rules for
+    /// types of the same name. Built-in types the file doesn't mention are
+    /// still tried after them.
+    pub fn load(path: &Path) -> Result<FileTypes, String> {
+        let text = std::fs::read_to_string(path)
+            .map_err(|e| format!("can't read {}: {}", path.display(), e))?;
+        let config = text.parse::<toml::Value>()
+            .map_err(|e| format!("{}: {}", path.display(), e))?;
+        let types = match config.get("types").map(|types| types.as_array()) {
+            Some(Some(types)) => types,
+            _ => return Err(format!("{}: expected [[types]] tables", path.display())),
+        };
+        let mut rules: Vec<(String, Vec<String>)> = vec![];
+        for table in types {
+            let name = match table.get("name").and_then(|name| name.as_str()) {
+                Some(name) => name.to_string(),
+                None => return Err(format!("{}: each type needs a `name`", path.display())),
+            };
+            let patterns: Option<Vec<String>> = table.get("patterns")
+                .and_then(|patterns| patterns.as_array())
+                .and_then(|patterns| {
+                    patterns.iter().map(|p| p.as_str().map(|p| p.to_string())).collect()
+                });
+            match patterns {
+                Some(patterns) => rules.push((name, patterns)),
+                None => return Err(format!("{}: `patterns` for type '{}' should be a list of \
+                                            strings", path.display(), name)),
+            }
+        }
+        for (name, patterns) in FileTypes::default().rules {
+            if !rules.iter().any(|(other, _)| *other == name) {
+                rules.push((name, patterns));
+            }
+        }
+        Ok(FileTypes { rules })
+    }
+
+    /// The type of the file at `path`, relative to the top of the
+    /// repository.
+    pub fn classify(&self, path: &str) -> &str {
+        self.rules.iter()
+            .find(|(_, patterns)| patterns.iter().any(|p| glob_match(p, path)))
+            .map_or(DEFAULT_TYPE, |(name, _)| name.as_str())
+    }
+}
@@ -0,0 +1,167 @@
+// `--format png`: a heatmap of when the most churned files changed, with a
+// row per file and a column per week or month.
+
+use churn::FileResult;
+use image::{Rgb, RgbImage};
+use std::path::Path;
+use super::SECONDS_PER_WEEK;
+
+/// How many files get a row, most churned first.
+const ROWS: usize = 50;
+
+/// Size of each cell, in pixels.
+const CELL: u32 = 12;
+
+/// Glyphs are 3 by 5 pixels, drawn this many times bigger, with a pixel
+/// column between characters.
+const SCALE: u32 = 2;
+const CHAR_WIDTH: u32 = 4 * SCALE;
+const CHAR_HEIGHT: u32 = 5 * SCALE;
+
+/// Paths longer than this many characters are cut down from the front.
+const LABEL_CHARS: usize = 32;
+
+/// Space around the grid: labels on the left, and dates underneath.
+const MARGIN: u32 = 8;
+const LEFT: u32 = MARGIN + LABEL_CHARS as u32 * CHAR_WIDTH + MARGIN;
+const BOTTOM: u32 = MARGIN + CHAR_HEIGHT + MARGIN;
+
+/// Columns get a date label this often, so the labels don't overlap.
+const DATE_EVERY: usize = 10;
+
+const SECONDS_PER_MONTH: i64 = 30 * 86400;
+
+/// History longer than this gets a column per month instead of per week.
+const MAX_WEEKS: i64 = 52;
+
+/// Draw the heatmap for `all_files`, using their `version_times`, and save it
+/// as a PNG file at `path`.
+pub fn write(all_files: &[FileResult], now: i64, path: &Path) -> Result<(), String> {
+    let files = &all_files[..all_files.len().min(ROWS)];
+    let start = files.iter().flat_map(|file| file.version_times.iter().cloned()).min()
+        .unwrap_or(now);
+    let bucket = if (now - start) / SECONDS_PER_WEEK < MAX_WEEKS {
+        SECONDS_PER_WEEK
+    } else {
+        SECONDS_PER_MONTH
+    };
+    let columns = ((now - start) / bucket + 1) as usize;
+
+    let mut counts = vec![vec![0usize; columns]; files.len()];
+    for (row, file) in counts.iter_mut().zip(files) {
+        for &time in &file.version_times {
+            let column = ((time - start).max(0) / bucket) as usize;
+            row[column.min(columns - 1)] += 1;
+        }
+    }
+    let max = counts.iter().flat_map(|row| row.iter().cloned()).max().unwrap_or(0).max(1);
+
+    let width = LEFT + columns as u32 * CELL + MARGIN;
+    let height = MARGIN + files.len() as u32 * CELL + BOTTOM;
+    let mut image = RgbImage::from_pixel(width, height, Rgb([255, 255, 255]));
+    for (i, (file, row)) in files.iter().zip(&counts).enumerate() {
+        let y = MARGIN + i as u32 * CELL;
+        draw_text(&mut image, MARGIN, y + (CELL - CHAR_HEIGHT) / 2, &label(&file.path));
+        for (j, &count) in row.iter().enumerate() {
+            let fade = 255 - (count * 255 / max) as u8;
+            // Cells are a pixel smaller than their spacing, so the grid shows.
+            fill(&mut image, LEFT + j as u32 * CELL, y, CELL - 1, CELL - 1,
+                 Rgb([255, fade, fade]));
+        }
+    }
+    let y = MARGIN + files.len() as u32 * CELL + MARGIN;
+    for j in (0..columns).step_by(DATE_EVERY) {
+        let date = churn::format_datetime(start + j as i64 * bucket);
+        draw_text(&mut image, LEFT + j as u32 * CELL, y, &date[..10]);
+    }
+
+    image.save_with_format(path, image::ImageFormat::Png)
+        .map_err(|e| format!("can't write {}: {}", path.display(), e))
+}
+
+/// `path`, cut down to `LABEL_CHARS` characters if need be, keeping the end.
+fn label(path: &str) -> String {
+    let chars: Vec<char> = path.chars().collect();
+    if chars.len() <= LABEL_CHARS {
+        return path.to_string();
+    }
+    let tail: String = chars[chars.len() - (LABEL_CHARS - 2)..].iter().collect();
+    format!("..{}", tail)
+}
+
+fn fill(image: &mut RgbImage, x: u32, y: u32, width: u32, height: u32, color: Rgb<u8>) {
+    for dy in 0..height {
+        for dx in 0..width {
+            image.put_pixel(x + dx, y + dy, color);
+        }
+    }
+}
+
+/// Draw `text` in black with its top left corner at `(x, y)`.
+fn draw_text(image: &mut RgbImage, x: u32, y: u32, text: &str) {
+    let black = Rgb([0, 0, 0]);
+    for (i, c) in text.chars().enumerate() {
+        let left = x + i as u32 * CHAR_WIDTH;
+        if left + CHAR_WIDTH > image.width() {
+            break;
+        }
+        for (row, bits) in glyph(c).iter().enumerate() {
+            for column in 0..3 {
+                if bits & (0b100 >> column) != 0 {
+                    fill(image, left + column * SCALE, y + row as u32 * SCALE, SCALE, SCALE,
+                         black);
+                }
+            }
+        }
+    }
+}
+
+/// A 3 by 5 pixel picture of `c`, a row at a time from the top, with the
+/// leftmost pixel in the highest bit. Letters are all drawn as capitals, and
+/// anything there's no glyph for as `?`.
+fn glyph(c: char) -> [u8; 5] {
+    match c.to_ascii_lowercase() {
+        '0' => [0b111, 0b101, 0b101, 0b101, 0b111],
+        '1' => [0b010, 0b110, 0b010, 0b010, 0b111],
+        '2' => [0b111, 0b001, 0b111, 0b100, 0b111],
+        '3' => [0b111, 0b001, 0b111, 0b001, 0b111],
+        '4' => [0b101, 0b101, 0b111, 0b001, 0b001],
+        '5' => [0b111, 0b100, 0b111, 0b001, 0b111],
+        '6' => [0b111, 0b100, 0b111, 0b101, 0b111],
+        '7' => [0b111, 0b001, 0b001, 0b001, 0b001],
+        '8' => [0b111, 0b101, 0b111, 0b101, 0b111],
+        '9' => [0b111, 0b101, 0b111, 0b001, 0b111],
+        'a' => [0b010, 0b101, 0b111, 0b101, 0b101],
+        'b' => [0b110, 0b101, 0b110, 0b101, 0b110],
+        'c' => [0b011, 0b100, 0b100, 0b100, 0b011],
+        'd' => [0b110, 0b101, 0b101, 0b101, 0b110],
+        'e' => [0b111, 0b100, 0b110, 0b100, 0b111],
+        'f' => [0b111, 0b100, 0b110, 0b100, 0b100],
+        'g' => [0b011, 0b100, 0b101, 0b101, 0b011],
+        'h' => [0b101, 0b101, 0b111, 0b101, 0b101],
+        'i' => [0b111, 0b010, 0b010, 0b010, 0b111],
+        'j' => [0b001, 0b001, 0b001, 0b101, 0b010],
+        'k' => [0b101, 0b101, 0b110, 0b101, 0b101],
+        'l' => [0b100, 0b100, 0b100, 0b100, 0b111],
+        'm' => [0b101, 0b111, 0b111, 0b101, 0b101],
+        'n' => [0b110, 0b101, 0b101, 0b101, 0b101],
+        'o' => [0b010, 0b101, 0b101, 0b101, 0b010],
+        'p' => [0b110, 0b101, 0b110, 0b100, 0b100],
+        'q' => [0b010, 0b101, 0b101, 0b110, 0b011],
+        'r' => [0b110, 0b101, 0b110, 0b101, 0b101],
+        's' => [0b011, 0b100, 0b010, 0b001, 0b110],
+        't' => [0b111, 0b010, 0b010, 0b010, 0b010],
+        'u' => [0b101, 0b101, 0b101, 0b101, 0b111],
+        'v' => [0b101, 0b101, 0b101, 0b101, 0b010],
+        'w' => [0b101, 0b101, 0b111, 0b111, 0b101],
+        'x' => [0b101, 0b101, 0b010, 0b101, 0b101],
+        'y' => [0b101, 0b101, 0b010, 0b010, 0b010],
+        'z' => [0b111, 0b001, 0b010, 0b100, 0b111],
+        '.' => [0b000, 0b000, 0b000, 0b000, 0b010],
+        '/' => [0b001, 0b001, 0b010, 0b100, 0b100],
+        '-' => [0b000, 0b000, 0b111, 0b000, 0b000],
+        '_' => [0b000, 0b000, 0b000, 0b000, 0b111],
+        ' ' => [0b000; 5],
+        _ => [0b111, 0b001, 0b010, 0b000, 0b010],
+    }
+}
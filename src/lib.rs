@@ -0,0 +1,2414 @@
+//! churn - Count how many versions exist of each file in a git repository.
+//!
+//! The `churn` command line tool is a thin wrapper around this library. To
+//! use it from your own code, open a repository with `git2`, pick a starting
+//! commit, and call `analyze_with_options`:
+//!
+//! ```no_run
+//! # fn main() -> Result<(), git2::Error> {
+//! let repo = git2::Repository::open(".")?;
+//! let head = repo.revparse_single("HEAD")?.id();
+//! let options = churn::ChurnOptions::new()
+//!     .author("Jane")
+//!     .since("2023-01-01")
+//!     .min_churn(5)
+//!     .build()?;
+//! for file in churn::analyze_with_options(&repo, head, &options)? {
+//!     println!("{:7} {}", file.churn_count, file.path);
+//! }
+//! # Ok(())
+//! # }
+//! ```
+//!
+//! Apart from `open_repository` and `open_git_dir`, everything here takes a
+//! `Repository` rather than a path, so it doesn't matter how that was
+//! opened: bare, from a worktree, or over a custom object database backend.
+//! Besides objects and refs, the analysis reads:
+//!
+//! - `core.useReplaceRefs` from the repository's config, and the
+//!   `GIT_NO_REPLACE_OBJECTS` environment variable;
+//! - `info/grafts` and `shallow` in the git directory, which are fine to be
+//!   missing, and `info/sparse-checkout` with `sparse`;
+//! - the files named with `graft_file` and `ignore_commits_file`, wherever
+//!   they are.
+//!
+//! With `threads` above 1, `walk_in_parallel` also opens the repository
+//! again from `Repository::path` for each thread, and walks every commit on
+//! the calling thread if that doesn't find them.
+
+extern crate git2;
+extern crate indexmap;
+extern crate regex;
+#[cfg(feature = "tracing")]
+extern crate tracing;
+
+mod names;
+pub mod pattern;
+
+use git2::{BranchType, Repository, Commit, Error, ErrorCode, Oid, Tree, TreeEntry, ObjectType};
+use names::NameMap;
+use pattern::{CodeOwners, PatternList};
+use regex::Regex;
+use std::borrow::Cow;
+use std::collections::{HashMap, HashSet};
+use std::collections::hash_map::DefaultHasher;
+use std::fmt;
+use std::hash::Hasher;
+use std::mem;
+use std::num::NonZeroU32;
+use std::path::{Path, PathBuf};
+
+/// Get or create an IndexMap entry.
+///
+/// If the given `map` does *not* already have an entry with the given `key`,
+/// this inserts the pair `(key, f())` into the map.
+///
+/// Returns a mut reference to `map[key]`.
+///
+fn get_mut_or_create_with<'a, V, F: FnOnce()->V>(
+    map: &'a mut NameMap<V>, key: &str, f: F) -> &'a mut V
+{
+    // This path is hot, but `names::intern` only copies the name the first
+    // time anything is called that, so there's no `key.to_string()` here.
+    map.entry(names::intern(key)).or_insert_with(f)
+}
+
+/// The name of a tree entry. Git doesn't require names to be UTF-8; any that
+/// aren't get U+FFFD in place of the bad bytes.
+fn entry_name<'a>(entry: &'a TreeEntry) -> Cow<'a, str> {
+    String::from_utf8_lossy(entry.name_bytes())
+}
+
+/// Join a directory path `base` to a path `name` under it, `/`-separated
+/// whatever the platform. Both are split on `/` only: empty and `.`
+/// components are dropped, and `..` ones take off the directory before them,
+/// or nothing at the top. So `join("src/", "./a/../b")` is `src/b`, and a
+/// `/` at the start of `name` doesn't make it replace `base`.
+fn join(base: &str, name: &str) -> String {
+    // Paths built from tree entries are already clean, so this is nearly
+    // always it.
+    let clean = |path: &str| path.split('/').all(|part| !["", ".", ".."].contains(&part));
+    if (base.is_empty() || clean(base)) && clean(name) {
+        return match base {
+            "" => name.to_string(),
+            _ => base.to_string() + "/" + name
+        };
+    }
+    let mut parts: Vec<&str> = vec![];
+    for part in base.split('/').chain(name.split('/')) {
+        match part {
+            "" | "." => {}
+            ".." => {
+                parts.pop();
+            }
+            part => parts.push(part),
+        }
+    }
+    parts.join("/")
+}
+
+/// Extensions of files that are almost always binary, for
+/// `ChurnOptionsBuilder::skip_extensions`.
+pub const BINARY_EXTENSIONS: &[&str] = &[
+    "png", "jpg", "jpeg", "gif", "bmp", "ico", "webp", "tif", "tiff", "psd",
+    "pdf", "doc", "docx", "xls", "xlsx", "ppt", "pptx",
+    "exe", "dll", "so", "dylib", "a", "o", "obj", "lib", "class", "jar", "pyc", "wasm",
+    "zip", "tar", "gz", "tgz", "bz2", "xz", "7z", "rar",
+    "mp3", "mp4", "wav", "ogg", "mov", "avi",
+    "ttf", "otf", "woff", "woff2", "eot",
+    "bin", "dat", "db", "sqlite",
+];
+
+/// True if `path` is `prefix` or inside it, where `prefix` has no
+/// trailing `/`.
+fn is_under(path: &str, prefix: &str) -> bool {
+    path.starts_with(prefix) &&
+        (path.len() == prefix.len() || path.as_bytes()[prefix.len()] == b'/')
+}
+
+/// The analysis results for one file.
+#[derive(Clone)]
+pub struct FileResult {
+    /// Path of the file, relative to the top of the repository.
+    pub path: String,
+
+    /// Number of different versions of the file.
+    pub churn_count: usize,
+
+    /// Total size of all versions of the file, if we were asked to track it.
+    pub total_bytes_churned: Option<u64>,
+
+    /// Commit times (seconds since the epoch) of the first and last new
+    /// versions of the file, if we were asked to track them.
+    pub first_seen: Option<i64>,
+    pub last_seen: Option<i64>,
+
+    /// Commit time of each counted version of the file, oldest first.
+    /// Empty unless we were asked to track them.
+    pub version_times: Vec<i64>,
+
+    /// The commit that introduced each counted version of the file, oldest
+    /// first. Empty unless we were asked to track them.
+    pub version_commits: Vec<Oid>,
+
+    /// The commit that first added the file, and its author date, if we
+    /// were asked to track it.
+    pub origin: Option<(Oid, git2::Time)>,
+
+    /// True if the file is not present in the commit we started from.
+    pub deleted: bool,
+
+    /// Each author who made a counted version of the file, with how many
+    /// they made, most first. Empty unless we were asked to track authors.
+    pub authors: Vec<(String, usize)>,
+
+    /// The file this one started out as a copy of, if we were asked to
+    /// detect copies and it did.
+    pub copy_of: Option<String>,
+}
+
+/// Formats as `path, churn_count`. For anything fancier, use the fields.
+impl fmt::Display for FileResult {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}, {}", self.path, self.churn_count)
+    }
+}
+
+/// A result with just a path and a churn count, and nothing else tracked.
+impl From<(String, usize)> for FileResult {
+    fn from((path, churn_count): (String, usize)) -> FileResult {
+        FileResult {
+            path,
+            churn_count,
+            total_bytes_churned: None,
+            first_seen: None,
+            last_seen: None,
+            version_times: vec![],
+            version_commits: vec![],
+            origin: None,
+            deleted: false,
+            authors: vec![],
+            copy_of: None,
+        }
+    }
+}
+
+/// The results of an analysis: a `FileResult` per file, most churned
+/// first. It derefs to a slice, so everything slices can do works on it
+/// too, and `into_iter` hands out the results themselves.
+#[derive(Default)]
+pub struct ChurnResult(Vec<FileResult>);
+
+impl ChurnResult {
+    /// The churn of all the files added up.
+    pub fn total(&self) -> usize {
+        self.0.iter().map(|file| file.churn_count).sum()
+    }
+
+    /// The most churned file, or `None` if there are no files. Of files
+    /// with the same churn, the first one.
+    pub fn max(&self) -> Option<&FileResult> {
+        self.0.iter().fold(None, |best: Option<&FileResult>, file| match best {
+            Some(best) if best.churn_count >= file.churn_count => Some(best),
+            _ => Some(file),
+        })
+    }
+
+    /// Just the files with a churn of at least `min_churn`, in the same
+    /// order.
+    pub fn filter_min_churn(self, min_churn: usize) -> ChurnResult {
+        self.into_iter().filter(|file| file.churn_count >= min_churn).collect()
+    }
+
+    /// The results as a `Vec`, to change the list itself.
+    pub fn into_vec(self) -> Vec<FileResult> {
+        self.0
+    }
+}
+
+impl std::ops::Deref for ChurnResult {
+    type Target = [FileResult];
+
+    fn deref(&self) -> &[FileResult] {
+        &self.0
+    }
+}
+
+impl std::ops::Index<usize> for ChurnResult {
+    type Output = FileResult;
+
+    fn index(&self, i: usize) -> &FileResult {
+        &self.0[i]
+    }
+}
+
+impl IntoIterator for ChurnResult {
+    type Item = FileResult;
+    type IntoIter = std::vec::IntoIter<FileResult>;
+
+    fn into_iter(self) -> std::vec::IntoIter<FileResult> {
+        self.0.into_iter()
+    }
+}
+
+impl<'a> IntoIterator for &'a ChurnResult {
+    type Item = &'a FileResult;
+    type IntoIter = std::slice::Iter<'a, FileResult>;
+
+    fn into_iter(self) -> std::slice::Iter<'a, FileResult> {
+        self.0.iter()
+    }
+}
+
+impl std::iter::FromIterator<FileResult> for ChurnResult {
+    fn from_iter<I: IntoIterator<Item = FileResult>>(iter: I) -> ChurnResult {
+        ChurnResult(iter.into_iter().collect())
+    }
+}
+
+impl Extend<FileResult> for ChurnResult {
+    fn extend<I: IntoIterator<Item = FileResult>>(&mut self, iter: I) {
+        self.0.extend(iter)
+    }
+}
+
+impl From<Vec<FileResult>> for ChurnResult {
+    fn from(files: Vec<FileResult>) -> ChurnResult {
+        ChurnResult(files)
+    }
+}
+
+impl From<ChurnResult> for Vec<FileResult> {
+    fn from(result: ChurnResult) -> Vec<FileResult> {
+        result.0
+    }
+}
+
+/// Settings for an analysis. Make one with `ChurnOptions::new()`, which
+/// returns a builder.
+#[derive(Clone, Debug)]
+pub struct ChurnOptions {
+    author: Option<String>,
+    author_email: Option<String>,
+    author_email_exact: bool,
+    since: Option<i64>,
+    min_churn: usize,
+    min_lines: usize,
+    max_versions: Option<usize>,
+    with_size: bool,
+    ignore_whitespace: bool,
+    since_n_commits: Option<usize>,
+
+    /// Leave this many commits at the start of the walk out entirely.
+    skip_first: usize,
+
+    /// Walk the commits `git stash` made too.
+    include_stash: bool,
+
+    /// More commits to walk the history of, as well as the start.
+    extra_starts: Vec<Oid>,
+
+    /// Take at most this many commits from each start's history.
+    max_commits_per_start: Option<usize>,
+
+    /// How many threads `walk_in_parallel` can use.
+    threads: usize,
+
+    track_dates: bool,
+    track_version_times: bool,
+    track_version_commits: bool,
+    track_origin: bool,
+    include_deleted: bool,
+    sparse: bool,
+
+    /// Skip objects that can't be read instead of failing.
+    skip_errors: bool,
+
+    /// Leave out files that the starting commit's `.gitattributes` marks
+    /// as generated or vendored.
+    skip_generated: bool,
+
+    /// Treat paths that differ only in case as the same path, reported in
+    /// lowercase.
+    ignore_path_case: bool,
+
+    /// Only count commits whose message matches these, any of them or all of
+    /// them depending on `all_match`.
+    grep: Vec<Regex>,
+    all_match: bool,
+
+    /// Leave out files under these paths, which have no trailing `/`.
+    exclude: Vec<String>,
+
+    /// If not empty, only report files under these paths, which have no
+    /// trailing `/` either.
+    only_paths: Vec<String>,
+
+    /// Leave out files with these extensions, lowercase and without the
+    /// `.`.
+    skipped_extensions: HashSet<String>,
+
+    /// If given, only these files are looked at at all, and only the
+    /// directories that contain them.
+    interesting_files: Option<HashSet<String>>,
+    interesting_dirs: HashSet<String>,
+
+    /// Only report files this many directory levels deep or less, where
+    /// files at the top of the repository are at level 1.
+    max_depth: Option<usize>,
+
+    /// Carry a directory's history over when it's moved without changes.
+    detect_moves: bool,
+
+    /// Note which new files start out the same as another file.
+    detect_copies: bool,
+
+    /// Keep count of who made each version, and only report files with at
+    /// least `min_authors` different authors.
+    track_authors: bool,
+    min_authors: usize,
+
+    /// Commits whose changes aren't counted, like the history before a
+    /// release.
+    skipped: HashSet<Oid>,
+
+    /// If given, the only commits walked at all.
+    only_commits: Option<HashSet<Oid>>,
+
+    /// Commits that aren't walked at all, from `ignore_commits_file`.
+    ignored_commits: HashSet<Oid>,
+
+    /// Grafts from a file named with `graft_file`, on top of any in the
+    /// repository.
+    grafts: HashMap<Oid, Vec<Oid>>,
+}
+
+impl ChurnOptions {
+    /// Start building a set of options. The defaults analyze every commit
+    /// and report every file in the starting commit.
+    #[allow(clippy::new_ret_no_self)]
+    pub fn new() -> ChurnOptionsBuilder {
+        ChurnOptionsBuilder::default()
+    }
+
+    /// A copy of these options that doesn't count the changes made in
+    /// `commits`, which still get walked. Use `reachable_commits` to skip
+    /// everything up to some commit, for instance the last release.
+    pub fn with_skipped_commits(&self, commits: HashSet<Oid>) -> ChurnOptions {
+        ChurnOptions { skipped: commits, ..self.clone() }
+    }
+
+    /// A copy of these options that only walks `commits`, as though the
+    /// rest weren't there. Unlike skipped commits, the others' versions
+    /// aren't seen at all, so with the commits releases were tagged on,
+    /// each file's churn is how many releases had a new version of it.
+    pub fn with_only_commits(&self, commits: HashSet<Oid>) -> ChurnOptions {
+        ChurnOptions { only_commits: Some(commits), ..self.clone() }
+    }
+
+    /// True if commit `id` is walked, which it is unless it's ignored, or
+    /// there are `only_commits` and it isn't one of them.
+    fn walks(&self, id: Oid) -> bool {
+        !self.ignored_commits.contains(&id) &&
+            self.only_commits.as_ref().is_none_or(|commits| commits.contains(&id))
+    }
+
+    /// A copy of these options that walks the history of `starts` as well
+    /// as the commit the analysis starts from, as if they'd all been merged
+    /// into it, like `git log` given several branches. Files are still only
+    /// current if they're in the start commit.
+    pub fn with_extra_starts(&self, starts: Vec<Oid>) -> ChurnOptions {
+        ChurnOptions { extra_starts: starts, ..self.clone() }
+    }
+
+    /// True if `commit` is one whose changes we're counting.
+    ///
+    /// Commits that don't pass still get walked, so that the file versions
+    /// they introduce aren't credited to some later commit.
+    fn counts(&self, commit: &Commit) -> bool {
+        if self.skipped.contains(&commit.id()) {
+            return false;
+        }
+        if let Some(ref author) = self.author {
+            if !commit.author().name().unwrap_or("").contains(author.as_str()) {
+                return false;
+            }
+        }
+        if let Some(ref pattern) = self.author_email {
+            let author = commit.author();
+            let email = author.email().unwrap_or("");
+            let matched = if self.author_email_exact {
+                email == pattern
+            } else {
+                email.contains(pattern.as_str())
+            };
+            if !matched {
+                return false;
+            }
+        }
+        if let Some(since) = self.since {
+            if commit.time().seconds() < since {
+                return false;
+            }
+        }
+        if !self.grep.is_empty() {
+            let message = commit.message().unwrap_or("");
+            let matched = if self.all_match {
+                self.grep.iter().all(|re| re.is_match(message))
+            } else {
+                self.grep.iter().any(|re| re.is_match(message))
+            };
+            if !matched {
+                return false;
+            }
+        }
+        true
+    }
+
+    /// True if `path` is one of the `exclude` paths or inside one, or if
+    /// there are `only_paths` and it isn't one of them or inside one.
+    fn excludes(&self, path: &str) -> bool {
+        if self.exclude.iter().any(|prefix| is_under(path, prefix)) {
+            return true;
+        }
+        !self.only_paths.is_empty() && !self.only_paths.iter().any(|prefix| is_under(path, prefix))
+    }
+
+    /// True if the file `name` has one of the `skipped_extensions`.
+    fn skips_extension(&self, name: &str) -> bool {
+        match name.rfind('.') {
+            Some(dot) if dot > 0 && !self.skipped_extensions.is_empty() => {
+                self.skipped_extensions.contains(&name[dot + 1..].to_lowercase())
+            }
+            _ => false,
+        }
+    }
+
+    /// True if the file or directory at `path` is, or leads to, one of the
+    /// `interesting_files`, or if there's no such list.
+    fn is_interesting(&self, path: &str, is_dir: bool) -> bool {
+        match self.interesting_files {
+            Some(_) if is_dir => self.interesting_dirs.contains(path),
+            Some(ref files) => files.contains(path),
+            None => true,
+        }
+    }
+
+    /// True if we need to see each file version in the commit that
+    /// introduced it, which means walking history oldest first.
+    ///
+    /// That's a bit slower: libgit2 has to read every commit before we can
+    /// start.
+    fn needs_oldest_first(&self) -> bool {
+        self.track_dates || self.track_version_times || self.track_version_commits ||
+            self.track_origin || self.author.is_some() || self.author_email.is_some() ||
+            self.since.is_some() ||
+            !self.grep.is_empty() || self.detect_moves || self.detect_copies ||
+            !self.skipped.is_empty()
+    }
+
+    /// True if there's more than one thread to use, and `walk_in_parallel`
+    /// would get the same results as walking the commits one after another:
+    /// there's more than one start to walk from, and no option cares which
+    /// commit saw a version first or counts what more than one walk would
+    /// have counted again.
+    pub fn walks_in_parallel(&self) -> bool {
+        self.threads > 1 && (!self.extra_starts.is_empty() || self.include_stash) &&
+            !self.needs_oldest_first() &&
+            !self.with_size && !self.ignore_whitespace && !self.track_authors &&
+            !self.ignore_path_case
+    }
+}
+
+/// Builder for `ChurnOptions`.
+#[derive(Clone, Debug, Default)]
+pub struct ChurnOptionsBuilder {
+    author: Option<String>,
+    author_email: Option<String>,
+    author_email_exact: bool,
+    since: Option<String>,
+    min_churn: Option<usize>,
+    min_lines: Option<usize>,
+    max_versions: Option<usize>,
+    with_size: bool,
+    ignore_whitespace: bool,
+    since_n_commits: Option<usize>,
+    skip_first: usize,
+    include_stash: bool,
+    max_commits_per_start: Option<usize>,
+    threads: Option<usize>,
+    track_dates: bool,
+    track_version_times: bool,
+    track_version_commits: bool,
+    track_origin: bool,
+    include_deleted: bool,
+    sparse: bool,
+    skip_errors: bool,
+    skip_generated: bool,
+    ignore_path_case: bool,
+    grep: Vec<String>,
+    grep_fixed_strings: bool,
+    all_match: bool,
+    exclude: Vec<String>,
+    only_paths: Vec<String>,
+    skipped_extensions: Vec<String>,
+    interesting_files: Option<Vec<String>>,
+    max_depth: Option<usize>,
+    detect_moves: bool,
+    detect_copies: bool,
+    graft_file: Option<PathBuf>,
+    ignore_commits_file: Option<PathBuf>,
+    track_authors: bool,
+    min_authors: Option<usize>,
+}
+
+impl ChurnOptionsBuilder {
+    /// Only count changes made in commits whose author name contains `name`.
+    pub fn author(mut self, name: &str) -> ChurnOptionsBuilder {
+        self.author = Some(name.to_string());
+        self
+    }
+
+    /// Only count changes made in commits whose author email contains
+    /// `pattern`. People can use different names, but tend to keep the same
+    /// address. With `author`, a commit has to match both.
+    pub fn author_email(mut self, pattern: &str) -> ChurnOptionsBuilder {
+        self.author_email = Some(pattern.to_string());
+        self
+    }
+
+    /// Make `author_email` match only the whole address, rather than any
+    /// part of it.
+    pub fn author_email_exact(mut self, yes: bool) -> ChurnOptionsBuilder {
+        self.author_email_exact = yes;
+        self
+    }
+
+    /// Only count changes made in commits on or after `date`, which is a
+    /// `YYYY-MM-DD` date (midnight UTC).
+    pub fn since(mut self, date: &str) -> ChurnOptionsBuilder {
+        self.since = Some(date.to_string());
+        self
+    }
+
+    /// Leave out files with fewer than `n` versions.
+    pub fn min_churn(mut self, n: usize) -> ChurnOptionsBuilder {
+        self.min_churn = Some(n);
+        self
+    }
+
+    /// Leave out files with fewer than `n` lines in the starting commit.
+    /// Files that aren't in it any more are kept, having no lines to count.
+    pub fn min_lines(mut self, n: usize) -> ChurnOptionsBuilder {
+        self.min_lines = Some(n);
+        self
+    }
+
+    /// Leave out files with more than `n` versions. With 1, only files that
+    /// have never been changed since they were added are reported.
+    pub fn max_versions(mut self, n: usize) -> ChurnOptionsBuilder {
+        self.max_versions = Some(n);
+        self
+    }
+
+    /// Add up the size of every version of every file. This means reading
+    /// every blob, so it's slower.
+    pub fn with_size(mut self, yes: bool) -> ChurnOptionsBuilder {
+        self.with_size = yes;
+        self
+    }
+
+    /// Don't count versions of a file that differ only in whitespace.
+    pub fn ignore_whitespace(mut self, yes: bool) -> ChurnOptionsBuilder {
+        self.ignore_whitespace = yes;
+        self
+    }
+
+    /// Only analyze the `n` most recent commits, by commit time.
+    pub fn since_n_commits(mut self, n: usize) -> ChurnOptionsBuilder {
+        self.since_n_commits = Some(n);
+        self
+    }
+
+    /// Leave out the first `n` commits of the walk, before `since_n_commits`
+    /// takes its share, so together they give a window of history. Which
+    /// commits are first depends on the walk: with `since_n_commits` they're
+    /// the newest, and otherwise, if any option needs history walked oldest
+    /// first, the oldest. Without either, the order isn't defined.
+    pub fn skip_first(mut self, n: usize) -> ChurnOptionsBuilder {
+        self.skip_first = n;
+        self
+    }
+
+    /// Also analyze every stash entry, with the index and untracked-files
+    /// commits `git stash` makes alongside it, so work in progress counts.
+    /// Files that only exist in stashes aren't in the starting commit, so
+    /// they count as deleted.
+    pub fn include_stash(mut self, yes: bool) -> ChurnOptionsBuilder {
+        self.include_stash = yes;
+        self
+    }
+
+    /// When walking from more than one commit (see
+    /// `ChurnOptions::with_extra_starts`), take only the `n` most recent
+    /// commits of each one's history, rather than `since_n_commits` of
+    /// them all together. `skip_first` applies to each walk too. Commits
+    /// in more than one history are only counted once.
+    pub fn max_commits_per_start(mut self, n: usize) -> ChurnOptionsBuilder {
+        self.max_commits_per_start = Some(n);
+        self
+    }
+
+    /// Walk the history of up to `n` starts at once, when there's more than
+    /// one (see `ChurnOptions::walks_in_parallel`). Each thread keeps its
+    /// own record of every file version it sees, so memory use goes up with
+    /// it. Each thread opens the repository again from `Repository::path`,
+    /// so this is only worth turning on for a repository that's on disk; if
+    /// that fails, the commits are walked on the calling thread instead. The
+    /// default is 1: every commit is walked on the calling thread, one after
+    /// another.
+    pub fn threads(mut self, n: usize) -> ChurnOptionsBuilder {
+        self.threads = Some(n);
+        self
+    }
+
+    /// Record when each file was first and last changed, in
+    /// `FileResult::first_seen` and `last_seen`.
+    pub fn track_dates(mut self, yes: bool) -> ChurnOptionsBuilder {
+        self.track_dates = yes;
+        self
+    }
+
+    /// Record when each version of each file was made, in
+    /// `FileResult::version_times`.
+    pub fn track_version_times(mut self, yes: bool) -> ChurnOptionsBuilder {
+        self.track_version_times = yes;
+        self
+    }
+
+    /// Record the commit that made each version of each file, in
+    /// `FileResult::version_commits`.
+    pub fn track_version_commits(mut self, yes: bool) -> ChurnOptionsBuilder {
+        self.track_version_commits = yes;
+        self
+    }
+
+    /// Record the commit that first added each file, in
+    /// `FileResult::origin`. Commits that aren't counted still count for
+    /// this.
+    pub fn track_origin(mut self, yes: bool) -> ChurnOptionsBuilder {
+        self.track_origin = yes;
+        self
+    }
+
+    /// Include files that no longer exist in the starting commit.
+    pub fn include_deleted(mut self, yes: bool) -> ChurnOptionsBuilder {
+        self.include_deleted = yes;
+        self
+    }
+
+    /// Carry on past trees and blobs that can't be read, as in a corrupt
+    /// repository, instead of failing. They're listed in
+    /// `DirData::skipped_objects`.
+    pub fn skip_errors(mut self, yes: bool) -> ChurnOptionsBuilder {
+        self.skip_errors = yes;
+        self
+    }
+
+    /// Leave out files marked `linguist-generated` or `linguist-vendored` in
+    /// the `.gitattributes` file at the top of the starting commit, which
+    /// GitHub also leaves out of its language statistics.
+    pub fn skip_generated(mut self, yes: bool) -> ChurnOptionsBuilder {
+        self.skip_generated = yes;
+        self
+    }
+
+    /// Treat paths that differ only in case, like `src/Main.rs` and
+    /// `src/main.rs`, as one file, as a case-insensitive filesystem would.
+    /// Paths in the results are all lowercase, and the ones that were
+    /// merged are listed in `DirData::case_merges`.
+    pub fn ignore_path_case(mut self, yes: bool) -> ChurnOptionsBuilder {
+        self.ignore_path_case = yes;
+        self
+    }
+
+    /// Only report files inside the repository's sparse checkout, as given by
+    /// `.git/info/sparse-checkout`. It's an error if there isn't one.
+    pub fn sparse(mut self, yes: bool) -> ChurnOptionsBuilder {
+        self.sparse = yes;
+        self
+    }
+
+    /// Only count changes made in commits whose message matches the regex
+    /// `pattern`, like `git log --grep`. Can be given more than once; a
+    /// commit counts if it matches any of them.
+    pub fn grep(mut self, pattern: &str) -> ChurnOptionsBuilder {
+        self.grep.push(pattern.to_string());
+        self
+    }
+
+    /// Treat `grep` patterns as plain strings rather than regexes.
+    pub fn grep_fixed_strings(mut self, yes: bool) -> ChurnOptionsBuilder {
+        self.grep_fixed_strings = yes;
+        self
+    }
+
+    /// Only count a commit if its message matches every `grep` pattern.
+    pub fn all_match(mut self, yes: bool) -> ChurnOptionsBuilder {
+        self.all_match = yes;
+        self
+    }
+
+    /// Leave out the file at `path`, or everything under it if it's a
+    /// directory. Can be given more than once.
+    pub fn exclude(mut self, path: &str) -> ChurnOptionsBuilder {
+        self.exclude.push(path.trim_end_matches('/').to_string());
+        self
+    }
+
+    /// Only report the file at `path`, or the files under it if it's a
+    /// directory. Given more than once, files under any of them are
+    /// reported. History is still walked in full, so churn counts are the
+    /// same as without it.
+    pub fn only_path(mut self, path: &str) -> ChurnOptionsBuilder {
+        self.only_paths.push(path.trim_end_matches('/').to_string());
+        self
+    }
+
+    /// Leave out files with any of these extensions, like
+    /// `BINARY_EXTENSIONS`, whatever their case and with or without the
+    /// `.`. This goes by the name alone, so it's quicker than looking at
+    /// what's in each file.
+    pub fn skip_extensions<I, S>(mut self, extensions: I) -> ChurnOptionsBuilder
+        where I: IntoIterator<Item = S>, S: AsRef<str>
+    {
+        self.skipped_extensions = extensions.into_iter()
+            .map(|extension| extension.as_ref().trim_start_matches('.').to_lowercase())
+            .collect();
+        self
+    }
+
+    /// Only analyze the files at these paths, ignoring everything else
+    /// while walking history.
+    pub fn interesting_files<I, S>(mut self, paths: I) -> ChurnOptionsBuilder
+        where I: IntoIterator<Item = S>, S: AsRef<str>
+    {
+        self.interesting_files = Some(paths.into_iter()
+            .map(|path| path.as_ref().trim_start_matches("./").to_string())
+            .collect());
+        self
+    }
+
+    /// Only report files at most `n` levels deep, like `find -maxdepth`:
+    /// with 1, just the files at the top of the repository.
+    pub fn max_depth(mut self, n: usize) -> ChurnOptionsBuilder {
+        self.max_depth = Some(n);
+        self
+    }
+
+    /// When a directory is moved or renamed without any changes, treat it
+    /// as one move, so the files in it keep their churn counts, rather than
+    /// as every file being deleted and added again. Needs an oldest-first
+    /// walk.
+    pub fn detect_moves(mut self, yes: bool) -> ChurnOptionsBuilder {
+        self.detect_moves = yes;
+        self
+    }
+
+    /// Notice files that start out the same as another file's latest
+    /// version, while that file is still there, and record which one in
+    /// `FileResult::copy_of`. Needs an oldest-first walk.
+    pub fn detect_copies(mut self, yes: bool) -> ChurnOptionsBuilder {
+        self.detect_copies = yes;
+        self
+    }
+
+    /// Count how many versions of each file each author made, for
+    /// `FileResult::authors`. Authors are told apart by name.
+    pub fn track_authors(mut self, yes: bool) -> ChurnOptionsBuilder {
+        self.track_authors = yes;
+        self
+    }
+
+    /// Leave out files that fewer than `n` different people have changed.
+    /// Turns on `track_authors`.
+    pub fn min_authors(mut self, n: usize) -> ChurnOptionsBuilder {
+        self.min_authors = Some(n);
+        self
+    }
+
+    /// Read grafts from the file at `path`, in the same format as
+    /// `.git/info/grafts`, and apply them along with the repository's own.
+    /// For history whose graft file has been lost.
+    pub fn graft_file<P: AsRef<Path>>(mut self, path: P) -> ChurnOptionsBuilder {
+        self.graft_file = Some(path.as_ref().to_path_buf());
+        self
+    }
+
+    /// Leave out the commits listed in the file at `path`, one full commit
+    /// id per line, as though they weren't in the history: their trees
+    /// aren't read at all, though the history behind them is still walked.
+    /// Anything after a `#` is a comment.
+    pub fn ignore_commits_file<P: AsRef<Path>>(mut self, path: P) -> ChurnOptionsBuilder {
+        self.ignore_commits_file = Some(path.as_ref().to_path_buf());
+        self
+    }
+
+    /// Check the options and build them. Fails if the `since` date can't be
+    /// parsed, a `grep` pattern isn't a valid regex, or the graft file or
+    /// the file of commits to ignore can't be read.
+    pub fn build(self) -> Result<ChurnOptions, Error> {
+        let since = match self.since {
+            None => None,
+            Some(ref date) => match parse_date(date) {
+                Some(time) => Some(time),
+                None => return Err(Error::from_str(
+                    &format!("can't parse date '{}'; expected YYYY-MM-DD", date)))
+            }
+        };
+        let mut grep = vec![];
+        for pattern in &self.grep {
+            let re = if self.grep_fixed_strings {
+                Regex::new(&regex::escape(pattern))
+            } else {
+                Regex::new(pattern)
+            };
+            match re {
+                Ok(re) => grep.push(re),
+                Err(e) => return Err(Error::from_str(
+                    &format!("bad grep pattern '{}': {}", pattern, e)))
+            }
+        }
+        let grafts = match self.graft_file {
+            None => HashMap::new(),
+            Some(ref path) => {
+                let text = std::fs::read_to_string(path).map_err(|e| Error::from_str(
+                    &format!("can't read graft file {}: {}", path.display(), e)))?;
+                parse_grafts(&text).map_err(|e| Error::from_str(
+                    &format!("{}: {}", path.display(), e.message())))?
+            }
+        };
+        let ignored_commits = match self.ignore_commits_file {
+            None => HashSet::new(),
+            Some(ref path) => {
+                let text = std::fs::read_to_string(path).map_err(|e| Error::from_str(
+                    &format!("can't read {}: {}", path.display(), e)))?;
+                parse_commit_list(&text).map_err(|e| Error::from_str(
+                    &format!("{}: {}", path.display(), e.message())))?
+            }
+        };
+        let (mut exclude, mut interesting_files) = (self.exclude, self.interesting_files);
+        let mut only_paths = self.only_paths;
+        if self.ignore_path_case {
+            exclude = exclude.iter().map(|path| path.to_lowercase()).collect();
+            only_paths = only_paths.iter().map(|path| path.to_lowercase()).collect();
+            interesting_files = interesting_files
+                .map(|paths| paths.iter().map(|path| path.to_lowercase()).collect());
+        }
+        let mut interesting_dirs = HashSet::new();
+        for path in interesting_files.iter().flatten() {
+            let mut dir = path.as_str();
+            while let Some(i) = dir.rfind('/') {
+                dir = &dir[..i];
+                interesting_dirs.insert(dir.to_string());
+            }
+        }
+        Ok(ChurnOptions {
+            author: self.author,
+            author_email: self.author_email,
+            author_email_exact: self.author_email_exact,
+            since,
+            min_churn: self.min_churn.unwrap_or(1),
+            min_lines: self.min_lines.unwrap_or(0),
+            max_versions: self.max_versions,
+            with_size: self.with_size,
+            ignore_whitespace: self.ignore_whitespace,
+            since_n_commits: self.since_n_commits,
+            skip_first: self.skip_first,
+            include_stash: self.include_stash,
+            extra_starts: vec![],
+            max_commits_per_start: self.max_commits_per_start,
+            threads: self.threads.unwrap_or(1),
+            track_dates: self.track_dates,
+            track_version_times: self.track_version_times,
+            track_version_commits: self.track_version_commits,
+            track_origin: self.track_origin,
+            include_deleted: self.include_deleted,
+            sparse: self.sparse,
+            skip_errors: self.skip_errors,
+            skip_generated: self.skip_generated,
+            ignore_path_case: self.ignore_path_case,
+            grep,
+            all_match: self.all_match,
+            exclude,
+            only_paths,
+            skipped_extensions: self.skipped_extensions.into_iter().collect(),
+            interesting_files: interesting_files.map(|paths| paths.into_iter().collect()),
+            interesting_dirs,
+            max_depth: self.max_depth,
+            detect_moves: self.detect_moves,
+            detect_copies: self.detect_copies,
+            track_authors: self.track_authors || self.min_authors.is_some(),
+            min_authors: self.min_authors.unwrap_or(0),
+            skipped: HashSet::new(),
+            only_commits: None,
+            ignored_commits,
+            grafts,
+        })
+    }
+}
+
+/// Everything we know about the history of one file.
+struct FileStats {
+    /// Set of all blob hashes ever seen for this file.
+    hashes: HashSet<Oid>,
+
+    /// Hashes of the contents of those blobs with all whitespace removed.
+    /// Only tracked with `ignore_whitespace`, where versions that differ
+    /// only in whitespace count as one.
+    normalized_hashes: HashSet<u64>,
+
+    /// How many of those versions we counted. Versions introduced by
+    /// commits that `ChurnOptions::counts` rejects are seen but not counted.
+    versions: usize,
+
+    /// How many of the counted versions each author made. Only tracked
+    /// with `track_authors`.
+    authors: HashMap<String, usize>,
+
+    /// Commit times of the counted versions, in the order they were found.
+    /// Only tracked with `track_version_times`.
+    version_times: Vec<i64>,
+
+    /// The commits that introduced the counted versions, in the order they
+    /// were found. Only tracked with `track_version_commits`.
+    version_commits: Vec<Oid>,
+
+    /// The commit this file was first seen in, and its author date. Only
+    /// tracked with `track_origin`.
+    origin_commit: Option<(Oid, git2::Time)>,
+
+    /// Sum of the sizes of the counted versions. Only tracked with
+    /// `with_size`, since it means reading every blob.
+    total_bytes: u64,
+
+    /// Commit times of the first and last commits that introduced a counted
+    /// version of this file. These are only accurate when commits are walked
+    /// oldest first; see `ChurnOptions::needs_oldest_first`.
+    first_seen: Option<i64>,
+    last_seen: Option<i64>,
+
+    /// The newest blob seen for this file, and the file it started out as
+    /// a copy of. Only tracked with `detect_copies`.
+    latest_blob: Option<Oid>,
+    copy_of: Option<String>,
+}
+
+impl FileStats {
+    fn new() -> FileStats {
+        FileStats {
+            hashes: HashSet::new(),
+            normalized_hashes: HashSet::new(),
+            versions: 0,
+            authors: HashMap::new(),
+            version_times: Vec::new(),
+            version_commits: Vec::new(),
+            origin_commit: None,
+            total_bytes: 0,
+            first_seen: None,
+            last_seen: None,
+            latest_blob: None,
+            copy_of: None,
+        }
+    }
+
+    /// Add what `other`, another record of the same file, has seen to this
+    /// one. Versions both records counted are counted once, as far as the
+    /// blob hashes can tell; sizes and authors of those are counted twice.
+    fn merge(&mut self, other: FileStats) {
+        let versions = self.versions + other.versions;
+        self.hashes.extend(other.hashes);
+        self.normalized_hashes.extend(other.normalized_hashes);
+        self.versions = versions.min(self.hashes.len());
+        for (author, count) in other.authors {
+            *self.authors.entry(author).or_insert(0) += count;
+        }
+        self.version_times.extend(other.version_times);
+        self.version_times.sort();
+        self.version_commits.extend(other.version_commits);
+        self.origin_commit = match (self.origin_commit, other.origin_commit) {
+            (Some(a), Some(b)) => Some(if b.1.seconds() < a.1.seconds() { b } else { a }),
+            (a, b) => a.or(b),
+        };
+        self.total_bytes += other.total_bytes;
+        self.first_seen = match (self.first_seen, other.first_seen) {
+            (Some(a), Some(b)) => Some(a.min(b)),
+            (a, b) => a.or(b),
+        };
+        self.last_seen = self.last_seen.max(other.last_seen);
+        self.latest_blob = self.latest_blob.or(other.latest_blob);
+        self.copy_of = self.copy_of.take().or(other.copy_of);
+    }
+}
+
+/// Called with a file's path and new churn count for each counted version;
+/// see `DirData::update_for_commit_with`.
+type NewVersionFn<'f> = &'f mut dyn FnMut(&str, usize);
+
+/// What `DirData::update_for_tree` needs to know about the commit it's
+/// recording, and the objects it had to skip.
+struct Snapshot<'a, 'f> {
+    commit: &'a Commit<'a>,
+
+    /// New versions are only counted if this is set; see
+    /// `ChurnOptions::counts`.
+    counted: bool,
+
+    options: &'a ChurnOptions,
+
+    on_new_version: Option<NewVersionFn<'f>>,
+
+    /// Objects that couldn't be read, with `skip_errors`.
+    skipped: Vec<(Oid, ObjectType)>,
+
+    /// Paths that differ only in case from one seen before, with
+    /// `ignore_path_case`, and the one they were merged into.
+    case_merges: Vec<(String, String)>,
+
+    /// The root `DirData`'s `latest_blobs`, while its commit is recorded.
+    latest_blobs: HashMap<Oid, String>,
+}
+
+impl<'a, 'f> Snapshot<'a, 'f> {
+    /// The file that a new file at `path`, whose first blob is `sha`, is a
+    /// copy of: the one whose latest blob that is, if it's still there. If
+    /// it isn't, the file was moved rather than copied.
+    fn copy_source(&self, sha: Oid, path: &str) -> Option<String> {
+        let source = self.latest_blobs.get(&sha)?;
+        if source == path {
+            return None;
+        }
+        let tree = self.commit.tree().ok()?;
+        tree.get_path(Path::new(source)).ok().map(|_| source.clone())
+    }
+}
+
+/// Cumulative version counts for everything under one directory of a
+/// repository, including subdirectories.
+///
+/// The basic algorithm here is to create a root `DirData`, update it for every
+/// commit in the repository, then get the results out of the resulting tree of
+/// `DirData` records.
+pub struct DirData {
+    /// Set of all Git "tree" hashes ever seen for this directory.
+    ///
+    /// A Git "tree" is a snapshot of a directory. When we query Git for a
+    /// given commit, Git doesn't give us a patch telling what was changed in
+    /// that commit. Instead, it gives us a complete snapshot of *all* files
+    /// and directories in that commit, including files and directories that
+    /// did not change.
+    ///
+    /// Therefore we have to keep the set of all hashes we've seen for every
+    /// directory (this field) and every file (`DirData::files`) to avoid
+    /// overcounting directories or doing redundant work.
+    hashes: HashSet<Oid>,
+
+    /// Table of all blob hashes ever seen for each file in this directory,
+    /// keyed by the id of its name in the `names` table, as `dirs` is. Like
+    /// `dirs`, it keeps the order the names were first seen in, so the
+    /// traversal order doesn't change from one run to the next.
+    files: NameMap<FileStats>,
+
+    /// Each subdirectory that ever existed under this directory gets its own
+    /// `DirData` record.
+    dirs: NameMap<DirData>,
+
+    /// The tree hash this directory had in the last commit it was seen in.
+    /// Used to spot a directory that was moved without being changed.
+    last_tree: Option<Oid>,
+
+    /// Objects that couldn't be read and were left out, with
+    /// `ChurnOptions::skip_errors`. Only kept in the root `DirData`.
+    skipped_objects: Vec<(Oid, ObjectType)>,
+
+    /// Every spelling seen of each lowercased name in this directory, first
+    /// one first. Only kept with `ChurnOptions::ignore_path_case`.
+    spellings: HashMap<NonZeroU32, Vec<String>>,
+
+    /// Every pair of paths merged by `ignore_path_case`, as the path seen
+    /// first and the one merged into it. Only kept in the root `DirData`.
+    case_merges: Vec<(String, String)>,
+
+    /// The path of each file's latest blob, so copies of it can be spotted.
+    /// Only kept in the root `DirData`, with `ChurnOptions::detect_copies`.
+    latest_blobs: HashMap<Oid, String>,
+}
+
+impl Default for DirData {
+    fn default() -> DirData {
+        DirData::new()
+    }
+}
+
+impl DirData {
+    pub fn new() -> DirData {
+        DirData {
+            hashes: HashSet::new(),
+            files: NameMap::default(),
+            dirs: NameMap::default(),
+            last_tree: None,
+            skipped_objects: Vec::new(),
+            spellings: HashMap::new(),
+            case_merges: Vec::new(),
+            latest_blobs: HashMap::new(),
+        }
+    }
+
+    /// Get a `DirData` record for a subdirectory of this dir, creating a new
+    /// record if we don't already have one.
+    fn subdir(&mut self, name: &str) -> &mut DirData {
+        get_mut_or_create_with(&mut self.dirs, name, DirData::new)
+    }
+
+    /// Add everything `other` has seen to this record, as if the commits it
+    /// was updated for had been walked here too. Use it to combine analyses
+    /// of different ranges of the same repository, each into its own root
+    /// `DirData`.
+    pub fn merge(&mut self, other: DirData) {
+        self.hashes.extend(other.hashes);
+        for (name, stats) in other.files {
+            match self.files.get_mut(&name) {
+                Some(mine) => mine.merge(stats),
+                None => {
+                    self.files.insert(name, stats);
+                }
+            }
+        }
+        for (name, subdir) in other.dirs {
+            match self.dirs.get_mut(&name) {
+                Some(mine) => mine.merge(subdir),
+                None => {
+                    self.dirs.insert(name, subdir);
+                }
+            }
+        }
+        if self.last_tree.is_none() {
+            self.last_tree = other.last_tree;
+        }
+        self.skipped_objects.extend(other.skipped_objects);
+        for (name, spellings) in other.spellings {
+            let mine = self.spellings.entry(name).or_default();
+            for spelling in spellings {
+                if !mine.contains(&spelling) {
+                    mine.push(spelling);
+                }
+            }
+        }
+        self.case_merges.extend(other.case_merges);
+        for (sha, path) in other.latest_blobs {
+            self.latest_blobs.entry(sha).or_insert(path);
+        }
+    }
+
+    /// Call `out` with an entry for each file in this tree.
+    ///
+    /// This is like `find . -type f`: directories aren't included, but files
+    /// in subdirectories are. Each directory's files come in the order they
+    /// were first seen, followed by its subdirectories'. `depth` is how many
+    /// levels deep this directory's files are: 1 for the root.
+    fn get_all_files<F>(&self, path: &str, depth: usize, options: &ChurnOptions,
+                        sparse: Option<&PatternList>, out: &mut F)
+        where F: FnMut(FileResult)
+    {
+        #[cfg(feature = "tracing")]
+        let _span = tracing::span!(tracing::Level::TRACE, "get_all_files", path,
+                                   entry_count = self.files.len() + self.dirs.len()).entered();
+        for (&name, stats) in &self.files {
+            let full_path = join(path, names::name(name));
+            if options.excludes(&full_path) {
+                continue;
+            }
+            if let Some(patterns) = sparse {
+                if !patterns.includes(&full_path) {
+                    continue;
+                }
+            }
+            out(FileResult {
+                path: full_path,
+                churn_count: stats.versions,
+                total_bytes_churned: if options.with_size { Some(stats.total_bytes) } else { None },
+                first_seen: if options.track_dates { stats.first_seen } else { None },
+                last_seen: if options.track_dates { stats.last_seen } else { None },
+                version_times: stats.version_times.clone(),
+                version_commits: stats.version_commits.clone(),
+                origin: stats.origin_commit,
+                deleted: false,
+                authors: {
+                    let mut authors: Vec<(String, usize)> = stats.authors.iter()
+                        .map(|(name, &count)| (name.clone(), count))
+                        .collect();
+                    authors.sort_by(|a, b| b.1.cmp(&a.1).then(a.0.cmp(&b.0)));
+                    authors
+                },
+                copy_of: stats.copy_of.clone(),
+            });
+        }
+        if options.max_depth.is_some_and(|max| depth >= max) {
+            return;
+        }
+        for (&name, subdir) in &self.dirs {
+            let full_path = join(path, names::name(name));
+            subdir.get_all_files(&full_path, depth + 1, options, sparse, out);
+        }
+    }
+
+    /// Record every file version in `commit`, which should be the root
+    /// `DirData`. Returns how many new versions were counted.
+    pub fn update_for_commit(&mut self, repo: &Repository, commit: &Commit, options: &ChurnOptions)
+        -> Result<usize, Error>
+    {
+        self.update(repo, commit, options, None)
+    }
+
+    /// Like `update_for_commit`, but also call `on_new_version` with the
+    /// path and new churn count of a file each time a new version of it is
+    /// counted.
+    pub fn update_for_commit_with<F>(&mut self, repo: &Repository, commit: &Commit,
+                                     options: &ChurnOptions, mut on_new_version: F)
+        -> Result<usize, Error>
+        where F: FnMut(&str, usize)
+    {
+        self.update(repo, commit, options, Some(&mut on_new_version))
+    }
+
+    fn update(&mut self, repo: &Repository, commit: &Commit, options: &ChurnOptions,
+              on_new_version: Option<NewVersionFn>)
+        -> Result<usize, Error>
+    {
+        let tree = match commit.tree() {
+            Ok(tree) => tree,
+            Err(_) if options.skip_errors => {
+                self.skipped_objects.push((commit.tree_id(), ObjectType::Tree));
+                return Ok(0);
+            }
+            Err(e) => return Err(e),
+        };
+        if options.detect_moves {
+            self.detect_moves(repo, &tree, options)?;
+        }
+        let mut snapshot = Snapshot {
+            commit,
+            counted: options.counts(commit),
+            options,
+            on_new_version,
+            skipped: vec![],
+            case_merges: vec![],
+            latest_blobs: mem::take(&mut self.latest_blobs),
+        };
+        let result = self.update_for_tree(repo, &tree, "", &mut snapshot);
+        self.skipped_objects.append(&mut snapshot.skipped);
+        self.case_merges.append(&mut snapshot.case_merges);
+        self.latest_blobs = snapshot.latest_blobs;
+        result
+    }
+
+    /// Every pair of paths that `ChurnOptions::ignore_path_case` treated as
+    /// one so far: the spelling seen first, then the other. This should be
+    /// called on the root `DirData`.
+    pub fn case_merges(&self) -> &[(String, String)] {
+        &self.case_merges
+    }
+
+    /// `name`, an entry in this directory at `path`, in lowercase. A
+    /// spelling we haven't seen before that matches one we have is noted in
+    /// `snapshot.case_merges`.
+    fn fold_case(&mut self, name: &str, path: &str, snapshot: &mut Snapshot) -> String {
+        let folded = name.to_lowercase();
+        let spellings = self.spellings.entry(names::intern(&folded)).or_default();
+        if !spellings.iter().any(|spelling| spelling == name) {
+            if let Some(first) = spellings.first() {
+                snapshot.case_merges.push((join(path, first), join(path, name)));
+            }
+            spellings.push(name.to_string());
+        }
+        folded
+    }
+
+    /// Every object left out so far because it couldn't be read, with
+    /// `ChurnOptions::skip_errors`, in the order they were found. This
+    /// should be called on the root `DirData`.
+    pub fn skipped_objects(&self) -> &[(Oid, ObjectType)] {
+        &self.skipped_objects
+    }
+
+    /// Find directories that were moved, unchanged, to a new path in `tree`,
+    /// the root tree of the next commit. Their records move with them, so
+    /// the files inside keep their history instead of starting over. This
+    /// should be called on the root `DirData`.
+    fn detect_moves(&mut self, repo: &Repository, tree: &Tree, options: &ChurnOptions)
+        -> Result<(), Error>
+    {
+        let mut new_dirs = vec![];
+        self.find_new_dirs(repo, tree, "", options, &mut new_dirs)?;
+        for (new_path, sha) in new_dirs {
+            let mut candidates = vec![];
+            self.find_dirs_with_tree(sha, "", &mut candidates);
+            // It's only a move if the old directory is gone; otherwise it's
+            // a copy.
+            let old_path = candidates.into_iter()
+                .find(|old_path| tree.get_path(Path::new(old_path)).is_err());
+            if let Some(old_path) = old_path {
+                let dir = self.take_dir(&old_path).unwrap();
+                self.insert_dir(&new_path, dir);
+            }
+        }
+        Ok(())
+    }
+
+    /// Add the path and hash of each directory in `tree`, a snapshot of this
+    /// directory, that we've never seen before to `out`.
+    fn find_new_dirs(&self, repo: &Repository, tree: &Tree, path: &str, options: &ChurnOptions,
+                     out: &mut Vec<(String, Oid)>)
+        -> Result<(), Error>
+    {
+        let empty = DirData::new();
+        for entry in tree.iter() {
+            if entry.kind() != Some(ObjectType::Tree) {
+                continue;
+            }
+            let mut name = entry_name(&entry);
+            if options.ignore_path_case {
+                name = Cow::Owned(name.to_lowercase());
+            }
+            let sha = entry.id();
+            let full_path = join(path, &name);
+            let subdir = match names::find(&name).and_then(|id| self.dirs.get(&id)) {
+                Some(subdir) if subdir.hashes.contains(&sha) => continue,
+                Some(subdir) => subdir,
+                None => {
+                    // Something inside a new directory may have been moved
+                    // there too, so look inside it as well.
+                    out.push((full_path.clone(), sha));
+                    &empty
+                }
+            };
+            let child_object = match entry.to_object(repo) {
+                Ok(object) => object,
+                // `update_for_tree` will find it and record it as skipped.
+                Err(_) if options.skip_errors => continue,
+                Err(e) => return Err(e),
+            };
+            subdir.find_new_dirs(repo, child_object.as_tree().unwrap(), &full_path, options, out)?;
+        }
+        Ok(())
+    }
+
+    /// Add the path of each directory under this one that was last seen with
+    /// tree hash `sha` to `out`.
+    fn find_dirs_with_tree(&self, sha: Oid, path: &str, out: &mut Vec<String>) {
+        for (&name, subdir) in &self.dirs {
+            let full_path = join(path, names::name(name));
+            if subdir.last_tree == Some(sha) {
+                out.push(full_path.clone());
+            }
+            subdir.find_dirs_with_tree(sha, &full_path, out);
+        }
+    }
+
+    /// Remove the record for the directory at `path`, relative to this one.
+    fn take_dir(&mut self, path: &str) -> Option<DirData> {
+        match path.find('/') {
+            None => self.dirs.shift_remove(&names::find(path)?),
+            Some(i) => self.dirs.get_mut(&names::find(&path[..i])?)?.take_dir(&path[i + 1..]),
+        }
+    }
+
+    /// Put `dir` at `path`, relative to this directory.
+    fn insert_dir(&mut self, path: &str, dir: DirData) {
+        match path.find('/') {
+            None => {
+                self.dirs.insert(names::intern(path), dir);
+            }
+            Some(i) => self.subdir(&path[..i]).insert_dir(&path[i + 1..], dir),
+        }
+    }
+
+    /// Record everything in `tree`, which is this directory, at `path`, in
+    /// `snapshot`'s commit.
+    fn update_for_tree(&mut self, repo: &Repository, tree: &Tree, path: &str,
+                       snapshot: &mut Snapshot)
+        -> Result<usize, Error>
+    {
+        let options = snapshot.options;
+        let counted = snapshot.counted;
+        let time = snapshot.commit.time().seconds();
+        let mut new_versions = 0;
+        #[cfg(feature = "tracing")]
+        let _span = tracing::span!(tracing::Level::TRACE, "update_for_tree",
+                                   tree_oid = %tree.id(), entry_count = tree.len()).entered();
+        for entry in tree.iter() {
+            let mut name = entry_name(&entry);
+            if options.ignore_path_case {
+                name = Cow::Owned(self.fold_case(&name, path, snapshot));
+            }
+            let sha = entry.id();
+            // Skip what we weren't asked about before it takes any memory.
+            if options.interesting_files.is_some() &&
+                !options.is_interesting(&join(path, &name), entry.kind() == Some(ObjectType::Tree))
+            {
+                continue;
+            }
+            match entry.kind() {
+                Some(ObjectType::Tree) => {
+                    let subdir = self.subdir(&name);
+                    subdir.last_tree = Some(sha);
+                    if subdir.hashes.insert(sha) {
+                        let child_object = match entry.to_object(repo) {
+                            Ok(object) => object,
+                            Err(_) if options.skip_errors => {
+                                snapshot.skipped.push((sha, ObjectType::Tree));
+                                continue;
+                            }
+                            Err(e) => return Err(e),
+                        };
+                        let subtree = child_object.as_tree().unwrap();
+                        new_versions += subdir.update_for_tree(repo, subtree, &join(path, &name),
+                                                               snapshot)?;
+                    }
+                }
+                Some(ObjectType::Blob) if options.skips_extension(&name) => {}
+                Some(ObjectType::Blob) => {
+                    let stats = get_mut_or_create_with(&mut self.files, &name, FileStats::new);
+                    if stats.hashes.insert(sha) {
+                        if options.track_origin && stats.origin_commit.is_none() {
+                            let commit = snapshot.commit;
+                            stats.origin_commit = Some((commit.id(), commit.author().when()));
+                        }
+                        if options.detect_copies {
+                            let full_path = join(path, &name);
+                            if stats.hashes.len() == 1 {
+                                stats.copy_of = snapshot.copy_source(sha, &full_path);
+                            }
+                            if let Some(old) = stats.latest_blob.replace(sha) {
+                                if snapshot.latest_blobs.get(&old) == Some(&full_path) {
+                                    snapshot.latest_blobs.remove(&old);
+                                }
+                            }
+                            // A copy of a copy is still a copy of the first.
+                            snapshot.latest_blobs.entry(sha).or_insert(full_path);
+                        }
+                        let mut size = 0;
+                        if options.ignore_whitespace || (counted && options.with_size) {
+                            let blob = match repo.find_blob(sha) {
+                                Ok(blob) => blob,
+                                Err(_) if options.skip_errors => {
+                                    snapshot.skipped.push((sha, ObjectType::Blob));
+                                    continue;
+                                }
+                                Err(e) => return Err(e),
+                            };
+                            if options.ignore_whitespace &&
+                                !stats.normalized_hashes.insert(hash_ignoring_whitespace(blob.content()))
+                            {
+                                // Same as a version we've already seen.
+                                continue;
+                            }
+                            size = blob.content().len() as u64;
+                        }
+                        if counted {
+                            new_versions += 1;
+                            stats.versions += 1;
+                            stats.total_bytes += size;
+                            stats.first_seen = Some(stats.first_seen.map_or(time, |t| t.min(time)));
+                            stats.last_seen = Some(stats.last_seen.map_or(time, |t| t.max(time)));
+                            if options.track_version_times {
+                                stats.version_times.push(time);
+                            }
+                            if options.track_version_commits {
+                                stats.version_commits.push(snapshot.commit.id());
+                            }
+                            if options.track_authors {
+                                let author = snapshot.commit.author();
+                                let name = author.name().unwrap_or("").to_string();
+                                *stats.authors.entry(name).or_insert(0) += 1;
+                            }
+                            if let Some(ref mut f) = snapshot.on_new_version {
+                                f(&join(path, &name), stats.versions);
+                            }
+                        }
+                    }
+                }
+                _ => {}
+            }
+        }
+        Ok(new_versions)
+    }
+
+    /// Get the results for every file, most churned first. `start` is the
+    /// commit the analysis started from, which decides which files count as
+    /// deleted.
+    pub fn results(&self, repo: &Repository, start: Oid, options: &ChurnOptions)
+        -> Result<ChurnResult, Error>
+    {
+        let mut all_files = vec![];
+        self.each_result(repo, start, options, |file| all_files.push(file))?;
+        all_files.sort_by(|a,b| a.churn_count.cmp(&b.churn_count).reverse());
+        Ok(ChurnResult(all_files))
+    }
+
+    /// Like `results`, but hand each file to `f` as soon as it's found
+    /// instead of collecting and sorting them, so the results are never all
+    /// in memory at once. They come directory by directory, in the order
+    /// they were first seen.
+    pub fn each_result<F>(&self, repo: &Repository, start: Oid, options: &ChurnOptions, mut f: F)
+        -> Result<(), Error>
+        where F: FnMut(FileResult)
+    {
+        // Files missing from the starting commit have been deleted (or renamed
+        // away) since they were last seen.
+        let head_tree = repo.find_commit(start)?.tree()?;
+        let mut head_files = HashMap::new();
+        collect_paths(repo, &head_tree, "", &mut head_files)?;
+        if options.ignore_path_case {
+            head_files = head_files.into_iter().map(|(path, id)| (path.to_lowercase(), id)).collect();
+        }
+
+        let sparse = if options.sparse { Some(sparse_patterns(repo)?) } else { None };
+        let generated = if options.skip_generated {
+            generated_patterns(repo, &head_tree)?
+        } else {
+            vec![]
+        };
+        // The same blob can be at more than one path; only count its lines
+        // once.
+        let mut line_counts: HashMap<Oid, usize> = HashMap::new();
+        let mut error = None;
+        self.get_all_files("", 1, options, sparse.as_ref(), &mut |mut file: FileResult| {
+            let head_blob = head_files.get(&file.path).cloned();
+            file.deleted = head_blob.is_none();
+            if generated.iter().any(|patterns| patterns.match_path(&file.path, false) == Some(true)) {
+                return;
+            }
+            if !((options.include_deleted || !file.deleted) && file.churn_count >= options.min_churn &&
+                options.max_versions.is_none_or(|max| file.churn_count <= max) &&
+                file.authors.len() >= options.min_authors)
+            {
+                return;
+            }
+            if let (Some(sha), true) = (head_blob, options.min_lines > 0) {
+                if error.is_some() {
+                    return;
+                }
+                let lines = match line_counts.get(&sha) {
+                    Some(&lines) => lines,
+                    None => match repo.find_blob(sha) {
+                        Ok(blob) => *line_counts.entry(sha).or_insert(count_lines(blob.content())),
+                        Err(e) => {
+                            error = Some(e);
+                            return;
+                        }
+                    },
+                };
+                if lines < options.min_lines {
+                    return;
+                }
+            }
+            f(file);
+        });
+        match error {
+            Some(e) => Err(e),
+            None => Ok(()),
+        }
+    }
+
+    /// Get a result for each directory in commit `start`, with how many
+    /// different tree objects it has had as its churn count: every change
+    /// to anything under it makes a new one. Paths end in `/` to tell them
+    /// apart from files, and the most changed directories come first.
+    pub fn dir_results(&self, repo: &Repository, start: Oid) -> Result<ChurnResult, Error> {
+        let mut all_dirs = vec![];
+        self.get_all_dirs(repo, &repo.find_commit(start)?.tree()?, "", &mut all_dirs)?;
+        all_dirs.sort_by(|a, b| a.churn_count.cmp(&b.churn_count).reverse());
+        Ok(ChurnResult(all_dirs))
+    }
+
+    /// Add a result for every subdirectory of this directory that's still
+    /// in `tree`, its tree in the starting commit, to `out`.
+    fn get_all_dirs(&self, repo: &Repository, tree: &Tree, path: &str,
+                    out: &mut Vec<FileResult>) -> Result<(), Error> {
+        for (&name, subdir) in &self.dirs {
+            let name = names::name(name);
+            let subtree = match tree.get_name(name) {
+                Some(ref entry) if entry.kind() == Some(ObjectType::Tree) => {
+                    repo.find_tree(entry.id())?
+                }
+                _ => continue,
+            };
+            let full_path = join(path, name);
+            out.push(FileResult::from((format!("{}/", full_path), subdir.hashes.len())));
+            subdir.get_all_dirs(repo, &subtree, &full_path, out)?;
+        }
+        Ok(())
+    }
+
+    /// Find "orphan" blobs: versions of files that no file in commit `start`
+    /// uses any more. That's every old version of a file, plus everything
+    /// from files that have been deleted or renamed. Each blob comes with a
+    /// path it was seen at, sorted by path.
+    pub fn orphan_blobs(&self, repo: &Repository, start: Oid) -> Result<Vec<(Oid, String)>, Error> {
+        let mut head_files = HashMap::new();
+        collect_paths(repo, &repo.find_commit(start)?.tree()?, "", &mut head_files)?;
+        let head_blobs: HashSet<Oid> = head_files.values().cloned().collect();
+
+        let mut blobs = vec![];
+        self.get_all_blobs("", &mut blobs);
+        blobs.retain(|&(sha, _)| !head_blobs.contains(&sha));
+        blobs.sort_by(|a, b| a.1.cmp(&b.1).then(a.0.cmp(&b.0)));
+        // A blob can turn up at more than one path; list it once.
+        let mut seen = HashSet::new();
+        blobs.retain(|&(sha, _)| seen.insert(sha));
+        Ok(blobs)
+    }
+
+    /// Add every blob ever seen under this directory to `out`, with its path.
+    fn get_all_blobs(&self, path: &str, out: &mut Vec<(Oid, String)>) {
+        for (&name, stats) in &self.files {
+            let full_path = join(path, names::name(name));
+            for &sha in &stats.hashes {
+                out.push((sha, full_path.clone()));
+            }
+        }
+        for (&name, subdir) in &self.dirs {
+            subdir.get_all_blobs(&join(path, names::name(name)), out);
+        }
+    }
+
+    /// Estimate how many bytes of heap this tree of records uses: its
+    /// directory and file records and every hash in their sets. The names
+    /// are kept once for the whole process rather than in each record, and
+    /// spare capacity in the tables isn't counted, so the real figure is
+    /// higher.
+    pub fn estimated_heap_bytes(&self) -> usize {
+        let oid = mem::size_of::<Oid>();
+        let mut bytes = self.hashes.len() * oid +
+            self.skipped_objects.len() * mem::size_of::<(Oid, ObjectType)>();
+        for path in self.latest_blobs.values() {
+            bytes += mem::size_of::<(Oid, String)>() + path.len();
+        }
+        for stats in self.files.values() {
+            bytes += mem::size_of::<(NonZeroU32, FileStats)>() +
+                stats.hashes.len() * oid +
+                stats.normalized_hashes.len() * mem::size_of::<u64>() +
+                stats.version_times.len() * mem::size_of::<i64>() +
+                stats.version_commits.len() * oid;
+            for author in stats.authors.keys() {
+                bytes += mem::size_of::<(String, usize)>() + author.len();
+            }
+        }
+        for subdir in self.dirs.values() {
+            bytes += mem::size_of::<(NonZeroU32, DirData)>() + subdir.estimated_heap_bytes();
+        }
+        bytes
+    }
+
+    /// Check that this tree of records is consistent, as a debugging aid.
+    /// Call it on the root `DirData`. Returns a description of the first
+    /// problem found.
+    ///
+    /// A name can be both a file and a directory: that's what happens when a
+    /// file is replaced by a directory with the same name.
+    pub fn check_integrity(&self) -> Result<(), String> {
+        self.check_dir_integrity("")
+    }
+
+    fn check_dir_integrity(&self, path: &str) -> Result<(), String> {
+        // Every subdirectory gets its first tree hash as soon as it's
+        // created. The root directory's own hashes are never recorded.
+        if !path.is_empty() && self.hashes.is_empty() {
+            return Err(format!("directory '{}' has no tree hashes", path));
+        }
+        let entries = self.files.keys().map(|&name| (names::name(name), "file"))
+            .chain(self.dirs.keys().map(|&name| (names::name(name), "directory")));
+        for (name, kind) in entries {
+            if name.is_empty() || name.contains('/') {
+                return Err(format!("bad {} name '{}' in directory '{}'", kind, name, path));
+            }
+        }
+        for (&name, stats) in &self.files {
+            let full_path = join(path, names::name(name));
+            if stats.hashes.is_empty() {
+                return Err(format!("file '{}' has no blob hashes", full_path));
+            }
+            if stats.versions > stats.hashes.len() ||
+                stats.normalized_hashes.len() > stats.hashes.len()
+            {
+                return Err(format!("file '{}' has {} versions counted and {} ignoring \
+                                    whitespace, but only {} blob hashes",
+                                   full_path, stats.versions, stats.normalized_hashes.len(),
+                                   stats.hashes.len()));
+            }
+            if let (Some(first), Some(last)) = (stats.first_seen, stats.last_seen) {
+                if first > last {
+                    return Err(format!("file '{}' was last seen before it was first seen",
+                                       full_path));
+                }
+            }
+        }
+        for (&name, subdir) in &self.dirs {
+            subdir.check_dir_integrity(&join(path, names::name(name)))?;
+        }
+        Ok(())
+    }
+}
+
+/// How many lines `content` has, counting a last line with no newline.
+fn count_lines(content: &[u8]) -> usize {
+    let newlines = content.iter().filter(|&&c| c == b'\n').count();
+    if content.last().is_some_and(|&c| c != b'\n') {
+        newlines + 1
+    } else {
+        newlines
+    }
+}
+
+/// Hash file contents, skipping all whitespace, so that two versions that
+/// differ only in whitespace (like `git diff -w` sees it) hash the same.
+fn hash_ignoring_whitespace(content: &[u8]) -> u64 {
+    let mut hasher = DefaultHasher::new();
+    for &b in content {
+        if !b.is_ascii_whitespace() {
+            hasher.write_u8(b);
+        }
+    }
+    hasher.finish()
+}
+
+/// The text of the file at `path` in `tree`, or `None` if there's no such
+/// file.
+fn read_tree_file(repo: &Repository, tree: &Tree, path: &str) -> Result<Option<String>, Error> {
+    match tree.get_path(Path::new(path)) {
+        Ok(entry) => {
+            let object = entry.to_object(repo)?;
+            Ok(object.as_blob().map(|blob| String::from_utf8_lossy(blob.content()).into_owned()))
+        }
+        Err(ref e) if e.code() == ErrorCode::NotFound => Ok(None),
+        Err(e) => Err(e),
+    }
+}
+
+/// Read the patterns for generated and vendored files from the
+/// `.gitattributes` file at the top of `tree`, if there is one.
+fn generated_patterns(repo: &Repository, tree: &Tree) -> Result<Vec<PatternList>, Error> {
+    let text = match read_tree_file(repo, tree, ".gitattributes")? {
+        Some(text) => text,
+        None => return Ok(vec![]),
+    };
+    Ok(vec![PatternList::from_attribute(&text, "linguist-generated"),
+            PatternList::from_attribute(&text, "linguist-vendored")])
+}
+
+/// Read the repository's sparse-checkout patterns.
+fn sparse_patterns(repo: &Repository) -> Result<PatternList, Error> {
+    let path = repo.path().join("info").join("sparse-checkout");
+    match std::fs::read_to_string(&path) {
+        Ok(text) => Ok(PatternList::parse(&text)),
+        Err(ref e) if e.kind() == std::io::ErrorKind::NotFound => Err(Error::from_str(
+            "repository has no sparse checkout (no .git/info/sparse-checkout)")),
+        Err(e) => Err(Error::from_str(&format!("can't read {}: {}", path.display(), e))),
+    }
+}
+
+/// Add the path and blob hash of every file in `tree` to `out`.
+fn collect_paths(repo: &Repository, tree: &Tree, path: &str, out: &mut HashMap<String, Oid>)
+    -> Result<(), Error>
+{
+    for entry in tree.iter() {
+        let full_path = join(path, &entry_name(&entry));
+        match entry.kind() {
+            Some(ObjectType::Tree) => {
+                let child_object = entry.to_object(repo)?;
+                collect_paths(repo, child_object.as_tree().unwrap(), &full_path, out)?;
+            }
+            Some(ObjectType::Blob) => {
+                out.insert(full_path, entry.id());
+            }
+            _ => {}
+        }
+    }
+    Ok(())
+}
+
+/// Format the date part of a commit timestamp as `YYYY-MM-DD`, in the
+/// committer's own time zone, like `git log --date=short`.
+pub fn format_date(time: git2::Time) -> String {
+    let local = time.seconds() + 60 * time.offset_minutes() as i64;
+    let (year, month, day) = civil_from_days(local.div_euclid(86400));
+    format!("{:04}-{:02}-{:02}", year, month, day)
+}
+
+/// Format seconds since the epoch as an RFC 3339 date and time in UTC, like
+/// `2024-01-15T09:30:00Z`.
+pub fn format_datetime(seconds: i64) -> String {
+    let (year, month, day) = civil_from_days(seconds.div_euclid(86400));
+    let time = seconds.rem_euclid(86400);
+    format!("{:04}-{:02}-{:02}T{:02}:{:02}:{:02}Z",
+            year, month, day, time / 3600, time / 60 % 60, time % 60)
+}
+
+/// Parse a `YYYY-MM-DD` date to seconds since the epoch, at midnight UTC.
+pub fn parse_date(date: &str) -> Option<i64> {
+    let parts: Vec<&str> = date.split('-').collect();
+    if parts.len() != 3 {
+        return None;
+    }
+    let year = parts[0].parse().ok()?;
+    let month = parts[1].parse().ok()?;
+    let day = parts[2].parse().ok()?;
+    if !(1..=12).contains(&month) || !(1..=31).contains(&day) {
+        return None;
+    }
+    Some(days_from_civil(year, month, day) * 86400)
+}
+
+/// Convert a count of days since 1970-01-01 to a (year, month, day) date in
+/// the proleptic Gregorian calendar.
+///
+/// This is Howard Hinnant's `civil_from_days` algorithm.
+fn civil_from_days(days: i64) -> (i64, u32, u32) {
+    let z = days + 719468;
+    let era = z.div_euclid(146097);
+    let doe = z - era * 146097;
+    let yoe = (doe - doe / 1460 + doe / 36524 - doe / 146096) / 365;
+    let doy = doe - (365 * yoe + yoe / 4 - yoe / 100);
+    let mp = (5 * doy + 2) / 153;
+    let day = (doy - (153 * mp + 2) / 5 + 1) as u32;
+    let month = if mp < 10 { mp + 3 } else { mp - 9 } as u32;
+    let year = yoe + era * 400 + if month <= 2 { 1 } else { 0 };
+    (year, month, day)
+}
+
+/// The inverse of `civil_from_days`.
+fn days_from_civil(year: i64, month: u32, day: u32) -> i64 {
+    let year = if month <= 2 { year - 1 } else { year };
+    let era = year.div_euclid(400);
+    let yoe = year - era * 400;
+    let mp = if month > 2 { month - 3 } else { month + 9 } as i64;
+    let doy = (153 * mp + 2) / 5 + day as i64 - 1;
+    let doe = yoe * 365 + yoe / 4 - yoe / 100 + doy;
+    era * 146097 + doe - 719468
+}
+
+/// Open the repository at `path`, without searching parent directories.
+///
+/// `path` may be a linked worktree (made with `git worktree add`). Those
+/// share the main repository's objects but have their own `HEAD`, and
+/// libgit2 sorts that out itself, so `HEAD` is the worktree's branch. If
+/// the worktree can't be opened, the error says which repository it
+/// belongs to.
+pub fn open_repository(path: &str) -> Result<Repository, Error> {
+    let no_ceiling: &[&std::ffi::OsStr] = &[];
+    Repository::open_ext(path, git2::REPOSITORY_OPEN_NO_SEARCH, no_ceiling).map_err(|e| {
+        match worktree_common_dir(Path::new(path)) {
+            Some(common) => Error::from_str(&format!(
+                "can't open {}, a worktree of {}: {}", path, common.display(), e.message())),
+            None => e,
+        }
+    })
+}
+
+/// Open the git directory `git_dir` itself, like `git --git-dir`, for when
+/// it's kept apart from the files, as in CI with the source in one volume
+/// and the git metadata in another. It's opened as bare, so libgit2 doesn't
+/// guess at a work tree from where it is; `work_tree` sets one, like
+/// `git --work-tree`, without writing it to the repository's config.
+pub fn open_git_dir(git_dir: &Path, work_tree: Option<&Path>) -> Result<Repository, Error> {
+    let no_ceiling: &[&std::ffi::OsStr] = &[];
+    let flags = git2::REPOSITORY_OPEN_NO_SEARCH | git2::REPOSITORY_OPEN_BARE;
+    let repo = Repository::open_ext(git_dir, flags, no_ceiling)?;
+    if let Some(work_tree) = work_tree {
+        repo.set_workdir(work_tree, false)?;
+    }
+    Ok(repo)
+}
+
+/// If `path` is a linked worktree, find the git directory of the main
+/// repository. A linked worktree's `.git` is a file pointing at
+/// `<main>/.git/worktrees/<name>`, whose `commondir` file points back at
+/// `<main>/.git`.
+fn worktree_common_dir(path: &Path) -> Option<PathBuf> {
+    let dot_git = std::fs::read_to_string(path.join(".git")).ok()?;
+    let gitdir = path.join(dot_git.trim().strip_prefix("gitdir:")?.trim());
+    let commondir = std::fs::read_to_string(gitdir.join("commondir")).ok()?;
+    Some(gitdir.join(commondir.trim()))
+}
+
+/// Changes to history that git makes as it reads it, but libgit2's revwalk
+/// doesn't know about. Repositories converted from other version control
+/// systems often use them to stitch old history on.
+struct Rewrites {
+    /// Grafts, from `.git/info/grafts`: commits and the parents they should
+    /// have instead of their own. The commits at the edge of a shallow
+    /// clone, listed in `.git/shallow`, are grafted to have no parents.
+    grafts: HashMap<Oid, Vec<Oid>>,
+
+    /// Replace refs, from `refs/replace/`: commits and the commits that
+    /// stand in for them.
+    replacements: HashMap<Oid, Oid>,
+}
+
+/// Parse a graft file: lines of a commit id followed by the ids of the
+/// parents it should have.
+fn parse_grafts(text: &str) -> Result<HashMap<Oid, Vec<Oid>>, Error> {
+    let mut grafts = HashMap::new();
+    for line in text.lines().map(|l| l.trim()) {
+        if line.is_empty() || line.starts_with('#') {
+            continue;
+        }
+        let mut ids = line.split_whitespace().map(Oid::from_str);
+        let commit = ids.next().unwrap()?;
+        grafts.insert(commit, ids.collect::<Result<Vec<Oid>, Error>>()?);
+    }
+    Ok(grafts)
+}
+
+/// Parse a list of commit ids, one per line, with `#` starting a comment.
+/// Abbreviated ids aren't allowed: without the repository, there's no way
+/// to tell which commit one means.
+fn parse_commit_list(text: &str) -> Result<HashSet<Oid>, Error> {
+    let mut commits = HashSet::new();
+    for (i, line) in text.lines().enumerate() {
+        let line = line.split('#').next().unwrap().trim();
+        if line.is_empty() {
+            continue;
+        }
+        if line.len() != 40 || !line.bytes().all(|b| b.is_ascii_hexdigit()) {
+            return Err(Error::from_str(&format!(
+                "line {}: expected a full 40-character commit id, not '{}'", i + 1, line)));
+        }
+        commits.insert(Oid::from_str(line)?);
+    }
+    Ok(commits)
+}
+
+impl Rewrites {
+    /// Load the repository's rewrites, plus any grafts given in `options`.
+    fn load(repo: &Repository, options: &ChurnOptions) -> Result<Rewrites, Error> {
+        let path = repo.path().join("info").join("grafts");
+        let mut grafts = match std::fs::read_to_string(&path) {
+            Ok(text) => parse_grafts(&text)?,
+            Err(_) => HashMap::new(),
+        };
+        grafts.extend(options.grafts.iter().map(|(&commit, parents)| (commit, parents.clone())));
+        // Older libgit2 walks right past the shallow boundary, into parents
+        // we may or may not have.
+        if let Ok(text) = std::fs::read_to_string(repo.path().join("shallow")) {
+            for line in text.lines().map(|l| l.trim()).filter(|l| !l.is_empty()) {
+                grafts.insert(Oid::from_str(line)?, vec![]);
+            }
+        }
+
+        // Like git, skip replacements if core.useReplaceRefs is off or
+        // GIT_NO_REPLACE_OBJECTS is set.
+        let mut replacements = HashMap::new();
+        let use_replace_refs = std::env::var_os("GIT_NO_REPLACE_OBJECTS").is_none() &&
+            repo.config()?.get_bool("core.useReplaceRefs").unwrap_or(true);
+        if use_replace_refs {
+            for reference in repo.references_glob("refs/replace/*")? {
+                let reference = reference?;
+                let original = reference.name()
+                    .and_then(|name| Oid::from_str(name.trim_start_matches("refs/replace/")).ok());
+                if let (Some(original), Some(replacement)) = (original, reference.target()) {
+                    replacements.insert(original, replacement);
+                }
+            }
+        }
+        Ok(Rewrites { grafts, replacements })
+    }
+
+    fn is_empty(&self) -> bool {
+        self.grafts.is_empty() && self.replacements.is_empty()
+    }
+
+    /// Walk the history from `starts` ourselves, applying the rewrites.
+    /// Returns each commit with its commit time, in no particular order.
+    fn walk(&self, repo: &Repository, starts: &[Oid]) -> Result<Vec<(Oid, i64)>, Error> {
+        let shallow = repo.is_shallow();
+        let mut seen = HashSet::new();
+        let mut stack = starts.to_vec();
+        let mut commits = vec![];
+        while let Some(original) = stack.pop() {
+            // Grafts can make cycles; `seen` stops us going round forever.
+            if !seen.insert(original) {
+                continue;
+            }
+            let id = *self.replacements.get(&original).unwrap_or(&original);
+            let commit = match repo.find_commit(id) {
+                Ok(commit) => commit,
+                Err(_) if shallow => continue,
+                Err(e) => return Err(e),
+            };
+            commits.push((id, commit.time().seconds()));
+            match self.grafts.get(&original) {
+                Some(parents) => stack.extend(parents),
+                None => stack.extend(commit.parent_ids()),
+            }
+        }
+        Ok(commits)
+    }
+}
+
+/// List the commits to analyze, starting from `start`, in the order they
+/// should be analyzed.
+///
+/// Grafts and replace refs are honored, the way `git log` does.
+pub fn find_commits(repo: &Repository, start: Oid, options: &ChurnOptions) -> Result<Vec<Oid>, Error> {
+    let mut starts = vec![start];
+    starts.extend(options.extra_starts.iter().cloned());
+    if options.include_stash {
+        starts.extend(stash_commits(repo)?);
+    }
+    if let Some(n) = options.max_commits_per_start {
+        return commits_per_start(repo, &starts, n, options);
+    }
+    let rewrites = Rewrites::load(repo, options)?;
+    if !rewrites.is_empty() {
+        let mut commits = rewrites.walk(repo, &starts)?;
+        commits.retain(|&(id, _)| options.walks(id));
+        let mut skip = options.skip_first;
+        if options.since_n_commits.is_some() || options.needs_oldest_first() {
+            commits.sort_by_key(|&(_, time)| std::cmp::Reverse(time));
+            if let Some(n) = options.since_n_commits {
+                // Newest first, as the revwalk below would be.
+                commits.drain(..skip.min(commits.len()));
+                commits.truncate(n);
+                skip = 0;
+            }
+            if options.needs_oldest_first() {
+                commits.reverse();
+            }
+        }
+        return Ok(commits.into_iter().skip(skip).map(|(id, _)| id).collect());
+    }
+
+    let mut revwalk = repo.revwalk()?;
+    if options.since_n_commits.is_some() {
+        // Newest first, so we can stop after the first N.
+        revwalk.set_sorting(git2::SORT_TIME);
+    } else if options.needs_oldest_first() {
+        revwalk.set_sorting(git2::SORT_TIME | git2::SORT_REVERSE);
+    } else {
+        revwalk.set_sorting(git2::SORT_NONE);
+    }
+    // Each stash entry has the commit it was made on as its first parent,
+    // and the index and untracked files as the others, so the walk gets
+    // them all.
+    for &id in &starts {
+        revwalk.push(id)?;
+    }
+
+    // Older libgit2 doesn't know about shallow clones, so walking past the
+    // shallow boundary fails when it tries to load the missing parents. What
+    // we got up to that point is all the history there is.
+    let shallow = repo.is_shallow();
+    let limit = options.since_n_commits.unwrap_or(usize::MAX);
+    let mut to_skip = options.skip_first;
+    let mut commit_ids = vec![];
+    for commit_id in revwalk {
+        if commit_ids.len() == limit {
+            break;
+        }
+        match commit_id {
+            Ok(commit_id) if !options.walks(commit_id) => {}
+            Ok(_) if to_skip > 0 => to_skip -= 1,
+            Ok(commit_id) => commit_ids.push(commit_id),
+            Err(_) if shallow => break,
+            Err(e) => return Err(e),
+        }
+    }
+    if options.since_n_commits.is_some() && options.needs_oldest_first() {
+        commit_ids.reverse();
+    }
+    Ok(commit_ids)
+}
+
+/// The most recent `n` commits from each of `starts`, for
+/// `max_commits_per_start`, in the order `find_commits` would give them.
+fn commits_per_start(repo: &Repository, starts: &[Oid], n: usize, options: &ChurnOptions)
+    -> Result<Vec<Oid>, Error>
+{
+    let each = ChurnOptions {
+        since_n_commits: Some(n),
+        extra_starts: vec![],
+        include_stash: false,
+        max_commits_per_start: None,
+        ..options.clone()
+    };
+    let mut seen = HashSet::new();
+    let mut commits = vec![];
+    for &start in starts {
+        for id in find_commits(repo, start, &each)? {
+            if seen.insert(id) {
+                commits.push((id, repo.find_commit(id)?.time().seconds()));
+            }
+        }
+    }
+    commits.sort_by_key(|&(_, time)| std::cmp::Reverse(time));
+    if let Some(n) = options.since_n_commits {
+        commits.truncate(n);
+    }
+    if options.needs_oldest_first() {
+        commits.reverse();
+    }
+    Ok(commits.into_iter().map(|(id, _)| id).collect())
+}
+
+/// Every stash entry, newest first. `refs/stash` only points at the newest;
+/// the rest are in its reflog, as `stash@{1}` and so on.
+fn stash_commits(repo: &Repository) -> Result<Vec<Oid>, Error> {
+    match repo.reflog("refs/stash") {
+        Ok(reflog) => Ok(reflog.iter().map(|entry| entry.id_new()).collect()),
+        Err(ref e) if e.code() == ErrorCode::NotFound => Ok(vec![]),
+        Err(e) => Err(e),
+    }
+}
+
+/// Find every commit reachable from `start`, including `start` itself.
+pub fn reachable_commits(repo: &Repository, start: Oid) -> Result<HashSet<Oid>, Error> {
+    let mut revwalk = repo.revwalk()?;
+    revwalk.push(start)?;
+    let shallow = repo.is_shallow();
+    let mut commits = HashSet::new();
+    for commit_id in revwalk {
+        match commit_id {
+            Ok(commit_id) => {
+                commits.insert(commit_id);
+            }
+            Err(_) if shallow => break,
+            Err(e) => return Err(e),
+        }
+    }
+    Ok(commits)
+}
+
+/// Find the merges reachable from `start` of branches whose names match the
+/// glob `pattern`, and every commit those merges brought in: the ones
+/// reachable from the merged branch but not from the branch it was merged
+/// into. Pass them to `ChurnOptions::with_skipped_commits` to leave those
+/// branches' changes out of the counts.
+///
+/// Git doesn't remember which branch a commit came from, so a merge only
+/// matches if its second parent is still the tip of a matching local or
+/// remote branch. Remote branches are matched without the remote's name,
+/// and `*` matches across `/`, as with `git branch --list`.
+pub fn merged_branch_commits(repo: &Repository, start: Oid, pattern: &str)
+    -> Result<HashSet<Oid>, Error>
+{
+    let mut tips = HashSet::new();
+    for branch in repo.branches(None)? {
+        let (branch, kind) = branch?;
+        let name = match branch.name()? {
+            Some(name) => name,
+            None => continue,
+        };
+        let name = match kind {
+            BranchType::Remote => name.split_once('/').map_or(name, |(_, name)| name),
+            BranchType::Local => name,
+        };
+        if pattern::ref_glob_match(pattern, name) {
+            tips.extend(branch.get().target());
+        }
+    }
+
+    let mut commits = HashSet::new();
+    if tips.is_empty() {
+        return Ok(commits);
+    }
+    for commit_id in reachable_commits(repo, start)? {
+        let commit = repo.find_commit(commit_id)?;
+        if commit.parent_ids().count() < 2 || !tips.contains(&commit.parent_id(1)?) {
+            continue;
+        }
+        commits.insert(commit_id);
+        let mut revwalk = repo.revwalk()?;
+        revwalk.push(commit.parent_id(1)?)?;
+        revwalk.hide(commit.parent_id(0)?)?;
+        for merged in revwalk {
+            commits.insert(merged?);
+        }
+    }
+    Ok(commits)
+}
+
+/// Read the `CODEOWNERS` file in commit `start` from the places GitHub looks
+/// for it, in the order it looks: `.github/`, the top of the repository,
+/// then `docs/`. `None` if there isn't one.
+pub fn code_owners(repo: &Repository, start: Oid) -> Result<Option<CodeOwners>, Error> {
+    let tree = repo.find_commit(start)?.tree()?;
+    for path in &[".github/CODEOWNERS", "CODEOWNERS", "docs/CODEOWNERS"] {
+        if let Some(text) = read_tree_file(repo, &tree, path)? {
+            return Ok(Some(CodeOwners::parse(&text)));
+        }
+    }
+    Ok(None)
+}
+
+/// Record every file version in `commit_ids`, listed by `find_commits` from
+/// `start`, walking the history of each start on a thread of its own. Each
+/// commit goes to the first start it's reachable from, so branches that
+/// split off long ago don't walk their shared history each time, and each
+/// thread's `DirData` is merged into the root once they're all done.
+///
+/// Only do this if `options.walks_in_parallel()`.
+pub fn walk_in_parallel(repo: &Repository, start: Oid, commit_ids: &[Oid], options: &ChurnOptions)
+    -> Result<DirData, Error>
+{
+    let mut starts = vec![start];
+    starts.extend(options.extra_starts.iter().cloned());
+    if options.include_stash {
+        starts.extend(stash_commits(repo)?);
+    }
+    let shallow = repo.is_shallow();
+    let mut walked_from = HashMap::new();
+    for (i, &tip) in starts.iter().enumerate() {
+        let mut revwalk = repo.revwalk()?;
+        revwalk.push(tip)?;
+        for &earlier in &starts[..i] {
+            revwalk.hide(earlier)?;
+        }
+        for commit_id in revwalk {
+            match commit_id {
+                Ok(commit_id) => {
+                    walked_from.entry(commit_id).or_insert(i);
+                }
+                Err(_) if shallow => break,
+                Err(e) => return Err(e),
+            }
+        }
+    }
+
+    let threads = options.threads.min(starts.len());
+    let mut batches = vec![vec![]; threads];
+    for &commit_id in commit_ids {
+        // Grafts can give a commit parents the revwalks above don't know
+        // about.
+        let i = walked_from.get(&commit_id).cloned().unwrap_or(0);
+        batches[i % threads].push(commit_id);
+    }
+    // A `Repository` can't be shared between threads, so each gets its own.
+    // Opening it again by path loses anything `repo` was given that isn't on
+    // disk, like extra object database backends, so if that doesn't work or
+    // can't find the commits, walk them all here instead.
+    let reopen = |batch: &Vec<Oid>| -> Option<Repository> {
+        let reopened = Repository::open(repo.path()).ok()?;
+        let found = {
+            let odb = reopened.odb().ok()?;
+            batch.iter().all(|&commit_id| odb.exists(commit_id))
+        };
+        if found { Some(reopened) } else { None }
+    };
+    let repos: Option<Vec<Repository>> = batches.iter().map(reopen).collect();
+    let repos = match repos {
+        Some(repos) => repos,
+        None => {
+            let mut root_dir = DirData::new();
+            for &commit_id in commit_ids {
+                root_dir.update_for_commit(repo, &repo.find_commit(commit_id)?, options)?;
+            }
+            return Ok(root_dir);
+        }
+    };
+    let dirs: Vec<Result<DirData, Error>> = std::thread::scope(|scope| {
+        let workers: Vec<_> = batches.iter().zip(repos).map(|(batch, repo)| {
+            scope.spawn(move || {
+                let mut dir = DirData::new();
+                for &commit_id in batch {
+                    dir.update_for_commit(&repo, &repo.find_commit(commit_id)?, options)?;
+                }
+                Ok(dir)
+            })
+        }).collect();
+        workers.into_iter().map(|worker| {
+            worker.join().unwrap_or_else(|_| Err(Error::from_str("worker thread panicked")))
+        }).collect()
+    });
+    let mut root_dir = DirData::new();
+    for dir in dirs {
+        root_dir.merge(dir?);
+    }
+    Ok(root_dir)
+}
+
+/// Analyze the history of `repo` leading up to commit `start`.
+pub fn analyze_with_options(repo: &Repository, start: Oid, options: &ChurnOptions)
+    -> Result<ChurnResult, Error>
+{
+    let commit_ids = find_commits(repo, start, options)?;
+    if options.walks_in_parallel() {
+        return walk_in_parallel(repo, start, &commit_ids, options)?.results(repo, start, options);
+    }
+    let mut root_dir = DirData::new();
+    for commit_id in commit_ids {
+        let commit = repo.find_commit(commit_id)?;
+        root_dir.update_for_commit(repo, &commit, options)?;
+    }
+    root_dir.results(repo, start, options)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::join;
+
+    #[test]
+    fn join_plain() {
+        assert_eq!(join("src", "lib.rs"), "src/lib.rs");
+        assert_eq!(join("src/bin", "main.rs"), "src/bin/main.rs");
+    }
+
+    #[test]
+    fn join_empty_base() {
+        assert_eq!(join("", "lib.rs"), "lib.rs");
+        assert_eq!(join("", ""), "");
+        assert_eq!(join("src", ""), "src");
+    }
+
+    #[test]
+    fn join_trailing_slash() {
+        assert_eq!(join("src/", "lib.rs"), "src/lib.rs");
+        assert_eq!(join("src/", "bin/"), "src/bin");
+    }
+
+    #[test]
+    fn join_double_slash() {
+        assert_eq!(join("src//bin", "main.rs"), "src/bin/main.rs");
+        assert_eq!(join("src", "bin//main.rs"), "src/bin/main.rs");
+    }
+
+    #[test]
+    fn join_current_dir() {
+        assert_eq!(join("./src", "lib.rs"), "src/lib.rs");
+        assert_eq!(join("src", "./lib.rs"), "src/lib.rs");
+        assert_eq!(join(".", "."), "");
+    }
+
+    #[test]
+    fn join_parent_dir() {
+        assert_eq!(join("src/bin", "../lib.rs"), "src/lib.rs");
+        assert_eq!(join("src", "a/../b"), "src/b");
+        // There's nothing above the top to go up to.
+        assert_eq!(join("src", "../../lib.rs"), "lib.rs");
+        assert_eq!(join("", ".."), "");
+    }
+
+    #[test]
+    fn join_leading_slash() {
+        assert_eq!(join("src", "/lib.rs"), "src/lib.rs");
+        assert_eq!(join("/src", "lib.rs"), "src/lib.rs");
+    }
+
+    #[test]
+    fn join_backslash() {
+        // Only `/` separates components, on every platform.
+        assert_eq!(join("src", "a\\b.rs"), "src/a\\b.rs");
+        assert_eq!(join("src", "./a\\b.rs"), "src/a\\b.rs");
+    }
+}
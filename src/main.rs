@@ -1,181 +1,4586 @@
 // churn - Count how many versions exist of each file in a git repository.
 
+#[cfg(any(feature = "arrow", feature = "parquet"))]
+extern crate arrow2;
+extern crate atty;
+extern crate churn;
 extern crate git2;
 extern crate docopt;
+extern crate regex;
+extern crate serde_json;
+extern crate toml;
+#[cfg(feature = "cbor")]
+extern crate ciborium;
+#[cfg(feature = "gzip")]
+extern crate flate2;
+#[cfg(feature = "png")]
+extern crate image;
+#[cfg(feature = "msgpack")]
+extern crate rmp_serde;
+#[cfg(feature = "xlsx")]
+extern crate rust_xlsxwriter;
+#[cfg(feature = "push")]
+extern crate ureq;
+#[cfg(feature = "tracing")]
+extern crate tracing;
+#[cfg(feature = "tracing")]
+extern crate tracing_subscriber;
 
+mod file_type;
+#[cfg(feature = "png")]
+mod heatmap;
+mod settings;
+#[cfg(feature = "xlsx")]
+mod spreadsheet;
+
+use churn::{ChurnOptions, DirData, FileResult};
+use churn::pattern::{glob_match, CodeOwners};
 use docopt::Docopt;
-use git2::{Repository, Error, Oid, Tree, ObjectType};
-use std::collections::{HashMap, HashSet};
+use file_type::FileTypes;
+use git2::{Repository, Error, ErrorClass, ErrorCode, ObjectType, Oid};
+use regex::Regex;
+use settings::Settings;
+use std::borrow::Cow;
+use std::collections::{BTreeMap, HashMap, HashSet};
 use std::io::Write;
+use std::path::{Component, Path, PathBuf};
+use std::time::Instant;
+
+/// How often to redraw the progress bar, in commits.
+const PROGRESS_INTERVAL: usize = 100;
+
+const PROGRESS_BAR_WIDTH: usize = 20;
+
+/// The ways we know how to print results.
+#[derive(Clone, Copy, PartialEq)]
+enum Format {
+    /// One line per file, churn count first. Meant for people.
+    Text,
+
+    /// TeamCity build service messages, so a CI build can flag files that
+    /// churn too much.
+    TeamCity,
+
+    /// `<path>\t<count>` per file and nothing else, for scripts.
+    Pairs,
+
+    /// `NAME=value` lines, quoted for a POSIX shell to `eval`.
+    Kv,
+
+    /// A logfmt line per file, like `path=src/main.rs churn=42`, for log
+    /// aggregators like Loki.
+    Logfmt,
+
+    /// Comma- or tab-separated values, with a header row.
+    Csv,
+    Tsv,
+
+    /// CSV with a row per file and a column per `--period`, holding how
+    /// many new versions the file got then.
+    CsvWide,
+
+    /// TSV that Excel opens as it is: a UTF-8 byte order mark first, and
+    /// CRLF line endings.
+    TsvExcel,
+
+    /// CSV that Google Sheets imports as it is, with dates as `MM/DD/YYYY`
+    /// and booleans as `TRUE` and `FALSE`.
+    GoogleSheets,
+
+    /// A JSON array of objects, one per file. Written as the files are
+    /// found rather than sorted, so it works for any size of repository.
+    Json,
+
+    /// The same objects as `Json`, but one per line with no array around
+    /// them (also known as JSON Lines).
+    NdJson,
+
+    /// A TOML document with a `[[files]]` table per file.
+    Toml,
+
+    /// SQL statements to create a `churn_results` table and insert a row
+    /// per file, for piping into `sqlite3`.
+    Sql,
+
+    /// A GraphML document of the `--coupling` graph, for Gephi or yEd.
+    GraphMl,
+
+    /// RDF in Turtle syntax, with a `file://` resource per file, for
+    /// SPARQL.
+    Turtle,
+
+    /// InfluxDB line protocol, a point per file, written to the
+    /// `--influx-url` server in batches rather than printed. Only available
+    /// when built with the `push` feature.
+    InfluxBatch,
+
+    /// A Graphviz graph with a node for each month, and edges between them
+    /// labeled with how many new versions each month brought.
+    DotTimeline,
+
+    /// An Apache Arrow IPC file with `path` and `churn_count` columns. Only
+    /// available when built with the `arrow` feature.
+    Arrow,
+
+    /// A Feather v2 file, for R and `pyarrow.feather`: also Arrow IPC, but
+    /// with a `LargeUtf8` path column, and with `--with-dates`, timestamp
+    /// columns too. Also needs the `arrow` feature.
+    Feather,
+
+    /// A Parquet file with `path`, `churn_count`, `first_seen` and
+    /// `last_seen` columns. Only available when built with the `parquet`
+    /// feature.
+    Parquet,
+
+    /// A heatmap of the most churned files' changes over time, saved as a
+    /// PNG image to the `--output` file. Only available when built with
+    /// the `png` feature.
+    Png,
+
+    /// An Excel workbook saved to the `--output` file, with sheets for the
+    /// files, a summary, and the top 20 files with a chart. Only available
+    /// when built with the `xlsx` feature.
+    Xlsx,
+
+    /// The same document as `Json`, encoded as CBOR. Only available when
+    /// built with the `cbor` feature.
+    Cbor,
+
+    /// The same document as `Json`, encoded as MessagePack. Only available
+    /// when built with the `msgpack` feature.
+    MsgPack,
+}
+
+impl Format {
+    fn from_name(name: &str) -> Option<Format> {
+        match name {
+            "text" => Some(Format::Text),
+            "teamcity" => Some(Format::TeamCity),
+            "pairs" => Some(Format::Pairs),
+            "kv" => Some(Format::Kv),
+            "logfmt" => Some(Format::Logfmt),
+            "csv" => Some(Format::Csv),
+            "csv-wide" => Some(Format::CsvWide),
+            "tsv" => Some(Format::Tsv),
+            "tsv-excel" => Some(Format::TsvExcel),
+            "google-sheets" => Some(Format::GoogleSheets),
+            "json" => Some(Format::Json),
+            "ndjson" => Some(Format::NdJson),
+            "toml" => Some(Format::Toml),
+            "sql" => Some(Format::Sql),
+            "arrow" => Some(Format::Arrow),
+            "feather" => Some(Format::Feather),
+            "graphml" => Some(Format::GraphMl),
+            "dot-timeline" => Some(Format::DotTimeline),
+            "turtle" => Some(Format::Turtle),
+            "influx-batch" => Some(Format::InfluxBatch),
+            "parquet" => Some(Format::Parquet),
+            "png" => Some(Format::Png),
+            "xlsx" => Some(Format::Xlsx),
+            "cbor" => Some(Format::Cbor),
+            "msgpack" => Some(Format::MsgPack),
+            _ => None
+        }
+    }
+
+    /// True for the JSON formats, which are written as the files are found.
+    fn is_streamed(self) -> bool {
+        self == Format::Json || self == Format::NdJson
+    }
+
+    /// True for the formats with a row per file, which `--output` can write
+    /// to a file and `--output-append` add to.
+    fn is_delimited(self) -> bool {
+        self == Format::Csv || self == Format::Tsv || self == Format::TsvExcel ||
+            self == Format::GoogleSheets
+    }
+
+    /// True for the formats that write a binary file.
+    fn is_binary(self) -> bool {
+        self == Format::Arrow || self == Format::Feather || self == Format::Parquet ||
+            self == Format::Cbor || self == Format::MsgPack
+    }
+
+    /// True for formats meant to be read by other programs, which need
+    /// anything else we have to say kept off stdout.
+    fn is_for_programs(self) -> bool {
+        match self {
+            Format::Pairs | Format::Kv | Format::Logfmt | Format::Csv | Format::CsvWide |
+                Format::Tsv | Format::GoogleSheets |
+                Format::TsvExcel | Format::Json |
+                Format::NdJson |
+                Format::Toml | Format::Sql | Format::Arrow | Format::Feather | Format::Parquet |
+                Format::Png | Format::Xlsx |
+                Format::Cbor | Format::MsgPack | Format::GraphMl | Format::DotTimeline |
+                Format::Turtle | Format::InfluxBatch => true,
+            Format::Text | Format::TeamCity => false,
+        }
+    }
+}
+
+/// What to sort the output by. Biggest goes first, except for stability,
+/// where the least stable files do.
+#[derive(Clone, Copy, PartialEq)]
+enum SortKey {
+    Churn,
+    Bytes,
+    Stability,
+}
+
+/// How long the columns of `--format csv-wide` are.
+#[derive(Clone, Copy, PartialEq)]
+enum Period {
+    Week,
+    Month,
+    Quarter,
+    Year,
+}
+
+impl Period {
+    /// Which period `time` is in, counting from some fixed start, so the
+    /// periods between two times are the numbers between theirs.
+    fn number(self, time: i64) -> i64 {
+        let days = time.div_euclid(86400);
+        let date = churn::format_datetime(time);
+        let year: i64 = date[..4].parse().unwrap();
+        let month: i64 = date[5..7].parse().unwrap();
+        match self {
+            // 1970-01-01 was a Thursday; weeks start on Monday.
+            Period::Week => (days + 3).div_euclid(7),
+            Period::Month => year * 12 + month - 1,
+            Period::Quarter => year * 4 + (month - 1) / 3,
+            Period::Year => year,
+        }
+    }
+
+    /// The column name for period `number`: the date the week starts on,
+    /// `2024-03`, `2024-Q1` or `2024`.
+    fn label(self, number: i64) -> String {
+        match self {
+            Period::Week => churn::format_datetime((number * 7 - 3) * 86400)[..10].to_string(),
+            Period::Month => {
+                format!("{:04}-{:02}", number.div_euclid(12), number.rem_euclid(12) + 1)
+            }
+            Period::Quarter => {
+                format!("{:04}-Q{}", number.div_euclid(4), number.rem_euclid(4) + 1)
+            }
+            Period::Year => format!("{:04}", number),
+        }
+    }
+}
+
+/// How to write file paths in the output.
+#[derive(Clone, Copy, PartialEq)]
+enum PathStyle {
+    /// Relative to the top of the repository's working directory.
+    RepoRelative,
+
+    /// Relative to the current directory.
+    Relative,
+
+    Absolute,
+}
+
+/// Settings from the command line.
+struct Options {
+    /// Settings for the analysis itself.
+    churn: ChurnOptions,
+
+    /// The git directory to open as it is, rather than looking for one in a
+    /// work tree, and the work tree to go with it.
+    git_dir: Option<PathBuf>,
+    work_tree: Option<PathBuf>,
+
+    format: Format,
+
+    /// Files with a churn count above this are reported as build problems
+    /// in TeamCity output.
+    max_churn: Option<usize>,
+
+    sort: SortKey,
+
+    /// Print one line per file extension instead of one per file.
+    group_by_extension: bool,
+
+    /// Print this many directories with the most churn inside them, instead
+    /// of files.
+    top_dirs: Option<usize>,
+
+    /// List only this many of the most churned files.
+    top: Option<usize>,
+
+    /// Print this many authors who made the most new file versions, instead
+    /// of files.
+    top_authors: Option<usize>,
+
+    /// Instead of files, print each source file matching the first glob
+    /// with its test file, matching the second, and how their churn
+    /// compares.
+    test_source_ratio: Option<(String, String)>,
+
+    /// Instead of files, list the source files matching the first glob
+    /// with at least this much churn, and no test file matching the second.
+    flag_untested: Option<(String, String, usize)>,
+
+    /// List directories too, with how many versions of their trees there
+    /// have been.
+    include_empty_dirs: bool,
+
+    /// Where `--format png` saves the image, or `--format xlsx` the
+    /// workbook. The csv and tsv formats write here instead of stdout.
+    output: Option<PathBuf>,
+
+    /// Add csv or tsv rows to the end of the `output` file rather than
+    /// replacing it.
+    output_append: bool,
+
+    /// Gzip the csv or tsv output, at this compression level.
+    output_gzip: Option<u32>,
+
+    /// Write the csv or tsv output to a file in this directory for each
+    /// top-level directory of the repository, instead of to stdout.
+    output_split_by_dir: Option<PathBuf>,
+
+    /// Also print which commits changed the most, and commits per month.
+    commit_stats: bool,
+
+    /// Print what's in the repository before the results: its HEAD,
+    /// branches, tags and size.
+    repo_info: bool,
+
+    /// Print how much memory the analysis took to stderr.
+    report_memory: bool,
+
+    /// Print pairs of files that change in the same commits, instead of
+    /// the files on their own.
+    coupling: bool,
+
+    /// `(k, n)`: in text output, mark files that got new versions in at
+    /// least `k` of the `n` most recent commits.
+    hot_streak: Option<(usize, usize)>,
+
+    /// Print churn totals grouped by the first capture group of each of
+    /// these patterns, nested in order.
+    group_by: Vec<Regex>,
+
+    path_style: PathStyle,
+
+    /// Leave this directory off the front of paths that start with it, for
+    /// display. Ends with `/`.
+    strip_prefix: Option<String>,
+
+    /// Shorten paths to at most this many characters for display.
+    truncate_path: Option<usize>,
+
+    /// Print a line for each commit as it's processed, instead of a progress
+    /// bar.
+    verbose: bool,
+
+    /// Point out commits that look like refactors: ones that make new
+    /// versions of more than `refactor_breadth` files whose churn counts
+    /// average less than `refactor_depth`.
+    show_refactoring: bool,
+    refactor_breadth: usize,
+    refactor_depth: f64,
+
+    /// Don't count the changes made in commits that look like refactors.
+    exclude_refactoring: bool,
+
+    /// Highlight the most churned files with ANSI colors.
+    color: bool,
+
+    /// Print the total size of each file's versions.
+    with_size: bool,
+
+    /// Print each file's churn per day since it was created.
+    with_daily_rate: bool,
+
+    /// How long each column of `--format csv-wide` is.
+    period: Period,
+
+    /// Print each file's churn with every version weighted by
+    /// `exp(-lambda * age_in_days)`, and sort by that.
+    decay: Option<f64>,
+
+    /// Print the fraction of the commits analyzed that made a new version
+    /// of each file.
+    change_ratio: bool,
+
+    /// Print each file's `stability_score`.
+    stability_score: bool,
+
+    /// Print how many commits a day the repository gets before the file
+    /// list.
+    density: bool,
+
+    /// Print when each file's first and last counted versions were made, in
+    /// the formats with named fields.
+    with_dates: bool,
+
+    /// Print the commit that first added each file, and its date.
+    show_origin: bool,
+
+    /// Print what type of file each file is, going by these rules.
+    file_types: Option<FileTypes>,
+
+    /// Print who owns each file according to the `CODEOWNERS` file.
+    with_codeowners: bool,
+
+    /// In text output, draw each file's churn per week for this many weeks
+    /// back.
+    sparkline_weeks: Option<usize>,
+
+    /// Print (age, churn) pairs for plotting instead of the usual output.
+    correlation: bool,
+
+    /// Print the running total of new file versions by date instead of the
+    /// usual output.
+    cumulative: bool,
+
+    /// Print each commit with how many new file versions it made, most
+    /// first, instead of the usual output.
+    per_commit: bool,
+
+    /// Print a line for each new file version as it's found, instead of
+    /// the usual output.
+    stream: bool,
+
+    /// List blobs that no file in HEAD uses instead of the usual output.
+    show_orphan_blobs: bool,
+
+    /// Instead of the usual output, list files where one author made more
+    /// than this fraction of the changes.
+    bus_factor_risk: Option<f64>,
+
+    /// Instead of the usual output, print who wrote most of the current
+    /// version of each of the top files, according to `git blame`.
+    blame_summary: bool,
+
+    /// Instead of the usual output, list the commits that made each
+    /// version of each file.
+    show_blame: bool,
+
+    /// Instead of the usual output, compare who made the most versions of
+    /// each file with who wrote the most of its current version.
+    blame_coupling: bool,
+
+    /// Indent `--format json` output for people to read.
+    pretty: bool,
+
+    /// Put `--format json` output in an object that also says what was
+    /// analyzed and when.
+    envelope: bool,
+
+    /// Start from this tag instead of HEAD.
+    tag: Option<String>,
+
+    /// Start from this branch, tag or commit instead of HEAD, or from every
+    /// ref matching it if it's a glob.
+    start_ref: Option<String>,
+
+    /// Instead of the usual analysis, compare the churn on these two
+    /// branches since they split.
+    compare: Option<(String, String)>,
+
+    /// Only count changes made since the last release.
+    since_release: bool,
+
+    /// Only walk tagged commits, and this many commits before each one.
+    only_tagged: Option<usize>,
+
+    /// Instead of printing the results, push them as metrics to the
+    /// Prometheus Pushgateway at this URL, with these job and instance
+    /// labels.
+    prometheus_push: Option<(String, String, Option<String>)>,
+
+    /// For `--format influx-batch`, the InfluxDB server's URL, the database
+    /// to write to, and the token to authenticate with, if any.
+    influx: Option<(String, String, Option<String>)>,
+
+    /// Only count changes made since the starting commit split from this
+    /// branch.
+    merge_base: Option<String>,
+
+    /// Don't count merges of branches matching this glob, or the commits
+    /// they brought in.
+    exclude_merges_from: Option<String>,
+
+    /// Record each commit's number of new file versions in a git note.
+    write_notes: bool,
+
+    /// Print how many commits there are to analyze, and nothing else.
+    commit_count_only: bool,
+
+    /// Print statistics after the file list.
+    summary: bool,
+
+    /// Print totals for the repository instead of the file list.
+    summary_only: bool,
+
+    /// Print the churn of all the files added up after the file list.
+    total: bool,
+
+    /// Print the mean, median, standard deviation and max churn after the
+    /// file list.
+    with_stats: bool,
+
+    /// Draw a bar chart of the top files instead of listing them.
+    histogram: bool,
+
+    /// Print how many files have each churn count instead of listing them.
+    distribution: bool,
+
+    /// How many columns wide the terminal is.
+    width: usize,
+
+    /// Print a header row: by default in CSV and TSV, with `--with-header`
+    /// in text.
+    header: bool,
+
+    /// Check the analysis records for internal consistency before printing.
+    check_integrity: bool,
+}
+
+impl Options {
+    /// True if one of the modes that print something other than the list of
+    /// files is on, like `--group-by-extension`.
+    fn replaces_file_list(&self) -> bool {
+        self.group_by_extension || self.top_dirs.is_some() || self.top_authors.is_some() ||
+            self.test_source_ratio.is_some() || self.flag_untested.is_some() || self.coupling ||
+            !self.group_by.is_empty() || self.correlation ||
+            self.show_orphan_blobs || self.bus_factor_risk.is_some() || self.blame_summary ||
+            self.show_blame || self.blame_coupling || self.histogram || self.distribution ||
+            self.summary_only
+    }
+}
+
+/// Shorten `path` to at most `max` characters, if given, by putting `…` in
+/// place of directories in the middle: `a/b/c/…/g/h/file.rs`. Whole path
+/// components are kept from both ends, the file name first; if even the
+/// file name is too long, only the end of it is kept.
+fn display_path(path: &str, max: Option<usize>) -> Cow<'_, str> {
+    let max = match max {
+        Some(max) if path.chars().count() > max => max,
+        _ => return Cow::Borrowed(path),
+    };
+    let components: Vec<&str> = path.split('/').collect();
+    let name = components[components.len() - 1];
+    let name_len = name.chars().count();
+    if name_len + 2 > max {
+        let keep: String = name.chars().skip((name_len + 1).saturating_sub(max)).collect();
+        return Cow::Owned(format!("…{}", keep));
+    }
+    // Add components from the start and the end in turn, while they fit
+    // around the `…/` in the middle.
+    let (mut head, mut tail) = (0, 1);
+    let mut len = name_len + 2;
+    let mut from_start = true;
+    while head + tail < components.len() {
+        let next = if from_start { head } else { components.len() - 1 - tail };
+        let added = components[next].chars().count() + 1;
+        if len + added > max {
+            break;
+        }
+        len += added;
+        if from_start {
+            head += 1;
+        } else {
+            tail += 1;
+        }
+        from_start = !from_start;
+    }
+    let mut parts = components[..head].to_vec();
+    parts.push("…");
+    parts.extend(&components[components.len() - tail..]);
+    Cow::Owned(parts.join("/"))
+}
+
+/// Escape a string for use as a value in a TeamCity service message.
+fn teamcity_escape(s: &str) -> String {
+    let mut out = String::with_capacity(s.len());
+    for c in s.chars() {
+        match c {
+            '\'' => out.push_str("|'"),
+            '|' => out.push_str("||"),
+            '\n' => out.push_str("|n"),
+            '\r' => out.push_str("|r"),
+            '[' => out.push_str("|["),
+            ']' => out.push_str("|]"),
+            _ => out.push(c)
+        }
+    }
+    out
+}
+
+fn print_teamcity(all_files: &[FileResult], max_churn: Option<usize>) {
+    if let Some(threshold) = max_churn {
+        for file in all_files {
+            if file.churn_count > threshold {
+                let description = format!("{} churn {} > threshold {}",
+                                          file.path, file.churn_count, threshold);
+                println!("##teamcity[buildProblem description='{}']",
+                         teamcity_escape(&description));
+            }
+        }
+    }
+    let total: usize = all_files.iter().map(|f| f.churn_count).sum();
+    println!("##teamcity[buildStatisticValue key='total_churn' value='{}']", total);
+}
+
+/// Quote `value` for a POSIX shell. Values made only of letters, digits and
+/// `_ . / : @ % + , -` are left alone; anything else goes in single quotes,
+/// inside which the shell treats every character literally, except that a
+/// single quote has to end the quoting, be escaped and start it again:
+/// `it's` becomes `'it'\''s'`.
+fn shell_quote(value: &str) -> Cow<'_, str> {
+    let plain = |c: char| c.is_ascii_alphanumeric() || "_./:@%+,-".contains(c);
+    if !value.is_empty() && value.chars().all(plain) {
+        Cow::Borrowed(value)
+    } else {
+        Cow::Owned(format!("'{}'", value.replace('\'', "'\\''")))
+    }
+}
+
+/// Print the results as shell variable assignments: `FILE_COUNT`, then
+/// `TOP_FILE` and `TOP_CHURN` for the most churned file, then `FILE_<n>`
+/// and `CHURN_<n>` for each file, from 1. With `total`, `TOTAL_CHURN` too.
+fn print_kv(all_files: &[FileResult], total: bool) {
+    println!("FILE_COUNT={}", all_files.len());
+    if let Some(top) = all_files.first() {
+        println!("TOP_FILE={}", shell_quote(&top.path));
+        println!("TOP_CHURN={}", top.churn_count);
+    }
+    for (i, file) in all_files.iter().enumerate() {
+        println!("FILE_{}={}", i + 1, shell_quote(&file.path));
+        println!("CHURN_{}={}", i + 1, file.churn_count);
+    }
+    if total {
+        println!("TOTAL_CHURN={}", all_files.iter().map(|f| f.churn_count).sum::<usize>());
+    }
+}
+
+/// Quote `value` for logfmt if it needs it: if it's empty, or has a space,
+/// `=`, `"` or a control character in it. Inside the quotes, `\` and `"` are
+/// escaped with a backslash, and newlines, tabs and returns written as
+/// `\n`, `\t` and `\r`.
+fn logfmt_quote(value: &str) -> Cow<'_, str> {
+    let special = |c: char| c == ' ' || c == '=' || c == '"' || c.is_control();
+    if !value.is_empty() && !value.chars().any(special) {
+        return Cow::Borrowed(value);
+    }
+    let mut quoted = String::from("\"");
+    for c in value.chars() {
+        match c {
+            '\\' => quoted += "\\\\",
+            '"' => quoted += "\\\"",
+            '\n' => quoted += "\\n",
+            '\t' => quoted += "\\t",
+            '\r' => quoted += "\\r",
+            c => quoted.push(c),
+        }
+    }
+    quoted.push('"');
+    Cow::Owned(quoted)
+}
+
+/// Print a logfmt line per file with its `path`, `churn`, `deleted`, and
+/// when they're tracked, `first_seen` and `last_seen`. With `total`, a
+/// last line with `total_churn`.
+fn print_logfmt(all_files: &[FileResult], total: bool) {
+    for file in all_files {
+        let mut line = format!("path={} churn={}", logfmt_quote(&file.path), file.churn_count);
+        if let (Some(first), Some(last)) = (file.first_seen, file.last_seen) {
+            line += &format!(" first_seen={} last_seen={}", churn::format_datetime(first),
+                             churn::format_datetime(last));
+        }
+        println!("{} deleted={}", line, file.deleted);
+    }
+    if total {
+        println!("total_churn={}", all_files.iter().map(|f| f.churn_count).sum::<usize>());
+    }
+}
+
+/// Escape `value` for a label value in the Prometheus text format.
+fn prometheus_escape(value: &str) -> String {
+    value.replace('\\', "\\\\").replace('"', "\\\"").replace('\n', "\\n")
+}
+
+/// The results in the Prometheus text format: a `churn_file_versions`
+/// gauge per file, labeled with its path, and the totals.
+fn prometheus_metrics(all_files: &[FileResult], commit_count: usize) -> String {
+    let mut text = String::new();
+    text += "# HELP churn_file_versions Number of different versions of the file.\n";
+    text += "# TYPE churn_file_versions gauge\n";
+    for file in all_files {
+        text += &format!("churn_file_versions{{path=\"{}\"}} {}\n",
+                         prometheus_escape(&file.path), file.churn_count);
+    }
+    let total: usize = all_files.iter().map(|f| f.churn_count).sum();
+    text += "# HELP churn_total Sum of the churn of all the files.\n";
+    text += "# TYPE churn_total gauge\n";
+    text += &format!("churn_total {}\n", total);
+    text += "# HELP churn_commits_analyzed How many commits were analyzed.\n";
+    text += "# TYPE churn_commits_analyzed gauge\n";
+    text += &format!("churn_commits_analyzed {}\n", commit_count);
+    text
+}
+
+/// POST `metrics` to the Pushgateway at `gateway` for `job`, and `instance`
+/// if given. POST replaces the metrics of the same names pushed for that
+/// job and instance before, and leaves any others alone.
+#[cfg(feature = "push")]
+fn push_metrics(gateway: &str, job: &str, instance: Option<&str>, metrics: &str)
+    -> Result<(), Error>
+{
+    let mut url = format!("{}/metrics/job/{}", gateway.trim_end_matches('/'), job);
+    if let Some(instance) = instance {
+        url += &format!("/instance/{}", instance);
+    }
+    ureq::post(&url)
+        .set("Content-Type", "text/plain; version=0.0.4")
+        .send_string(metrics)
+        .map(|_| ())
+        .map_err(|e| Error::from_str(&format!("can't push metrics to {}: {}", url, e)))
+}
+
+#[cfg(not(feature = "push"))]
+fn push_metrics(_: &str, _: &str, _: Option<&str>, _: &str) -> Result<(), Error> {
+    unreachable!("refused in main")
+}
+
+/// How many lines `--format influx-batch` sends in each request.
+#[cfg(feature = "push")]
+const INFLUX_BATCH_LINES: usize = 5000;
+
+/// How many times to try sending each batch, and how long to wait before
+/// the first retry. Each wait after that is twice as long.
+#[cfg(feature = "push")]
+const INFLUX_ATTEMPTS: u32 = 5;
+#[cfg(feature = "push")]
+const INFLUX_FIRST_RETRY: std::time::Duration = std::time::Duration::from_secs(1);
+
+/// Escape `value` for a tag value in InfluxDB line protocol.
+fn influx_escape(value: &str) -> String {
+    let mut out = String::with_capacity(value.len());
+    for c in value.chars() {
+        if c == ',' || c == '=' || c == ' ' {
+            out.push('\\');
+        }
+        out.push(c);
+    }
+    out
+}
+
+/// A `churn` point per file in InfluxDB line protocol, tagged with its path
+/// and timestamped `now`, in seconds.
+fn influx_lines(all_files: &[FileResult], now: i64) -> Vec<String> {
+    all_files.iter()
+        .map(|file| format!("churn,path={} versions={}i,deleted={} {}",
+                            influx_escape(&file.path), file.churn_count, file.deleted, now))
+        .collect()
+}
+
+/// Write `lines` to database `db` on the InfluxDB server at `url`, in
+/// batches of `INFLUX_BATCH_LINES`. The 1.x `/write` endpoint is used, which
+/// InfluxDB 2 also serves, mapping the database to a bucket. A batch that
+/// fails because of the network or the server is tried again a few times,
+/// waiting longer each time; one the server rejects as bad isn't.
+#[cfg(feature = "push")]
+fn write_influx(url: &str, db: &str, token: Option<&str>, lines: &[String])
+    -> Result<(), Error>
+{
+    let url = format!("{}/write", url.trim_end_matches('/'));
+    for batch in lines.chunks(INFLUX_BATCH_LINES) {
+        let body = batch.join("\n");
+        let mut delay = INFLUX_FIRST_RETRY;
+        for attempt in 1.. {
+            let mut request = ureq::post(&url)
+                .query("db", db)
+                .query("precision", "s")
+                .set("Content-Type", "text/plain; charset=utf-8");
+            if let Some(token) = token {
+                request = request.set("Authorization", &format!("Token {}", token));
+            }
+            let error = match request.send_string(&body) {
+                Ok(_) => break,
+                Err(e) => e,
+            };
+            let retry = match error {
+                ureq::Error::Status(code, _) => code == 429 || code >= 500,
+                ureq::Error::Transport(_) => true,
+            };
+            if !retry || attempt == INFLUX_ATTEMPTS {
+                return Err(Error::from_str(&format!("can't write to InfluxDB at {}: {}",
+                                                    url, error)));
+            }
+            eprintln!("writing to InfluxDB failed ({}); trying again in {}s", error,
+                      delay.as_secs());
+            std::thread::sleep(delay);
+            delay *= 2;
+        }
+    }
+    Ok(())
+}
+
+#[cfg(not(feature = "push"))]
+fn write_influx(_: &str, _: &str, _: Option<&str>, _: &[String]) -> Result<(), Error> {
+    unreachable!("refused in main")
+}
+
+/// The extension of the file at `path`, including the dot, or `<no-ext>`.
+///
+/// Like `Path::extension`, a leading dot doesn't count, so `.gitignore` has
+/// no extension.
+fn extension(path: &str) -> String {
+    let filename = path.rsplit('/').next().unwrap();
+    match filename.rfind('.') {
+        Some(i) if i > 0 => filename[i..].to_string(),
+        _ => "<no-ext>".to_string()
+    }
+}
+
+/// Add up churn counts per file extension. The result is sorted with the
+/// most churned extension first.
+fn group_by_extension(all_files: &[FileResult]) -> Vec<(String, usize)> {
+    let mut totals: HashMap<String, usize> = HashMap::new();
+    for file in all_files {
+        *totals.entry(extension(&file.path)).or_insert(0) += file.churn_count;
+    }
+    let mut groups: Vec<(String, usize)> = totals.into_iter().collect();
+    groups.sort_by(|a, b| b.1.cmp(&a.1).then_with(|| a.0.cmp(&b.0)));
+    groups
+}
+
+/// Add up churn counts per directory, counting each file in every directory
+/// above it, and keep the `n` directories with the most. Files at the top of
+/// the repository aren't in any directory.
+fn top_dirs(all_files: &[FileResult], n: usize) -> Vec<(String, usize)> {
+    let mut totals: HashMap<&str, usize> = HashMap::new();
+    for file in all_files {
+        let mut dir = file.path.as_str();
+        while let Some(i) = dir.rfind('/') {
+            dir = &dir[..i];
+            *totals.entry(dir).or_insert(0) += file.churn_count;
+        }
+    }
+    let mut dirs: Vec<(String, usize)> = totals.into_iter()
+        .map(|(dir, total)| (dir.to_string(), total))
+        .collect();
+    dirs.sort_by(|a, b| b.1.cmp(&a.1).then_with(|| a.0.cmp(&b.0)));
+    dirs.truncate(n);
+    dirs
+}
+
+/// Add up how many new file versions each author made, across all the
+/// files, and keep the `n` authors who made the most.
+fn top_authors(all_files: &[FileResult], n: usize) -> Vec<(String, usize)> {
+    let mut totals: HashMap<&str, usize> = HashMap::new();
+    for file in all_files {
+        for (name, count) in &file.authors {
+            *totals.entry(name).or_insert(0) += count;
+        }
+    }
+    let mut authors: Vec<(String, usize)> = totals.into_iter()
+        .map(|(name, total)| (name.to_string(), total))
+        .collect();
+    authors.sort_by(|a, b| b.1.cmp(&a.1).then_with(|| a.0.cmp(&b.0)));
+    authors.truncate(n);
+    authors
+}
+
+/// What a source or test file at `path` is named for: its file name up to
+/// the first `.`, without any `test` or `spec` marker, so `src/parser.rs`,
+/// `tests/parser_test.rs`, `test_parser.py` and `parser.spec.js` are all
+/// `parser`.
+fn test_subject(path: &str) -> &str {
+    let name = path.rsplit('/').next().unwrap_or(path);
+    let mut subject = name.split('.').next().unwrap_or(name);
+    for marker in &["_test", "_spec", "-test", "-spec", "Tests", "Test", "Spec"] {
+        subject = subject.strip_suffix(marker).unwrap_or(subject);
+    }
+    for marker in &["test_", "spec_"] {
+        subject = subject.strip_prefix(marker).unwrap_or(subject);
+    }
+    subject
+}
+
+/// Pair each file matching the glob `source_pattern`, but not
+/// `test_pattern`, with the file matching `test_pattern` that's named for
+/// the same thing (see `test_subject`), if there is one. Of several, the
+/// one sharing the most directories with the source wins. Biggest ratio of
+/// source churn to test churn first, starting with the untested ones.
+fn test_source_pairs<'a>(all_files: &'a [FileResult], source_pattern: &str,
+                         test_pattern: &str)
+    -> Vec<(&'a FileResult, Option<&'a FileResult>)>
+{
+    let mut tests: HashMap<&str, Vec<&FileResult>> = HashMap::new();
+    for file in all_files.iter().filter(|file| glob_match(test_pattern, &file.path)) {
+        tests.entry(test_subject(&file.path)).or_default().push(file);
+    }
+    let shared_dirs = |a: &str, b: &str| {
+        a.split('/').zip(b.split('/')).take_while(|(a, b)| a == b).count()
+    };
+    let mut pairs: Vec<(&FileResult, Option<&FileResult>)> = all_files.iter()
+        .filter(|file| {
+            glob_match(source_pattern, &file.path) && !glob_match(test_pattern, &file.path)
+        })
+        .map(|source| {
+            let test = tests.get(test_subject(&source.path)).and_then(|candidates| {
+                candidates.iter()
+                    .max_by_key(|test| {
+                        (shared_dirs(&source.path, &test.path), std::cmp::Reverse(&test.path))
+                    })
+                    .cloned()
+            });
+            (source, test)
+        })
+        .collect();
+    let ratio = |&(source, test): &(&FileResult, Option<&FileResult>)| {
+        source.churn_count as f64 / test.map_or(0, |test| test.churn_count) as f64
+    };
+    pairs.sort_by(|a, b| ratio(b).total_cmp(&ratio(a)).then_with(|| a.0.path.cmp(&b.0.path)));
+    pairs
+}
+
+/// Print each source file with at least `min_churn` and no test file, as
+/// `test_source_pairs` pairs them, like `[NO TEST] src/parser.rs, churn=47`,
+/// most churned first.
+fn print_untested(all_files: &[FileResult], source_pattern: &str, test_pattern: &str,
+                  min_churn: usize)
+{
+    for file in untested(all_files, source_pattern, test_pattern, min_churn) {
+        println!("[NO TEST] {}, churn={}", file.path, file.churn_count);
+    }
+}
+
+/// The files `print_untested` prints, in order.
+fn untested<'a>(all_files: &'a [FileResult], source_pattern: &str, test_pattern: &str,
+                min_churn: usize)
+    -> Vec<&'a FileResult>
+{
+    let mut untested: Vec<&FileResult> = test_source_pairs(all_files, source_pattern, test_pattern)
+        .into_iter()
+        .filter(|&(source, test)| test.is_none() && source.churn_count >= min_churn)
+        .map(|(source, _)| source)
+        .collect();
+    untested.sort_by(|a, b| b.churn_count.cmp(&a.churn_count).then_with(|| a.path.cmp(&b.path)));
+    untested
+}
+
+/// Churn totals for one group of files, and for the subgroups within it when
+/// there are several levels of `--group-by`.
+struct Group {
+    total: usize,
+    subgroups: HashMap<String, Group>,
+}
+
+impl Group {
+    fn new() -> Group {
+        Group {
+            total: 0,
+            subgroups: HashMap::new()
+        }
+    }
+
+    /// Add `churn_count` to this group and to each nested subgroup that
+    /// `path` falls into, one level per pattern.
+    fn add(&mut self, patterns: &[Regex], path: &str, churn_count: usize) {
+        self.total += churn_count;
+        if let Some((pattern, rest)) = patterns.split_first() {
+            let key = match pattern.captures(path).and_then(|c| c.get(1)) {
+                Some(m) => m.as_str(),
+                None => "<no-match>"
+            };
+            self.subgroups.entry(key.to_string()).or_insert_with(Group::new)
+                .add(rest, path, churn_count);
+        }
+    }
+
+    /// Print the subgroups, most churned first, indenting each level.
+    fn print(&self, depth: usize) {
+        let mut subgroups: Vec<(&String, &Group)> = self.subgroups.iter().collect();
+        subgroups.sort_by(|a, b| b.1.total.cmp(&a.1.total).then_with(|| a.0.cmp(b.0)));
+        for (name, group) in subgroups {
+            println!("{:7} {:indent$}{}", group.total, "", name, indent = 2 * depth);
+            group.print(depth + 1);
+        }
+    }
+}
+
+/// Find the relative path that leads from directory `from` to `to`. Both
+/// must be absolute.
+fn relative_path(from: &Path, to: &Path) -> PathBuf {
+    let from: Vec<Component> = from.components().collect();
+    let to: Vec<Component> = to.components().collect();
+    let common = from.iter().zip(&to).take_while(|&(a, b)| a == b).count();
+    let mut result = PathBuf::new();
+    for _ in common..from.len() {
+        result.push("..");
+    }
+    for c in &to[common..] {
+        result.push(c.as_os_str());
+    }
+    result
+}
+
+/// Get the string to put in front of repository-relative paths to print
+/// them in the given style. It is either empty or ends with a slash.
+fn path_prefix(repo: &Repository, style: PathStyle) -> std::io::Result<String> {
+    let workdir = match repo.workdir() {
+        Some(dir) if style != PathStyle::RepoRelative => dir.canonicalize()?,
+        // Bare repositories have no working directory for paths to be
+        // relative to.
+        _ => return Ok(String::new())
+    };
+    let base = match style {
+        PathStyle::Relative => relative_path(&std::env::current_dir()?.canonicalize()?, &workdir),
+        _ => workdir
+    };
+    let parts: Vec<String> = base.components()
+        .filter(|c| *c != Component::RootDir)
+        .map(|c| c.as_os_str().to_string_lossy().into_owned())
+        .collect();
+    let mut prefix = if base.has_root() { "/".to_string() } else { String::new() };
+    for part in parts {
+        prefix = prefix + &part + "/";
+    }
+    Ok(prefix)
+}
+
+const RED: &str = "\x1b[31m";
+const YELLOW: &str = "\x1b[33m";
+const RESET: &str = "\x1b[0m";
+
+/// Pick a color for each file in text output: red for the top 10% by churn,
+/// yellow for the rest of the top 25%, and no color for everything else.
+fn churn_colors(all_files: &[FileResult]) -> Vec<Option<&'static str>> {
+    let mut counts: Vec<usize> = all_files.iter().map(|f| f.churn_count).collect();
+    counts.sort_by(|a, b| b.cmp(a));
+    let threshold = |percent: usize| counts.get(counts.len() * percent / 100).cloned();
+    let (red, yellow) = (threshold(10), threshold(25));
+    all_files.iter()
+        .map(|f| {
+            if Some(f.churn_count) >= red {
+                Some(RED)
+            } else if Some(f.churn_count) >= yellow {
+                Some(YELLOW)
+            } else {
+                None
+            }
+        })
+        .collect()
+}
+
+const SECONDS_PER_DAY: f64 = 86400.0;
+
+/// Average number of new versions per day since the file was created. Files
+/// less than a day old just get their churn count, so brand new files don't
+/// get absurd rates.
+fn daily_rate(file: &FileResult, now: i64) -> f64 {
+    let age_days = (now - file.first_seen.unwrap_or(now)) as f64 / SECONDS_PER_DAY;
+    if age_days < 1.0 {
+        file.churn_count as f64
+    } else {
+        file.churn_count as f64 / age_days
+    }
+}
+
+/// Churn with recent versions counting for more: each version counts
+/// `exp(-lambda * age_in_days)` instead of 1, so with `lambda` 0.01, a
+/// version from 100 days ago counts about 0.37.
+fn weighted_churn(file: &FileResult, lambda: f64, now: i64) -> f64 {
+    file.version_times.iter()
+        .map(|&time| (-lambda * (now - time).max(0) as f64 / SECONDS_PER_DAY).exp())
+        .sum()
+}
+
+/// How many days back counts as recent for `stability_score`.
+const RECENT_DAYS: i64 = 30;
+
+/// A score from 0 to 100 for how settled `file` is, falling as it gets more
+/// versions, more versions in the last `RECENT_DAYS` days, and more authors:
+/// `100 / ((1 + churn) * (1 + recent churn) * (1 + authors))`. Even a file
+/// with one version, and one author, scores only 25, so the score is more
+/// use for comparing files than on its own.
+fn stability_score(file: &FileResult, now: i64) -> f64 {
+    let since = now - RECENT_DAYS * SECONDS_PER_DAY as i64;
+    let recent = file.version_times.iter().filter(|&&time| time >= since).count();
+    100.0 / ((1 + file.churn_count) * (1 + recent) * (1 + file.authors.len())) as f64
+}
+
+/// What fraction of the `commit_count` commits analyzed made a new version
+/// of `file`: 1 for a file every commit changed. A commit makes at most one
+/// new version of each file, so it's never more than that.
+fn change_ratio(file: &FileResult, commit_count: usize) -> f64 {
+    if commit_count == 0 {
+        0.0
+    } else {
+        file.churn_count as f64 / commit_count as f64
+    }
+}
+
+const SECONDS_PER_WEEK: i64 = 7 * 86400;
+
+/// A bar for each of the last `weeks` weeks, oldest first, as tall as the
+/// number of new versions of `file` that week compared to its busiest week.
+fn sparkline(file: &FileResult, weeks: usize, now: i64) -> String {
+    const BARS: [char; 8] = ['▁', '▂', '▃', '▄', '▅', '▆', '▇', '█'];
+    let mut counts = vec![0usize; weeks];
+    for &time in &file.version_times {
+        let weeks_ago = (now - time).max(0) / SECONDS_PER_WEEK;
+        if (weeks_ago as usize) < weeks {
+            counts[weeks - 1 - weeks_ago as usize] += 1;
+        }
+    }
+    let max = counts.iter().cloned().max().unwrap_or(0).max(1);
+    // Round up, so any week with changes stands out from the quiet ones.
+    counts.iter().map(|&n| BARS[(n * 7).div_ceil(max)]).collect()
+}
+
+/// Commits per day between the first and last commit times. History less
+/// than a day long counts as a day.
+fn commit_density(commit_count: usize, first: i64, last: i64) -> f64 {
+    let days = ((last - first) as f64 / SECONDS_PER_DAY).max(1.0);
+    commit_count as f64 / days
+}
+
+/// Pearson's correlation coefficient between `xs` and `ys`, which must be the
+/// same length. Returns `None` if it's undefined: fewer than two points, or
+/// either variable is constant.
+fn pearson(xs: &[f64], ys: &[f64]) -> Option<f64> {
+    let n = xs.len() as f64;
+    if xs.len() < 2 {
+        return None;
+    }
+    let mean_x = xs.iter().sum::<f64>() / n;
+    let mean_y = ys.iter().sum::<f64>() / n;
+    let mut cov = 0.0;
+    let mut var_x = 0.0;
+    let mut var_y = 0.0;
+    for (x, y) in xs.iter().zip(ys) {
+        cov += (x - mean_x) * (y - mean_y);
+        var_x += (x - mean_x) * (x - mean_x);
+        var_y += (y - mean_y) * (y - mean_y);
+    }
+    if var_x == 0.0 || var_y == 0.0 {
+        return None;
+    }
+    Some(cov / (var_x * var_y).sqrt())
+}
+
+/// The median of `sorted`, which must be sorted. Returns 0 if it's empty.
+fn median(sorted: &[usize]) -> f64 {
+    let n = sorted.len();
+    match n {
+        0 => 0.0,
+        _ if n % 2 == 1 => sorted[n / 2] as f64,
+        _ => (sorted[n / 2 - 1] + sorted[n / 2]) as f64 / 2.0,
+    }
+}
+
+/// The Gini coefficient of `sorted`, which must be sorted smallest first:
+/// 0 if every file churns the same, approaching 1 if one file has all the
+/// churn.
+fn gini(sorted: &[usize]) -> f64 {
+    let n = sorted.len() as f64;
+    let total: usize = sorted.iter().sum();
+    if total == 0 {
+        return 0.0;
+    }
+    let weighted: f64 = sorted.iter().enumerate()
+        .map(|(i, &x)| (i + 1) as f64 * x as f64)
+        .sum();
+    2.0 * weighted / (n * total as f64) - (n + 1.0) / n
+}
+
+/// Print statistics about the churn of `all_files`, which came from
+/// analyzing `commit_count` commits.
+fn print_summary(all_files: &[FileResult], commit_count: usize, skipped_objects: usize) {
+    let mut counts: Vec<usize> = all_files.iter().map(|f| f.churn_count).collect();
+    counts.sort();
+    let total: usize = counts.iter().sum();
+    let mean = if counts.is_empty() { 0.0 } else { total as f64 / counts.len() as f64 };
+    println!();
+    println!("Files analyzed:   {}", all_files.len());
+    println!("Commits analyzed: {}", commit_count);
+    println!("Median churn:     {}", median(&counts));
+    println!("Mean churn:       {:.1}", mean);
+    if let Some(top) = all_files.iter().max_by_key(|f| f.churn_count) {
+        println!("Max churn:        {} ({})", top.churn_count, top.path);
+    }
+    println!("Gini coefficient: {:.3}", gini(&counts));
+    if skipped_objects > 0 {
+        println!("Skipped objects:  {}", skipped_objects);
+    }
+}
+
+/// Print the totals for the analysis of `commit_count` commits made in
+/// `time_range`, and the most churned file, for `--summary-only`. The
+/// results are added up as they're found, without a list of them all.
+fn print_summary_only(root_dir: &DirData, repo: &Repository, start: Oid,
+                      churn_options: &ChurnOptions, commit_count: usize,
+                      time_range: Option<(i64, i64)>)
+    -> Result<(), Error>
+{
+    let (mut file_count, mut total) = (0, 0);
+    let mut top: Option<(String, usize)> = None;
+    root_dir.each_result(repo, start, churn_options, |file| {
+        file_count += 1;
+        total += file.churn_count;
+        if top.as_ref().is_none_or(|&(_, churn)| file.churn_count > churn) {
+            top = Some((file.path, file.churn_count));
+        }
+    })?;
+    println!("Commits analyzed: {}", commit_count);
+    if let Some((first, last)) = time_range {
+        println!("Date range:       {} to {}", churn::format_date(git2::Time::new(first, 0)),
+                 churn::format_date(git2::Time::new(last, 0)));
+    }
+    println!("Files analyzed:   {}", file_count);
+    println!("Total churn:      {}", total);
+    if let Some((path, churn)) = top {
+        println!("Hottest file:     {} ({})", path, churn);
+    }
+    Ok(())
+}
+
+/// Print the mean, median, standard deviation and maximum churn of
+/// `all_files`, to help judge what counts as high churn in this repository.
+fn print_stats(all_files: &[FileResult]) {
+    let mut counts: Vec<usize> = all_files.iter().map(|f| f.churn_count).collect();
+    counts.sort();
+    let n = counts.len() as f64;
+    let mean = if counts.is_empty() { 0.0 } else { counts.iter().sum::<usize>() as f64 / n };
+    let variance = if counts.is_empty() {
+        0.0
+    } else {
+        counts.iter().map(|&c| (c as f64 - mean) * (c as f64 - mean)).sum::<f64>() / n
+    };
+    println!();
+    println!("Files analyzed: {}", all_files.len());
+    println!("Mean churn: {:.1}", mean);
+    println!("Median churn: {}", median(&counts));
+    println!("Std dev: {:.1}", variance.sqrt());
+    if let Some(top) = all_files.iter().max_by_key(|f| f.churn_count) {
+        println!("Max churn: {} ({})", top.churn_count, top.path);
+    }
+}
+
+/// Print the process's peak resident memory, where the system tells us, and
+/// an estimate of how much of it the analysis records take, to stderr.
+fn report_memory(root_dir: &DirData) {
+    const MB: f64 = 1024.0 * 1024.0;
+    match peak_rss_bytes() {
+        Some(bytes) => eprintln!("Peak memory (RSS):     {:.1} MB", bytes as f64 / MB),
+        None => eprintln!("Peak memory (RSS):     unknown on this system"),
+    }
+    eprintln!("Analysis records:      {:.1} MB (estimated)",
+              root_dir.estimated_heap_bytes() as f64 / MB);
+}
+
+/// The process's peak resident set size, from the `VmHWM` line of
+/// `/proc/self/status`. Only Linux has that.
+fn peak_rss_bytes() -> Option<u64> {
+    let status = std::fs::read_to_string("/proc/self/status").ok()?;
+    let line = status.lines().find(|line| line.starts_with("VmHWM:"))?;
+    let kb: u64 = line["VmHWM:".len()..].trim().trim_end_matches("kB").trim().parse().ok()?;
+    Some(kb * 1024)
+}
+
+/// Commits that make new versions of more files than this are left out of
+/// `--coupling`: a mass rename or reformat would couple everything with
+/// everything, and the pairs would fill memory.
+const MAX_COUPLED_FILES: usize = 100;
+
+/// Count each pair of files in `changed`, the files one commit made new
+/// versions of, as changed together once more.
+fn add_coupling(pairs: &mut HashMap<(String, String), usize>, mut changed: Vec<String>) {
+    if changed.len() > MAX_COUPLED_FILES {
+        return;
+    }
+    changed.sort();
+    changed.dedup();
+    for (i, a) in changed.iter().enumerate() {
+        for b in &changed[i + 1..] {
+            *pairs.entry((a.clone(), b.clone())).or_insert(0) += 1;
+        }
+    }
+}
+
+/// The pairs in `pairs` where both files are in `all_files`, with how many
+/// commits changed them together, most first.
+fn coupled_pairs<'a>(pairs: &'a HashMap<(String, String), usize>, all_files: &[FileResult])
+    -> Vec<(&'a str, &'a str, usize)>
+{
+    let paths: HashSet<&str> =
+        all_files.iter().map(|file| file.path.as_str()).collect();
+    let mut coupled: Vec<(&str, &str, usize)> = pairs.iter()
+        .filter(|&((a, b), _)| paths.contains(a.as_str()) && paths.contains(b.as_str()))
+        .map(|((a, b), &count)| (a.as_str(), b.as_str(), count))
+        .collect();
+    coupled.sort_by(|x, y| y.2.cmp(&x.2).then_with(|| (x.0, x.1).cmp(&(y.0, y.1))));
+    coupled
+}
+
+/// Escape `text` for an XML attribute or element.
+fn xml_escape(text: &str) -> String {
+    text.replace('&', "&amp;").replace('<', "&lt;").replace('>', "&gt;").replace('"', "&quot;")
+}
+
+/// Print the coupling graph as GraphML: a node per file, with its churn,
+/// and an edge per pair, weighted by how many commits changed both.
+fn print_graphml(pairs: &[(&str, &str, usize)], all_files: &[FileResult]) {
+    println!("<?xml version=\"1.0\" encoding=\"UTF-8\"?>");
+    println!("<graphml xmlns=\"http://graphml.graphdrawing.org/xmlns\">");
+    println!("  <key id=\"churn\" for=\"node\" attr.name=\"churn\" attr.type=\"int\"/>");
+    println!("  <key id=\"weight\" for=\"edge\" attr.name=\"weight\" attr.type=\"int\"/>");
+    println!("  <graph id=\"coupling\" edgedefault=\"undirected\">");
+    let coupled: HashSet<&str> =
+        pairs.iter().flat_map(|&(a, b, _)| vec![a, b]).collect();
+    for file in all_files.iter().filter(|file| coupled.contains(file.path.as_str())) {
+        println!("    <node id=\"{}\"><data key=\"churn\">{}</data></node>",
+                 xml_escape(&file.path), file.churn_count);
+    }
+    for &(a, b, count) in pairs {
+        println!("    <edge source=\"{}\" target=\"{}\"><data key=\"weight\">{}</data></edge>",
+                 xml_escape(a), xml_escape(b), count);
+    }
+    println!("  </graph>");
+    println!("</graphml>");
+}
+
+/// Print a row per file with how many new versions it got in each `period`,
+/// from the first period any file changed in to the last. Periods with
+/// none get a 0, not an empty field, so the columns are all numbers.
+fn print_csv_wide(all_files: &[FileResult], period: Period, header: bool) {
+    let numbers = all_files.iter()
+        .flat_map(|file| &file.version_times)
+        .map(|&time| period.number(time));
+    let (first, last) = numbers.fold((i64::MAX, i64::MIN), |(first, last), n| {
+        (first.min(n), last.max(n))
+    });
+    let periods = if first <= last { first..last + 1 } else { 0..0 };
+    if header {
+        let labels: Vec<String> = periods.clone().map(|n| period.label(n)).collect();
+        if labels.is_empty() {
+            println!("path");
+        } else {
+            println!("path,{}", labels.join(","));
+        }
+    }
+    for file in all_files {
+        let mut counts = vec![0usize; periods.clone().count()];
+        for &time in &file.version_times {
+            counts[(period.number(time) - first) as usize] += 1;
+        }
+        let mut row = quote_field(&file.path, ',');
+        for count in counts {
+            row += &format!(",{}", count);
+        }
+        println!("{}", row);
+    }
+}
+
+/// Print a Graphviz graph of when `all_files` changed: a node for each month
+/// from the first version to the last, and an edge into each one labeled
+/// with how many new versions it brought, starting from a point before the
+/// first month.
+fn print_dot_timeline(all_files: &[FileResult]) {
+    let mut months: BTreeMap<(i32, u32), usize> = BTreeMap::new();
+    for &time in all_files.iter().flat_map(|file| &file.version_times) {
+        let date = churn::format_datetime(time);
+        let month = (date[..4].parse().unwrap(), date[5..7].parse().unwrap());
+        *months.entry(month).or_insert(0) += 1;
+    }
+    println!("digraph timeline {{");
+    println!("  rankdir=LR;");
+    println!("  node [shape=box];");
+    println!("  start [shape=point];");
+    if let (Some(&first), Some(&last)) = (months.keys().next(), months.keys().next_back()) {
+        let mut previous = "start".to_string();
+        let (mut year, mut month) = first;
+        while (year, month) <= last {
+            let name = format!("\"{}-{:02}\"", year, month);
+            let count = months.get(&(year, month)).cloned().unwrap_or(0);
+            println!("  {} -> {} [label=\"{}\"];", previous, name, count);
+            previous = name;
+            if month == 12 {
+                year += 1;
+                month = 1;
+            } else {
+                month += 1;
+            }
+        }
+    }
+    println!("}}");
+}
+
+/// Print an `age_days, churn_count` line for each file, then the
+/// correlation between the two as a `#` comment, which most plotting tools
+/// skip when loading the data.
+fn print_correlation(all_files: &[FileResult], now: i64) {
+    let ages: Vec<f64> = all_files.iter()
+        .map(|f| (now - f.first_seen.unwrap_or(now)) as f64 / SECONDS_PER_DAY)
+        .collect();
+    let churns: Vec<f64> = all_files.iter().map(|f| f.churn_count as f64).collect();
+    for (age, file) in ages.iter().zip(all_files) {
+        println!("{:.1}, {}", age, file.churn_count);
+    }
+    match pearson(&ages, &churns) {
+        Some(r) => println!("# pearson_r = {:.4}", r),
+        None => println!("# pearson_r = undefined"),
+    }
+}
+
+/// Print each file where a single author made more than `threshold` of the
+/// changes, like `src/parser.rs, 42 total churn, 36 (86%) by Jane Doe`, with
+/// the most one-sided files first.
+fn print_bus_factor_risk(all_files: &[FileResult], threshold: f64) {
+    let mut risky: Vec<(&FileResult, &str, usize, f64)> = all_files.iter()
+        .filter_map(|f| {
+            let &(ref name, count) = f.authors.first()?;
+            let share = count as f64 / f.churn_count as f64;
+            if share > threshold { Some((f, name.as_str(), count, share)) } else { None }
+        })
+        .collect();
+    risky.sort_by(|a, b| b.3.total_cmp(&a.3).then(b.0.churn_count.cmp(&a.0.churn_count)));
+    for (file, name, count, share) in risky {
+        println!("{}, {} total churn, {} ({:.0}%) by {}",
+                 file.path, file.churn_count, count, share * 100.0, name);
+    }
+}
+
+/// For each file, print a `== path ==` line, then a line for each commit
+/// that made a new version of it, oldest first, like
+/// `abc1234 2023-06-15 "Fix the parser"`.
+fn print_version_commits(repo: &Repository, all_files: &[FileResult]) -> Result<(), Error> {
+    for file in all_files {
+        println!("== {} ==", file.path);
+        for &sha in &file.version_commits {
+            let commit = repo.find_commit(sha)?;
+            println!("{} {} \"{}\"", &sha.to_string()[..7], churn::format_date(commit.time()),
+                     commit.summary().unwrap_or(""));
+        }
+    }
+    Ok(())
+}
+
+/// How many files `--with-blame-summary` looks at.
+const BLAME_FILES: usize = 10;
+
+/// How many lines of `path` as of `start` each author wrote, according to
+/// `git blame`, and how many lines there are in all.
+fn blame_lines(repo: &Repository, start: Oid, path: &str)
+    -> Result<(HashMap<String, usize>, usize), Error>
+{
+    let mut blame_options = git2::BlameOptions::new();
+    blame_options.newest_commit(start);
+    let blame = repo.blame_file(Path::new(path), Some(&mut blame_options))?;
+    let mut lines_by_author: HashMap<String, usize> = HashMap::new();
+    let mut total_lines = 0;
+    for hunk in blame.iter() {
+        let name = hunk.final_signature().name().unwrap_or("").to_string();
+        *lines_by_author.entry(name).or_insert(0) += hunk.lines_in_hunk();
+        total_lines += hunk.lines_in_hunk();
+    }
+    Ok((lines_by_author, total_lines))
+}
+
+/// The author who wrote the most of `lines_by_author`, and how many lines.
+/// Ties go to the name that sorts first.
+fn primary_author(lines_by_author: &HashMap<String, usize>) -> Option<(&str, usize)> {
+    lines_by_author.iter()
+        .max_by(|a, b| a.1.cmp(b.1).then(b.0.cmp(a.0)))
+        .map(|(name, &lines)| (name.as_str(), lines))
+}
+
+/// For each of the most churned files still present in `start`, print who
+/// wrote the most lines of it as of `start`, like
+/// `src/parser.rs, 42, Jane Doe (63% of lines)`.
+fn print_blame_summary(repo: &Repository, start: Oid, all_files: &[FileResult])
+    -> Result<(), Error>
+{
+    for file in all_files.iter().filter(|f| !f.deleted).take(BLAME_FILES) {
+        let (lines_by_author, total_lines) = blame_lines(repo, start, &file.path)?;
+        match primary_author(&lines_by_author) {
+            Some((name, lines)) if total_lines > 0 => {
+                println!("{}, {}, {} ({:.0}% of lines)", file.path, file.churn_count, name,
+                         lines as f64 * 100.0 / total_lines as f64);
+            }
+            _ => println!("{}, {}, (empty)", file.path, file.churn_count),
+        }
+    }
+    Ok(())
+}
+
+/// For each file still present in `start`, print the author who made the
+/// most of its versions, the one who wrote the most of its lines as of
+/// `start`, and what percentage of those lines the first one wrote, like
+/// `src/parser.rs, Jane Doe, John Roe, 20%`. A low percentage means the
+/// file has changed hands since most of its churn.
+fn print_blame_coupling(repo: &Repository, start: Oid, all_files: &[FileResult])
+    -> Result<(), Error>
+{
+    for file in all_files.iter().filter(|f| !f.deleted) {
+        let churn_author = match file.authors.first() {
+            Some((name, _)) => name,
+            None => continue,
+        };
+        let (lines_by_author, total_lines) = blame_lines(repo, start, &file.path)?;
+        let blame_author = primary_author(&lines_by_author).map_or("(empty)", |(name, _)| name);
+        let matched = lines_by_author.get(churn_author).cloned().unwrap_or(0);
+        let percent = if total_lines == 0 {
+            0.0
+        } else {
+            matched as f64 * 100.0 / total_lines as f64
+        };
+        println!("{}, {}, {}, {:.0}%", file.path, churn_author, blame_author, percent);
+    }
+    Ok(())
+}
+
+/// The current time in seconds since the epoch.
+fn now() -> i64 {
+    std::time::SystemTime::now()
+        .duration_since(std::time::UNIX_EPOCH)
+        .map(|d| d.as_secs() as i64)
+        .unwrap_or(0)
+}
+
+/// A progress bar on stderr, with an estimate of the time left.
+struct Progress {
+    total: usize,
+    start: Instant,
+
+    /// How many commits to analyze before the average time per commit is a
+    /// good enough guess to project from.
+    warmup: usize,
+}
+
+impl Progress {
+    fn new(total: usize) -> Progress {
+        Progress {
+            total,
+            start: Instant::now(),
+            warmup: (total / 100).clamp(1, 1000),
+        }
+    }
+
+    /// Redraw the bar after `done` commits, if it's time to.
+    fn update(&self, done: usize) {
+        if !done.is_multiple_of(PROGRESS_INTERVAL) && done != self.total {
+            return;
+        }
+        let filled = PROGRESS_BAR_WIDTH * done / self.total;
+        let mut bar = "=".repeat(filled);
+        if filled < PROGRESS_BAR_WIDTH {
+            bar.push('>');
+            bar += &" ".repeat(PROGRESS_BAR_WIDTH - filled - 1);
+        }
+        let eta = if done >= self.warmup {
+            let per_commit = self.start.elapsed().as_secs_f64() / done as f64;
+            let secs = (per_commit * (self.total - done) as f64).round() as u64;
+            format!("{:02}:{:02}:{:02}", secs / 3600, secs / 60 % 60, secs % 60)
+        } else {
+            "--:--:--".to_string()
+        };
+        eprint!("\r[{}] {:3}% ETA {}", bar, done * 100 / self.total, eta);
+        std::io::stderr().flush().unwrap();
+    }
+}
+
+/// Find the commit that tag `name` points to. Lightweight tags point right
+/// at it; annotated tags point at a tag object that points at it.
+fn tag_commit(repo: &Repository, name: &str) -> Result<Oid, Error> {
+    match repo.revparse_single(&format!("refs/tags/{}", name)) {
+        Ok(object) => Ok(object.peel(ObjectType::Commit)?.id()),
+        Err(ref e) if e.code() == ErrorCode::NotFound => {
+            let tags = repo.tag_names(None)?;
+            let names: Vec<&str> = tags.iter().flatten().collect();
+            let available = if names.is_empty() { "none".to_string() } else { names.join(", ") };
+            Err(Error::from_str(&format!("tag '{}' not found; available tags: {}", name, available)))
+        }
+        Err(e) => Err(e),
+    }
+}
+
+/// True if HEAD points straight at a commit rather than at a branch. A
+/// branch with no commits yet doesn't count as detached.
+fn head_detached(repo: &Repository) -> Result<bool, Error> {
+    match repo.head() {
+        Ok(head) => Ok(!head.is_branch()),
+        Err(ref e) if e.code() == ErrorCode::UnbornBranch => Ok(false),
+        Err(e) => Err(e),
+    }
+}
+
+/// Find the commit that `name`, a branch, tag or other revision, points to.
+fn ref_commit(repo: &Repository, name: &str) -> Result<Oid, Error> {
+    match repo.revparse_single(name) {
+        Ok(object) => Ok(object.peel(ObjectType::Commit)?.id()),
+        Err(ref e) if e.code() == ErrorCode::NotFound => {
+            Err(Error::from_str(&format!("no branch, tag or commit named '{}'", name)))
+        }
+        Err(e) => Err(e),
+    }
+}
+
+/// True if `--ref` was given a pattern rather than one name.
+fn is_glob(name: &str) -> bool {
+    name.contains(['*', '?', '['])
+}
+
+/// The commits every ref matching `pattern`, like `refs/heads/feature-*`,
+/// points to, newest first. Refs pointing at the same commit only give it
+/// once.
+fn glob_commits(repo: &Repository, pattern: &str) -> Result<Vec<Oid>, Error> {
+    let mut commits = vec![];
+    for reference in repo.references_glob(pattern)? {
+        let reference = reference?;
+        let name = match reference.name() {
+            Some(name) => name,
+            None => continue,
+        };
+        let id = ref_commit(repo, name)?;
+        if !commits.iter().any(|&(other, _)| other == id) {
+            commits.push((id, repo.find_commit(id)?.time().seconds()));
+        }
+    }
+    if commits.is_empty() {
+        return Err(Error::from_str(&format!("no refs match '{}'", pattern)));
+    }
+    commits.sort_by_key(|&(_, time)| std::cmp::Reverse(time));
+    Ok(commits.into_iter().map(|(id, _)| id).collect())
+}
+
+/// Print `path, base_churn, branch_churn, delta` for each file changed on
+/// either branch since they split, counting only the commits that aren't on
+/// the other. Biggest differences first.
+fn print_comparison(repo: &Repository, base: &str, branch: &str, options: &Options)
+    -> Result<(), Error>
+{
+    let base_id = ref_commit(repo, base)?;
+    let branch_id = ref_commit(repo, branch)?;
+    let mut counts: BTreeMap<String, (usize, usize)> = BTreeMap::new();
+    for &(start, other, is_branch) in &[(base_id, branch_id, false), (branch_id, base_id, true)] {
+        let churn_options = options.churn.with_skipped_commits(churn::reachable_commits(repo, other)?);
+        for file in churn::analyze_with_options(repo, start, &churn_options)? {
+            let entry = counts.entry(file.path).or_insert((0, 0));
+            if is_branch {
+                entry.1 = file.churn_count;
+            } else {
+                entry.0 = file.churn_count;
+            }
+        }
+    }
+    let mut rows: Vec<(String, usize, usize, i64)> = counts.into_iter()
+        .map(|(path, (base_churn, branch_churn))| {
+            (path, base_churn, branch_churn, branch_churn as i64 - base_churn as i64)
+        })
+        .collect();
+    rows.sort_by_key(|row| std::cmp::Reverse(row.3.abs()));
+    for (path, base_churn, branch_churn, delta) in rows {
+        println!("{}, {}, {}, {:+}", path, base_churn, branch_churn, delta);
+    }
+    Ok(())
+}
+
+/// Find the last release before `start`: the annotated tag, reachable from
+/// `start`, on the most recent commit. Returns the tag's name and commit.
+/// Lightweight tags don't count, as with `git describe`.
+fn last_release(repo: &Repository, start: Oid) -> Result<Option<(String, Oid)>, Error> {
+    let reachable = churn::reachable_commits(repo, start)?;
+    let mut best: Option<(String, Oid, i64)> = None;
+    for name in repo.tag_names(None)?.iter().flatten() {
+        let object = repo.revparse_single(&format!("refs/tags/{}", name))?;
+        if object.kind() != Some(ObjectType::Tag) {
+            continue;
+        }
+        let commit_id = object.peel(ObjectType::Commit)?.id();
+        if !reachable.contains(&commit_id) {
+            continue;
+        }
+        let time = repo.find_commit(commit_id)?.time().seconds();
+        if best.as_ref().is_none_or(|&(_, _, best_time)| time > best_time) {
+            best = Some((name.to_string(), commit_id, time));
+        }
+    }
+    Ok(best.map(|(name, commit_id, _)| (name, commit_id)))
+}
+
+/// The commits tags point to, for `--only-tagged`, and the `within`
+/// commits before each, following first parents. Tags on trees or blobs
+/// are left out.
+fn tagged_commits(repo: &Repository, within: usize) -> Result<HashSet<Oid>, Error> {
+    let mut commits = HashSet::new();
+    for name in repo.tag_names(None)?.iter().flatten() {
+        let object = repo.revparse_single(&format!("refs/tags/{}", name))?;
+        let mut commit = match object.peel(ObjectType::Commit) {
+            Ok(object) => repo.find_commit(object.id())?,
+            Err(_) => continue,
+        };
+        for _ in 0..within {
+            commits.insert(commit.id());
+            commit = match commit.parent(0) {
+                Ok(parent) => parent,
+                Err(_) => break,
+            };
+        }
+        commits.insert(commit.id());
+    }
+    if commits.is_empty() {
+        return Err(Error::from_str("--only-tagged found no tagged commits"));
+    }
+    Ok(commits)
+}
+
+/// What `--repo-info` says about the repository.
+struct RepoInfo {
+    path: PathBuf,
+    head: Oid,
+
+    /// The branch HEAD is on, or `None` when it's detached.
+    branch: Option<String>,
+
+    branches: usize,
+    tags: usize,
+
+    /// Total size of the files in the `.git` directory.
+    size_bytes: u64,
+}
+
+impl RepoInfo {
+    fn read(repo: &Repository) -> Result<RepoInfo, Error> {
+        let head = repo.head()?;
+        let branch = if head_detached(repo)? {
+            None
+        } else {
+            head.shorthand().map(|name| name.to_string())
+        };
+        let git_dir = repo.path();
+        let size_bytes = dir_size(git_dir).map_err(|e| Error::from_str(&format!(
+            "can't read {}: {}", git_dir.display(), e)))?;
+        Ok(RepoInfo {
+            path: repo.workdir().unwrap_or(git_dir).to_path_buf(),
+            head: repo.revparse_single("HEAD")?.id(),
+            branch,
+            branches: repo.branches(Some(git2::BranchType::Local))?.count(),
+            tags: repo.tag_names(None)?.len(),
+            size_bytes,
+        })
+    }
+
+    /// Print the header block for text output.
+    fn print(&self) {
+        const MB: f64 = 1024.0 * 1024.0;
+        println!("Repository: {}", self.path.display());
+        println!("HEAD:       {} ({})", &self.head.to_string()[..7],
+                 self.branch.as_deref().unwrap_or("detached"));
+        println!("Branches:   {}", self.branches);
+        println!("Tags:       {}", self.tags);
+        println!("Size:       {:.1} MB", self.size_bytes as f64 / MB);
+        println!();
+    }
+
+    /// The `metadata` object for the JSON envelope.
+    fn to_json(&self) -> serde_json::Value {
+        let mut object = serde_json::Map::new();
+        object.insert("path".to_string(), self.path.display().to_string().into());
+        object.insert("head".to_string(), self.head.to_string().into());
+        object.insert("branch".to_string(), match self.branch {
+            Some(ref branch) => branch.clone().into(),
+            None => serde_json::Value::Null,
+        });
+        object.insert("branch_count".to_string(), self.branches.into());
+        object.insert("tag_count".to_string(), self.tags.into());
+        object.insert("size_bytes".to_string(), self.size_bytes.into());
+        object.into()
+    }
+}
+
+/// The total size of the files under `dir`. Symbolic links aren't followed.
+fn dir_size(dir: &Path) -> std::io::Result<u64> {
+    let mut total = 0;
+    for entry in std::fs::read_dir(dir)? {
+        let entry = entry?;
+        let file_type = entry.file_type()?;
+        if file_type.is_dir() {
+            total += dir_size(&entry.path())?;
+        } else if file_type.is_file() {
+            total += entry.metadata()?.len();
+        }
+    }
+    Ok(total)
+}
+
+fn run(dirname: &str, options: &Options) -> Result<(), git2::Error> {
+    let repo = match options.git_dir {
+        Some(ref git_dir) => churn::open_git_dir(git_dir, options.work_tree.as_deref()),
+        None => churn::open_repository(dirname),
+    };
+    let repo = repo.map_err(|e| match (e.code(), options.git_dir.as_ref()) {
+        (ErrorCode::NotFound, Some(git_dir)) => Error::from_str(&format!(
+            "No git directory at '{}'", git_dir.display())),
+        (ErrorCode::NotFound, None) => Error::from_str(&format!(
+            "No git repository found at path '{}'. Did you mean to run from a different directory?",
+            dirname)),
+        _ => e,
+    })?;
+    if let Some((ref base, ref branch)) = options.compare {
+        return print_comparison(&repo, base, branch, options);
+    }
+    let spec = "HEAD";
+
+    let mut root_dir: DirData = DirData::new();
+
+    let mut extra_starts = vec![];
+    let id:Oid = match (&options.tag, &options.start_ref) {
+        (Some(tag), _) => tag_commit(&repo, tag)?,
+        (None, Some(name)) if is_glob(name) => {
+            let mut starts = glob_commits(&repo, name)?;
+            extra_starts = starts.split_off(1);
+            starts[0]
+        }
+        (None, Some(name)) => ref_commit(&repo, name)?,
+        (None, None) => {
+            // In a new repository HEAD names a branch with no commits yet.
+            // That's not an error; there's just nothing to analyze.
+            if let Err(e) = repo.head() {
+                if e.code() == ErrorCode::UnbornBranch {
+                    // Programs reading ndjson expect no lines at all.
+                    if options.format.is_for_programs() {
+                        eprintln!("repository has no commits yet");
+                    } else {
+                        println!("repository has no commits yet");
+                    }
+                    return Ok(());
+                }
+            }
+            let id = repo.revparse_single(spec)?.id();
+            // Common on CI, and after `git checkout <sha>`. Worth a mention,
+            // since HEAD then isn't the tip of any branch.
+            if head_detached(&repo)? {
+                eprintln!("(analyzing detached HEAD at {})", &id.to_string()[..7]);
+            }
+            id
+        }
+    };
+    if options.repo_info && options.format == Format::Text {
+        RepoInfo::read(&repo)?.print();
+    }
+    let shallow = repo.is_shallow();
+    let mut skipped = if options.since_release {
+        let (tag, release) = match last_release(&repo, id)? {
+            Some(release) => release,
+            None => return Err(Error::from_str(
+                "--since-release found no annotated tag reachable from the starting commit")),
+        };
+        if options.verbose {
+            println!("counting changes since {}", tag);
+        }
+        Some(churn::reachable_commits(&repo, release)?)
+    } else if let Some(ref branch) = options.merge_base {
+        let base = repo.merge_base(id, ref_commit(&repo, branch)?).map_err(|e| match e.code() {
+            ErrorCode::NotFound => Error::from_str(&format!(
+                "HEAD and '{}' have no commits in common", branch)),
+            _ => e,
+        })?;
+        if options.verbose {
+            println!("counting changes since {}", &base.to_string()[..7]);
+        }
+        Some(churn::reachable_commits(&repo, base)?)
+    } else {
+        None
+    };
+    if let Some(ref pattern) = options.exclude_merges_from {
+        skipped.get_or_insert_with(HashSet::new)
+            .extend(churn::merged_branch_commits(&repo, id, pattern)?);
+    }
+    let churn_options = match skipped {
+        Some(ref commits) => options.churn.with_skipped_commits(commits.clone()),
+        None => options.churn.clone(),
+    };
+    let mut churn_options = churn_options.with_extra_starts(extra_starts);
+    if let Some(within) = options.only_tagged {
+        churn_options = churn_options.with_only_commits(tagged_commits(&repo, within)?);
+    }
+    let commit_ids = churn::find_commits(&repo, id, &churn_options)?;
+    if options.commit_count_only {
+        // Just the walk: no trees get read, so this is quick even where
+        // the full analysis isn't.
+        println!("{} commits", commit_ids.len());
+        return Ok(());
+    }
+    // Which commits are refactors only shows once their new versions have
+    // been counted, so leaving them out takes a walk of its own first.
+    let refactors = if options.exclude_refactoring {
+        find_refactors(&repo, &commit_ids, &churn_options, options)?
+    } else {
+        HashMap::new()
+    };
+    let churn_options = if refactors.is_empty() {
+        churn_options
+    } else {
+        let mut commits = skipped.unwrap_or_default();
+        commits.extend(refactors.keys().cloned());
+        churn_options.with_skipped_commits(commits)
+    };
+    // Nothing here looks at the commits one by one, so each start's history
+    // can be walked at the same time as the others'.
+    let parallel = churn_options.walks_in_parallel() && !options.verbose && !options.stream &&
+        !options.coupling && !options.commit_stats && !options.show_refactoring &&
+        options.hot_streak.is_none() && !options.write_notes && !options.cumulative &&
+        !options.per_commit && !options.density && !options.summary_only;
+    let serial_ids: &[Oid] = if parallel {
+        root_dir = churn::walk_in_parallel(&repo, id, &commit_ids, &churn_options)?;
+        &[]
+    } else {
+        &commit_ids
+    };
+    // The progress bar redraws itself with carriage returns, which would
+    // just make a mess of a log file.
+    let progress = if !parallel && !options.verbose && !options.stream &&
+        atty::is(atty::Stream::Stderr)
+    {
+        Some(Progress::new(commit_ids.len()))
+    } else {
+        None
+    };
+    let mut versions_per_day = BTreeMap::new();
+    let mut skipped = 0;
+    let mut case_merges = 0;
+    let mut notes = vec![];
+    let mut coupling = HashMap::new();
+    let mut commit_stats = vec![];
+    let recent = match options.hot_streak {
+        Some((_, n)) => most_recent_commits(&repo, &commit_ids, n)?,
+        None => HashSet::new(),
+    };
+    let mut recent_changes: HashMap<String, usize> = HashMap::new();
+    let mut time_range: Option<(i64, i64)> = None;
+    let mut per_commit = vec![];
+    #[cfg(feature = "tracing")]
+    let walk_span = tracing::span!(tracing::Level::DEBUG, "revwalk",
+                                   commit_count = commit_ids.len()).entered();
+    for (n, &commit_id) in serial_ids.iter().enumerate() {
+        #[cfg(feature = "tracing")]
+        let _span = tracing::span!(tracing::Level::TRACE, "commit",
+                                   commit_sha = %commit_id).entered();
+        let commit = repo.find_commit(commit_id)?;
+        let time = commit.time().seconds();
+        time_range = Some(time_range.map_or((time, time), |(first, last)| {
+            (first.min(time), last.max(time))
+        }));
+        if options.verbose {
+            let summary = commit.message().and_then(|m| m.lines().next()).unwrap_or("");
+            println!("[{}/{}] {} {} \"{}\"", n + 1, commit_ids.len(),
+                     &commit_id.to_string()[..7], churn::format_date(commit.time()), summary);
+        }
+        let mut touched_churn = vec![];
+        let new_versions = if options.stream || options.coupling || options.commit_stats ||
+            options.show_refactoring || recent.contains(&commit_id)
+        {
+            let sha = commit_id.to_string();
+            let mut changed = vec![];
+            let new_versions = root_dir.update_for_commit_with(&repo, &commit, &churn_options,
+                                                               |path, count| {
+                if options.stream {
+                    println!("{} {} {}", sha, path, count);
+                }
+                if options.coupling || options.commit_stats {
+                    changed.push(path.to_string());
+                }
+                if recent.contains(&commit_id) {
+                    *recent_changes.entry(path.to_string()).or_insert(0) += 1;
+                }
+                if options.show_refactoring {
+                    touched_churn.push(count);
+                }
+            })?;
+            if options.commit_stats {
+                commit_stats.push(CommitRecord::new(&commit, &changed, new_versions));
+            }
+            if options.coupling {
+                add_coupling(&mut coupling, changed);
+            }
+            new_versions
+        } else {
+            root_dir.update_for_commit(&repo, &commit, &churn_options)?
+        };
+        if options.write_notes {
+            notes.push((commit_id, new_versions));
+        }
+        if options.show_refactoring {
+            // Refactors left out of the count were found beforehand.
+            let refactor = refactors.get(&commit_id).cloned()
+                .or_else(|| refactor_stats(&touched_churn, options));
+            if let Some((files, mean_churn)) = refactor {
+                let line = format!("[refactor] {} \"{}\": {} files, average churn {:.1}",
+                                   &commit_id.to_string()[..7], commit.summary().unwrap_or(""),
+                                   files, mean_churn);
+                if options.verbose {
+                    println!("{}", line);
+                } else {
+                    eprintln!("{}", line);
+                }
+            }
+        }
+        for &(sha, kind) in &root_dir.skipped_objects()[skipped..] {
+            eprintln!("warning: skipping corrupt {} object {}", kind.str(), sha);
+        }
+        skipped = root_dir.skipped_objects().len();
+        for (first, other) in &root_dir.case_merges()[case_merges..] {
+            eprintln!("warning: treating {} and {} as the same path", first, other);
+        }
+        case_merges = root_dir.case_merges().len();
+        if options.cumulative {
+            *versions_per_day.entry(churn::format_date(commit.time())).or_insert(0) += new_versions;
+        }
+        if options.per_commit {
+            let author = commit.author().name().unwrap_or("").to_string();
+            per_commit.push((commit_id, author, commit.time(), new_versions));
+        }
+
+        if let Some(ref progress) = progress {
+            progress.update(n + 1);
+        }
+    }
+    #[cfg(feature = "tracing")]
+    drop(walk_span);
+    if progress.is_some() {
+        eprintln!();
+    }
+    for &(sha, kind) in &root_dir.skipped_objects()[skipped..] {
+        eprintln!("warning: skipping corrupt {} object {}", kind.str(), sha);
+    }
+    if options.check_integrity {
+        root_dir.check_integrity()
+            .map_err(|e| Error::from_str(&format!("integrity check failed: {}", e)))?;
+    }
+    if options.write_notes {
+        write_notes(&repo, &notes)?;
+    }
+    if options.report_memory {
+        report_memory(&root_dir);
+    }
+    if shallow {
+        let note = format!("(repository is shallow; analysis covers only {} commits. \
+                            Run `git fetch --unshallow` to get the full history.)",
+                           commit_ids.len());
+        if options.format.is_for_programs() {
+            eprintln!("{}", note);
+        } else {
+            println!("{}", note);
+        }
+    }
+    if options.density {
+        if let Some((first, last)) = time_range {
+            let line = format!("Repository density: {:.2} commits/day",
+                               commit_density(commit_ids.len(), first, last));
+            if options.format.is_for_programs() {
+                eprintln!("{}", line);
+            } else {
+                println!("{}", line);
+            }
+        }
+    }
+
+    if options.stream {
+        return Ok(());
+    }
+    if options.summary_only {
+        return print_summary_only(&root_dir, &repo, id, &churn_options, commit_ids.len(),
+                                  time_range);
+    }
+    if options.cumulative {
+        let mut total = 0;
+        for (date, count) in versions_per_day {
+            total += count;
+            println!("{}, {}", date, total);
+        }
+        return Ok(());
+    }
+    if options.per_commit {
+        // Newest first among commits with the same count.
+        per_commit.sort_by(|a, b| b.3.cmp(&a.3).then(b.2.seconds().cmp(&a.2.seconds())));
+        for (sha, author, time, new_versions) in per_commit {
+            println!("{}, {}, {}, {}", sha, author, churn::format_date(time), new_versions);
+        }
+        return Ok(());
+    }
+
+    if options.format.is_streamed() && !options.replaces_file_list() {
+        let commit_stats = if options.commit_stats { Some(&commit_stats[..]) } else { None };
+        return print_json(&root_dir, &repo, id, &churn_options, options, commit_ids.len(),
+                          commit_stats);
+    }
+    let prefix = path_prefix(&repo, options.path_style)
+        .map_err(|e| Error::from_str(&e.to_string()))?;
+
+    let mut all_files = root_dir.results(&repo, id, &churn_options)?.into_vec();
+    if options.include_empty_dirs {
+        all_files.extend(root_dir.dir_results(&repo, id)?);
+        all_files.sort_by(|a, b| a.churn_count.cmp(&b.churn_count).reverse());
+    }
+    if options.sort == SortKey::Bytes {
+        all_files.sort_by(|a,b| a.total_bytes_churned.cmp(&b.total_bytes_churned).reverse());
+    } else if options.sort == SortKey::Stability {
+        let now = now();
+        all_files.sort_by(|a, b| stability_score(a, now).total_cmp(&stability_score(b, now)));
+    } else if let Some(lambda) = options.decay {
+        let now = now();
+        all_files.sort_by(|a, b| {
+            weighted_churn(b, lambda, now).total_cmp(&weighted_churn(a, lambda, now))
+        });
+    }
+    if options.group_by_extension {
+        for (ext, churn_count) in group_by_extension(&all_files) {
+            println!("{:7} {}", churn_count, ext);
+        }
+        return Ok(());
+    }
+    if let Some(n) = options.top_dirs {
+        for (dir, churn_count) in top_dirs(&all_files, n) {
+            println!("{:7} {}/", churn_count, dir);
+        }
+        return Ok(());
+    }
+    if let Some(n) = options.top_authors {
+        for (name, churn_count) in top_authors(&all_files, n) {
+            println!("{}, {}", name, churn_count);
+        }
+        return Ok(());
+    }
+    if let Some((ref source_pattern, ref test_pattern)) = options.test_source_ratio {
+        for (source, test) in test_source_pairs(&all_files, source_pattern, test_pattern) {
+            match test {
+                Some(test) => {
+                    println!("{}, {}, {}, {}, {:.2}", source.path, source.churn_count, test.path,
+                             test.churn_count,
+                             source.churn_count as f64 / test.churn_count as f64);
+                }
+                // No test to divide by: the most under-tested of all.
+                None => println!("{}, {}, , 0, no-test", source.path, source.churn_count),
+            }
+        }
+        return Ok(());
+    }
+    if let Some((ref source_pattern, ref test_pattern, min_churn)) = options.flag_untested {
+        print_untested(&all_files, source_pattern, test_pattern, min_churn);
+        return Ok(());
+    }
+    if options.coupling {
+        let pairs = coupled_pairs(&coupling, &all_files);
+        if options.format == Format::GraphMl {
+            print_graphml(&pairs, &all_files);
+        } else {
+            for (a, b, count) in pairs {
+                println!("{:7} {} {}", count, a, b);
+            }
+        }
+        return Ok(());
+    }
+    if options.correlation {
+        print_correlation(&all_files, now());
+        return Ok(());
+    }
+    if options.show_orphan_blobs {
+        for (sha, path) in root_dir.orphan_blobs(&repo, id)? {
+            println!("[orphan] {}, {}", sha, path);
+        }
+        return Ok(());
+    }
+    if let Some(threshold) = options.bus_factor_risk {
+        print_bus_factor_risk(&all_files, threshold);
+        return Ok(());
+    }
+    if options.blame_summary {
+        print_blame_summary(&repo, id, &all_files)?;
+        return Ok(());
+    }
+    if options.show_blame {
+        print_version_commits(&repo, &all_files)?;
+        return Ok(());
+    }
+    if options.blame_coupling {
+        print_blame_coupling(&repo, id, &all_files)?;
+        return Ok(());
+    }
+    if options.distribution {
+        for (label, file_count) in churn_distribution(&all_files) {
+            println!("{}, {}", label, file_count);
+        }
+        return Ok(());
+    }
+    if !options.group_by.is_empty() {
+        let mut root = Group::new();
+        for file in &all_files {
+            root.add(&options.group_by, &file.path, file.churn_count);
+        }
+        root.print(0);
+        return Ok(());
+    }
+    if let Some(n) = options.top {
+        all_files.truncate(n);
+    }
+    // These go by paths from the top of the repository, so they're worked
+    // out before `prefix` goes on them.
+    let code_owners = load_code_owners(&repo, id, options)?;
+    let labels: Vec<PathLabels> = all_files.iter()
+        .map(|file| PathLabels::new(&file.path, options, code_owners.as_ref()))
+        .collect();
+    let hot: Vec<bool> = match options.hot_streak {
+        Some((k, _)) => all_files.iter()
+            .map(|file| recent_changes.get(&file.path).is_some_and(|&count| count >= k))
+            .collect(),
+        None => vec![],
+    };
+    // Turtle names each file by its `file://` IRI, whatever the path style.
+    let iris: Vec<String> = if options.format == Format::Turtle {
+        let root = repo.workdir().unwrap_or_else(|| repo.path());
+        all_files.iter().map(|file| file_iri(&root.join(&file.path))).collect()
+    } else {
+        vec![]
+    };
+    if let Some(ref strip) = options.strip_prefix {
+        for file in &mut all_files {
+            if let Some(path) = file.path.strip_prefix(strip.as_str()) {
+                file.path = path.to_string();
+            }
+        }
+    }
+    if !prefix.is_empty() {
+        for file in &mut all_files {
+            file.path = prefix.clone() + &file.path;
+        }
+    }
+    if options.histogram {
+        print_histogram(&all_files, options.width, options.truncate_path);
+        return Ok(());
+    }
+    if let Some((ref gateway, ref job, ref instance)) = options.prometheus_push {
+        let metrics = prometheus_metrics(&all_files, commit_ids.len());
+        return push_metrics(gateway, job, instance.as_deref(), &metrics);
+    }
+    match options.format {
+        Format::Text => {
+            let colors = if options.color { churn_colors(&all_files) } else { vec![] };
+            let now = now();
+            let owner_width = labels.iter()
+                .filter_map(|labels| labels.owner.as_ref())
+                .map(|owner| owner.chars().count())
+                .chain(Some("owner".len()))
+                .max()
+                .unwrap();
+            if options.header {
+                let mut line = format!("{:>7} ", "churn");
+                if options.with_size {
+                    line += &format!("{:>12} ", "bytes");
+                }
+                if options.with_daily_rate {
+                    line += &format!("{:>9} ", "per_day");
+                }
+                if options.decay.is_some() {
+                    line += &format!("{:>9} ", "weighted");
+                }
+                if options.change_ratio {
+                    line += &format!("{:>7} ", "ratio");
+                }
+                if options.stability_score {
+                    line += &format!("{:>9} ", "stability");
+                }
+                if let Some(weeks) = options.sparkline_weeks {
+                    line += &format!("{:<1$} ", "weekly", weeks);
+                }
+                if options.show_origin {
+                    line += &format!("{:7} {:10} ", "origin", "date");
+                }
+                if options.file_types.is_some() {
+                    line += &format!("{:9} ", "type");
+                }
+                if options.with_codeowners {
+                    line += &format!("{:1$} ", "owner", owner_width);
+                }
+                println!("{}path", line);
+            }
+            for (i, file) in all_files.iter().enumerate() {
+                let mut line = format!("{:7} ", file.churn_count);
+                if let Some(bytes) = file.total_bytes_churned {
+                    line += &format!("{:12} ", bytes);
+                }
+                if options.with_daily_rate {
+                    line += &format!("{:9.3} ", daily_rate(file, now));
+                }
+                if let Some(lambda) = options.decay {
+                    line += &format!("{:9.3} ", weighted_churn(file, lambda, now));
+                }
+                if options.change_ratio {
+                    line += &format!("{:7.4} ", change_ratio(file, commit_ids.len()));
+                }
+                if options.stability_score {
+                    line += &format!("{:9.3} ", stability_score(file, now));
+                }
+                if let Some(weeks) = options.sparkline_weeks {
+                    line += &sparkline(file, weeks, now);
+                    line += " ";
+                }
+                if let Some((sha, time)) = file.origin {
+                    line += &format!("{} {} ", &sha.to_string()[..7], churn::format_date(time));
+                }
+                if let Some(file_type) = labels[i].file_type {
+                    line += &format!("{:9} ", file_type);
+                }
+                if let Some(ref owner) = labels[i].owner {
+                    line += &format!("{:1$} ", owner, owner_width);
+                }
+                line += &display_path(&file.path, options.truncate_path);
+                if file.deleted {
+                    line += " [deleted]";
+                }
+                if let Some(ref source) = file.copy_of {
+                    line += &format!(" [COPY of {}]", source);
+                }
+                if hot.get(i) == Some(&true) {
+                    line += " [HOT]";
+                }
+                match colors.get(i).and_then(|&c| c) {
+                    Some(color) => println!("{}{}{}", color, line, RESET),
+                    None => println!("{}", line),
+                }
+            }
+        }
+        Format::TeamCity => print_teamcity(&all_files, options.max_churn),
+        Format::Pairs => {
+            for file in &all_files {
+                println!("{}\t{}", display_path(&file.path, options.truncate_path), file.churn_count);
+            }
+        }
+        Format::Kv => print_kv(&all_files, options.total),
+        Format::Logfmt => print_logfmt(&all_files, options.total),
+        Format::Csv | Format::Tsv | Format::TsvExcel | Format::GoogleSheets => {
+            match options.output_split_by_dir {
+                Some(ref dir) => {
+                    write_split_by_dir(&all_files, &labels, options, commit_ids.len(), dir)?;
+                }
+                None => {
+                    let mut out = Output::open(options)?;
+                    write_delimited(&all_files, &labels, options, commit_ids.len(), &mut out)?;
+                }
+            }
+        }
+        Format::CsvWide => print_csv_wide(&all_files, options.period, options.header),
+        Format::Toml => print_toml(&all_files, &labels, options, commit_ids.len()),
+        Format::Sql => print_sql(&all_files),
+        #[cfg(feature = "arrow")]
+        Format::Arrow => print_arrow(&all_files)?,
+        #[cfg(not(feature = "arrow"))]
+        Format::Arrow => unreachable!("refused in main"),
+        #[cfg(feature = "arrow")]
+        Format::Feather => print_feather(&all_files, options.with_dates)?,
+        #[cfg(not(feature = "arrow"))]
+        Format::Feather => unreachable!("refused in main"),
+        #[cfg(feature = "parquet")]
+        Format::Parquet => print_parquet(&all_files)?,
+        #[cfg(not(feature = "parquet"))]
+        Format::Parquet => unreachable!("refused in main"),
+        #[cfg(feature = "png")]
+        Format::Png => {
+            let path = options.output.as_ref().expect("checked in main");
+            heatmap::write(&all_files, now(), path).map_err(|e| Error::from_str(&e))?;
+        }
+        #[cfg(not(feature = "png"))]
+        Format::Png => unreachable!("refused in main"),
+        #[cfg(feature = "xlsx")]
+        Format::Xlsx => {
+            let path = options.output.as_ref().expect("checked in main");
+            let summary = envelope_fields(&repo, options, commit_ids.len(), now());
+            spreadsheet::write(&all_files, &summary, path).map_err(|e| Error::from_str(&e))?;
+        }
+        #[cfg(not(feature = "xlsx"))]
+        Format::Xlsx => unreachable!("refused in main"),
+        #[cfg(feature = "cbor")]
+        Format::Cbor => print_cbor(&results_document(&repo, &all_files, &labels, options,
+                                                     commit_ids.len()))?,
+        #[cfg(not(feature = "cbor"))]
+        Format::Cbor => unreachable!("refused in main"),
+        #[cfg(feature = "msgpack")]
+        Format::MsgPack => print_msgpack(&results_document(&repo, &all_files, &labels, options,
+                                                           commit_ids.len()))?,
+        #[cfg(not(feature = "msgpack"))]
+        Format::MsgPack => unreachable!("refused in main"),
+        Format::GraphMl => unreachable!("only used with --coupling"),
+        Format::DotTimeline => print_dot_timeline(&all_files),
+        Format::Turtle => print_turtle(&all_files, &labels, &iris, options),
+        Format::InfluxBatch => {
+            let (url, db, token) = options.influx.as_ref().expect("checked in main");
+            write_influx(url, db, token.as_deref(), &influx_lines(&all_files, now()))?;
+        }
+        Format::Json | Format::NdJson => unreachable!(),
+    }
+    // TOML, CBOR, MessagePack and logfmt have their own `total_churn` key,
+    // and kv a `TOTAL_CHURN` variable.
+    let own_total = [Format::Toml, Format::Cbor, Format::MsgPack, Format::Kv, Format::Logfmt]
+        .contains(&options.format);
+    if options.total && !own_total {
+        println!("TOTAL, {}", all_files.iter().map(|file| file.churn_count).sum::<usize>());
+    }
+    if options.commit_stats {
+        print_commit_stats(&commit_stats);
+    }
+    if options.summary {
+        print_summary(&all_files, commit_ids.len(), root_dir.skipped_objects().len());
+    }
+    if options.with_stats {
+        print_stats(&all_files);
+    }
+
+    Ok(())
+}
+
+/// The churn counts `--distribution` counts files for one at a time; above
+/// them, they're counted in these ranges, and above the last, together.
+const DISTRIBUTION_SINGLES: usize = 5;
+const DISTRIBUTION_RANGES: &[(usize, usize)] = &[(6, 10), (11, 20), (21, 50)];
+
+/// How many files have each churn count, as `(label, file_count)`: each of
+/// the small counts, then ranges like `6-10`, then `51+`. Every row is
+/// there, even with no files, so runs can be compared line by line.
+fn churn_distribution(all_files: &[FileResult]) -> Vec<(String, usize)> {
+    let mut rows: Vec<(String, usize, usize)> = (1..DISTRIBUTION_SINGLES + 1)
+        .map(|n| (n.to_string(), n, n))
+        .chain(DISTRIBUTION_RANGES.iter()
+               .map(|&(low, high)| (format!("{}-{}", low, high), low, high)))
+        .collect();
+    let top = DISTRIBUTION_RANGES.last().map_or(DISTRIBUTION_SINGLES, |&(_, high)| high) + 1;
+    rows.push((format!("{}+", top), top, usize::MAX));
+    rows.into_iter()
+        .map(|(label, low, high)| {
+            let count = all_files.iter()
+                .filter(|file| file.churn_count >= low && file.churn_count <= high)
+                .count();
+            (label, count)
+        })
+        .collect()
+}
+
+/// How many files `--histogram` shows.
+const HISTOGRAM_FILES: usize = 20;
+
+/// Draw a bar chart of the most churned files, fitting in `width` columns,
+/// with paths shortened to `truncate_path` characters if given.
+fn print_histogram(all_files: &[FileResult], width: usize, truncate_path: Option<usize>) {
+    for line in histogram(all_files, width, truncate_path) {
+        println!("{}", line);
+    }
+}
+
+/// The lines of `print_histogram`'s bar chart.
+fn histogram(all_files: &[FileResult], width: usize, truncate_path: Option<usize>) -> Vec<String> {
+    // Partial blocks, for the last eighths of a bar.
+    const EIGHTHS: [char; 8] = [' ', '▏', '▎', '▍', '▌', '▋', '▊', '▉'];
+
+    let top = &all_files[..all_files.len().min(HISTOGRAM_FILES)];
+    let max = match top.iter().map(|f| f.churn_count).max() {
+        Some(max) if max > 0 => max,
+        _ => return vec![],
+    };
+    let paths: Vec<Cow<str>> = top.iter().map(|f| display_path(&f.path, truncate_path)).collect();
+    let path_width = paths.iter().map(|p| p.chars().count()).max().unwrap();
+    let count_width = max.to_string().len();
+    // Room left for the bar after `path |` and `| count`; at least a little.
+    let bar_width = width.saturating_sub(path_width + count_width + 4).max(10);
+    top.iter().zip(paths)
+        .map(|(file, path)| {
+            let eighths = file.churn_count * bar_width * 8 / max;
+            let mut bar = "█".repeat(eighths / 8);
+            match eighths % 8 {
+                0 => {}
+                n => bar.push(EIGHTHS[n]),
+            }
+            format!("{:pw$} |{}| {}", path, bar, file.churn_count, pw = path_width)
+        })
+        .collect()
+}
+
+/// The terminal width to fit output into: `--width`, or else `$COLUMNS`, or
+/// else 80. `$COLUMNS` is only used when output goes to a terminal, since
+/// it describes that terminal, not whatever reads the file or pipe.
+fn output_width(width_arg: Option<usize>) -> usize {
+    width_arg
+        .or_else(|| if atty::is(atty::Stream::Stdout) {
+            std::env::var("COLUMNS").ok().and_then(|c| c.parse().ok())
+        } else {
+            None
+        })
+        .unwrap_or(80)
+}
+
+/// What's printed for files `CODEOWNERS` doesn't give an owner.
+const UNOWNED: &str = "<unowned>";
+
+/// Columns worked out from where a file is in the repository, which have to
+/// be found before its path is changed for printing.
+#[derive(Clone)]
+struct PathLabels<'a> {
+    /// With `--show-file-type`, what type of file it is.
+    file_type: Option<&'a str>,
+
+    /// With `--with-codeowners`, its owners, separated by spaces.
+    owner: Option<String>,
+
+    /// With `--output-split-by-dir`, the top-level directory it's in, or
+    /// `ROOT_DIR` for a file at the top.
+    top_dir: Option<String>,
+}
+
+/// What `--output-split-by-dir` names the file for the files at the top of
+/// the repository.
+const ROOT_DIR: &str = "ROOT";
+
+impl<'a> PathLabels<'a> {
+    fn new(path: &str, options: &'a Options, code_owners: Option<&CodeOwners>)
+        -> PathLabels<'a>
+    {
+        PathLabels {
+            file_type: options.file_types.as_ref().map(|types| types.classify(path)),
+            owner: code_owners.map(|code_owners| match code_owners.owners(path) {
+                Some(owners) if !owners.is_empty() => owners.join(" "),
+                _ => UNOWNED.to_string(),
+            }),
+            top_dir: options.output_split_by_dir.as_ref().map(|_| top_dir(path).to_string()),
+        }
+    }
+}
+
+/// The top-level directory `path` is in, or `ROOT_DIR` for a file at the
+/// top.
+fn top_dir(path: &str) -> &str {
+    match path.find('/') {
+        Some(i) => &path[..i],
+        None => ROOT_DIR,
+    }
+}
+
+/// The `CODEOWNERS` rules for `--with-codeowners`. With no `CODEOWNERS`
+/// file there are no rules, and every file is unowned.
+fn load_code_owners(repo: &Repository, start: Oid, options: &Options)
+    -> Result<Option<CodeOwners>, Error>
+{
+    if !options.with_codeowners {
+        return Ok(None);
+    }
+    match churn::code_owners(repo, start)? {
+        Some(code_owners) => Ok(Some(code_owners)),
+        None => {
+            eprintln!("warning: no CODEOWNERS file, so every file is {}", UNOWNED);
+            Ok(Some(CodeOwners::default()))
+        }
+    }
+}
+
+/// Quote `field` for CSV (or TSV), if it needs it: when it contains the
+/// delimiter, a quote or a line break.
+fn quote_field(field: &str, delimiter: char) -> String {
+    if field.contains([delimiter, '"', '\n', '\r']) {
+        format!("\"{}\"", field.replace('"', "\"\""))
+    } else {
+        field.to_string()
+    }
+}
+
+/// Format seconds since the epoch as a `MM/DD/YYYY` date in UTC, the way
+/// Google Sheets reads dates in CSV.
+fn sheets_date(seconds: i64) -> String {
+    let date = churn::format_datetime(seconds);
+    format!("{}/{}/{}", &date[5..7], &date[8..10], &date[..4])
+}
+
+/// Write `all_files` to `out` as csv, tsv, tsv-excel or google-sheets,
+/// whichever `--format` asked for.
+fn write_delimited(all_files: &[FileResult], labels: &[PathLabels], options: &Options,
+                   commit_count: usize, out: &mut Output)
+    -> Result<(), Error>
+{
+    match options.format {
+        Format::Csv | Format::GoogleSheets => {
+            print_delimited(all_files, labels, options, commit_count, ',', "\n", out)
+        }
+        Format::Tsv => print_delimited(all_files, labels, options, commit_count, '\t', "\n", out),
+        Format::TsvExcel => {
+            // The byte order mark is how Excel knows the file is UTF-8. It
+            // only goes at the start.
+            if out.empty {
+                out.write(|w| w.write_all("\u{feff}".as_bytes()))?;
+            }
+            print_delimited(all_files, labels, options, commit_count, '\t', "\r\n", out)
+        }
+        _ => unreachable!("not a delimited format"),
+    }
+}
+
+/// Write the files in each top-level directory of the repository to their
+/// own file in `dir`, named for the directory, like `src.csv`, with the
+/// files at the top in `ROOT.csv`.
+fn write_split_by_dir(all_files: &[FileResult], labels: &[PathLabels], options: &Options,
+                      commit_count: usize, dir: &Path)
+    -> Result<(), Error>
+{
+    std::fs::create_dir_all(dir)
+        .map_err(|e| Error::from_str(&format!("can't create {}: {}", dir.display(), e)))?;
+    let mut groups: BTreeMap<&str, (Vec<FileResult>, Vec<PathLabels>)> = BTreeMap::new();
+    for (file, file_labels) in all_files.iter().zip(labels) {
+        let top_dir = file_labels.top_dir.as_ref().expect("set with --output-split-by-dir");
+        let group = groups.entry(top_dir).or_default();
+        group.0.push(file.clone());
+        group.1.push(file_labels.clone());
+    }
+    let extension = match options.format {
+        Format::Csv | Format::GoogleSheets => "csv",
+        _ => "tsv",
+    };
+    for (top_dir, (files, files_labels)) in groups {
+        let mut name = format!("{}.{}", top_dir, extension);
+        if options.output_gzip.is_some() {
+            name += ".gz";
+        }
+        let mut out = Output::create(&dir.join(name), options)?;
+        write_delimited(&files, &files_labels, options, commit_count, &mut out)?;
+    }
+    Ok(())
+}
+
+/// Print one row per file, fields separated by `delimiter` and rows ended by
+/// `line_end`, with a header row unless `--no-header` was given. `labels`
+/// has each file's type and owner, if they're wanted.
+fn print_delimited(all_files: &[FileResult], labels: &[PathLabels], options: &Options,
+                   commit_count: usize, delimiter: char, line_end: &str, out: &mut Output)
+    -> Result<(), Error>
+{
+    let mut columns = vec!["path", "churn"];
+    if options.with_size {
+        columns.push("bytes");
+    }
+    if options.with_daily_rate {
+        columns.push("per_day");
+    }
+    if options.decay.is_some() {
+        columns.push("weighted_churn");
+    }
+    if options.change_ratio {
+        columns.push("change_ratio");
+    }
+    if options.stability_score {
+        columns.push("stability_score");
+    }
+    if options.with_dates {
+        columns.push("first_seen");
+        columns.push("last_seen");
+    }
+    if options.show_origin {
+        columns.push("origin_sha");
+        columns.push("origin_date");
+    }
+    if options.file_types.is_some() {
+        columns.push("file_type");
+    }
+    if options.with_codeowners {
+        columns.push("owner");
+    }
+    columns.push("deleted");
+    let separator = delimiter.to_string();
+    // Appending to a file that already has rows, it has the header too.
+    if options.header && out.empty {
+        out.write(|w| write!(w, "{}{}", columns.join(&separator), line_end))?;
+    }
+    let now = now();
+    let sheets = options.format == Format::GoogleSheets;
+    let format_time: fn(i64) -> String = if sheets { sheets_date } else { churn::format_datetime };
+    for (i, file) in all_files.iter().enumerate() {
+        let path = display_path(&file.path, options.truncate_path);
+        let mut row = vec![quote_field(&path, delimiter), file.churn_count.to_string()];
+        if let Some(bytes) = file.total_bytes_churned {
+            row.push(bytes.to_string());
+        }
+        if options.with_daily_rate {
+            row.push(format!("{:.3}", daily_rate(file, now)));
+        }
+        if let Some(lambda) = options.decay {
+            row.push(format!("{:.3}", weighted_churn(file, lambda, now)));
+        }
+        if options.change_ratio {
+            row.push(format!("{:.4}", change_ratio(file, commit_count)));
+        }
+        if options.stability_score {
+            row.push(format!("{:.3}", stability_score(file, now)));
+        }
+        if options.with_dates {
+            row.push(file.first_seen.map(format_time).unwrap_or_default());
+            row.push(file.last_seen.map(format_time).unwrap_or_default());
+        }
+        if options.show_origin {
+            let (sha, date) = origin_fields(file, format_time);
+            row.push(sha);
+            row.push(date);
+        }
+        if let Some(file_type) = labels[i].file_type {
+            row.push(file_type.to_string());
+        }
+        if let Some(ref owner) = labels[i].owner {
+            row.push(quote_field(owner, delimiter));
+        }
+        let deleted = file.deleted.to_string();
+        row.push(if sheets { deleted.to_uppercase() } else { deleted });
+        out.write(|w| write!(w, "{}{}", row.join(&separator), line_end))?;
+    }
+    out.finish()
+}
+
+/// Where the csv and tsv formats write: stdout, or the `--output` file,
+/// either of them gzipped with `--output-gzip`.
+struct Output {
+    writer: Writer,
+
+    /// True if it was empty when opened: false when appending, with
+    /// `--output-append`, to a file that already had rows.
+    empty: bool,
+}
+
+impl Output {
+    fn open(options: &Options) -> Result<Output, Error> {
+        match options.output {
+            Some(ref path) => Output::create(path, options),
+            None => {
+                let writer = Writer::new(Box::new(std::io::stdout()), options.output_gzip);
+                Ok(Output { writer, empty: true })
+            }
+        }
+    }
+
+    /// Write to the file at `path`, or with `--output-append`, add to it.
+    fn create(path: &Path, options: &Options) -> Result<Output, Error> {
+        let error = |e: std::io::Error| Error::from_str(&format!("can't write {}: {}",
+                                                                 path.display(), e));
+        let file = std::fs::OpenOptions::new()
+            .create(true)
+            .append(options.output_append)
+            .write(true)
+            .truncate(!options.output_append)
+            .open(path)
+            .map_err(error)?;
+        let empty = file.metadata().map_err(error)?.len() == 0;
+        let writer = Writer::new(Box::new(std::io::BufWriter::new(file)), options.output_gzip);
+        Ok(Output { writer, empty })
+    }
+
+    /// Call `f` to write something, turning a failure into an `Error`.
+    fn write<F>(&mut self, f: F) -> Result<(), Error>
+        where F: FnOnce(&mut dyn Write) -> std::io::Result<()>
+    {
+        f(&mut self.writer).map_err(output_error)
+    }
+
+    /// Write out everything still buffered, and with `--output-gzip`, the
+    /// end of the gzip stream.
+    fn finish(&mut self) -> Result<(), Error> {
+        match self.writer {
+            Writer::Plain(ref mut writer) => writer.flush(),
+            #[cfg(feature = "gzip")]
+            Writer::Gzip(ref mut encoder) => {
+                encoder.try_finish().and_then(|()| encoder.get_mut().flush())
+            }
+        }.map_err(output_error)
+    }
+}
+
+/// What an `Output` writes to.
+enum Writer {
+    Plain(Box<dyn Write>),
+    #[cfg(feature = "gzip")]
+    Gzip(flate2::write::GzEncoder<Box<dyn Write>>),
+}
+
+impl Writer {
+    /// Write to `writer`, compressed at `gzip_level` if given.
+    #[cfg(feature = "gzip")]
+    fn new(writer: Box<dyn Write>, gzip_level: Option<u32>) -> Writer {
+        match gzip_level {
+            Some(level) => {
+                Writer::Gzip(flate2::write::GzEncoder::new(writer, flate2::Compression::new(level)))
+            }
+            None => Writer::Plain(writer),
+        }
+    }
+
+    #[cfg(not(feature = "gzip"))]
+    fn new(writer: Box<dyn Write>, gzip_level: Option<u32>) -> Writer {
+        assert!(gzip_level.is_none(), "refused in main");
+        Writer::Plain(writer)
+    }
+}
+
+impl Write for Writer {
+    fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+        match *self {
+            Writer::Plain(ref mut writer) => writer.write(buf),
+            #[cfg(feature = "gzip")]
+            Writer::Gzip(ref mut encoder) => encoder.write(buf),
+        }
+    }
+
+    fn flush(&mut self) -> std::io::Result<()> {
+        match *self {
+            Writer::Plain(ref mut writer) => writer.flush(),
+            #[cfg(feature = "gzip")]
+            Writer::Gzip(ref mut encoder) => encoder.flush(),
+        }
+    }
+}
+
+fn output_error(e: std::io::Error) -> Error {
+    Error::from_str(&format!("can't write output: {}", e))
+}
+
+/// The `origin_sha` and `origin_date` fields for `file`, with the date
+/// formatted by `format_time`, or empty strings if we don't know its origin.
+fn origin_fields(file: &FileResult, format_time: fn(i64) -> String) -> (String, String) {
+    match file.origin {
+        Some((sha, time)) => (sha.to_string(), format_time(time.seconds())),
+        None => (String::new(), String::new()),
+    }
+}
+
+/// Print the results as JSON, writing each file out as soon as it's found
+/// instead of collecting them all first: for `--format json`, as a `files`
+/// array in an object that also says what was analyzed and when (or on its
+/// own, with `--no-envelope`), or for `--format ndjson`, one object per line.
+/// With `--pretty`, it's indented the way `serde_json::to_writer_pretty`
+/// would. File paths get `prefix` in front.
+fn print_json(root_dir: &DirData, repo: &Repository, start: Oid, churn_options: &ChurnOptions,
+              options: &Options, commit_count: usize, commit_stats: Option<&[CommitRecord]>)
+    -> Result<(), git2::Error>
+{
+    let prefix = path_prefix(repo, options.path_style)
+        .map_err(|e| Error::from_str(&e.to_string()))?;
+    let array = options.format == Format::Json;
+    let envelope = array && options.envelope;
+    let stdout = std::io::stdout();
+    let mut out = std::io::BufWriter::new(stdout.lock());
+    let now = now();
+    // With --pretty, each file is indented to sit inside the array, and the
+    // array inside the envelope.
+    let (indent, field_separator) = match (options.pretty, envelope) {
+        (false, _) => ("", ":"),
+        (true, false) => ("  ", ": "),
+        (true, true) => ("    ", ": "),
+    };
+    // Writing to stdout fails when it's a closed pipe. Keep the first error
+    // and stop writing.
+    let metadata = if envelope && options.repo_info {
+        Some(RepoInfo::read(repo)?.to_json())
+    } else {
+        None
+    };
+    let mut result = if envelope {
+        let mut header = String::from("{");
+        let fields = envelope_fields(repo, options, commit_count, now).into_iter()
+            .chain(metadata.map(|metadata| ("metadata", metadata)));
+        for (name, value) in fields {
+            if options.pretty {
+                header += "\n  ";
+            }
+            let value = if options.pretty {
+                serde_json::to_string_pretty(&value).unwrap().replace('\n', "\n  ")
+            } else {
+                value.to_string()
+            };
+            header += &format!("\"{}\"{}{},", name, field_separator, value);
+        }
+        if options.pretty {
+            header += "\n  ";
+        }
+        header += &format!("\"files\"{}[", field_separator);
+        out.write_all(header.as_bytes())
+    } else if array {
+        out.write_all(b"[")
+    } else {
+        Ok(())
+    };
+    let code_owners = load_code_owners(repo, start, options)?;
+    let mut first = true;
+    let mut total = 0;
+    root_dir.each_result(repo, start, churn_options, |file| {
+        if result.is_err() {
+            return;
+        }
+        total += file.churn_count;
+        let labels = PathLabels::new(&file.path, options, code_owners.as_ref());
+        let path = match options.strip_prefix {
+            Some(ref strip) => file.path.strip_prefix(strip.as_str()).unwrap_or(&file.path),
+            None => &file.path,
+        };
+        let object = json_object(&file, prefix.to_string() + path, &labels, options,
+                                 commit_count, now);
+        let separator: &[u8] = match (array, first) {
+            (true, true) => b"\n",
+            (true, false) => b",\n",
+            (false, _) => b"",
+        };
+        first = false;
+        result = out.write_all(separator)
+            .and_then(|()| if options.pretty {
+                let text = serde_json::to_string_pretty(&object).map_err(std::io::Error::other)?;
+                write!(out, "{}{}", indent, text.replace('\n', &format!("\n{}", indent)))
+            } else {
+                serde_json::to_writer(&mut out, &object).map_err(std::io::Error::other)
+            })
+            .and_then(|()| if array { Ok(()) } else { out.write_all(b"\n") });
+    })?;
+    let end = match (array, first) {
+        (false, _) => String::new(),
+        (true, true) => "]".to_string(),
+        // The closing bracket lines up with the line that opened the array.
+        (true, false) => format!("\n{}]", if envelope && options.pretty { "  " } else { "" }),
+    };
+    let end = if !envelope {
+        if array { end + "\n" } else { end }
+    } else {
+        let mut fields: Vec<(&str, serde_json::Value)> = vec![];
+        if options.total {
+            fields.push(("total_churn", total.into()));
+        }
+        if let Some(commits) = commit_stats {
+            fields.push(("commit_stats", commit_stats_json(commits)));
+        }
+        let mut end = end;
+        for (name, value) in fields {
+            let value = if options.pretty {
+                serde_json::to_string_pretty(&value).unwrap().replace('\n', "\n  ")
+            } else {
+                value.to_string()
+            };
+            let newline = if options.pretty { "\n  " } else { "" };
+            end += &format!(",{}\"{}\"{}{}", newline, name, field_separator, value);
+        }
+        end + if options.pretty { "\n}\n" } else { "}\n" }
+    };
+    result.and_then(|()| out.write_all(end.as_bytes()))
+        .and_then(|()| out.flush())
+        .map_err(|e| Error::from_str(&format!("can't write output: {}", e)))
+}
+
+/// The fields of the JSON envelope that come before the `files` array.
+fn envelope_fields(repo: &Repository, options: &Options, commit_count: usize, now: i64)
+    -> Vec<(&'static str, serde_json::Value)>
+{
+    let repository = repo.workdir().unwrap_or_else(|| repo.path());
+    vec![
+        ("repository", repository.display().to_string().into()),
+        ("analyzed_at", churn::format_datetime(now).into()),
+        ("start_ref", options.tag.as_deref().or(options.start_ref.as_deref()).unwrap_or("HEAD")
+            .into()),
+        ("commit_count", commit_count.into()),
+    ]
+}
+
+/// The JSON object for `file`, printed at `path`, with its `labels`.
+fn json_object(file: &FileResult, path: String, labels: &PathLabels, options: &Options,
+               commit_count: usize, now: i64)
+    -> serde_json::Map<String, serde_json::Value>
+{
+    let mut object = serde_json::Map::new();
+    if options.truncate_path.is_some() {
+        let display = display_path(&path, options.truncate_path).into_owned();
+        object.insert("display_path".to_string(), display.into());
+    }
+    object.insert("path".to_string(), path.into());
+    object.insert("churn".to_string(), file.churn_count.into());
+    if let Some(bytes) = file.total_bytes_churned {
+        object.insert("bytes".to_string(), bytes.into());
+    }
+    if options.with_daily_rate {
+        object.insert("per_day".to_string(), daily_rate(file, now).into());
+    }
+    if let Some(lambda) = options.decay {
+        object.insert("weighted_churn".to_string(), weighted_churn(file, lambda, now).into());
+    }
+    if options.change_ratio {
+        object.insert("change_ratio".to_string(), change_ratio(file, commit_count).into());
+    }
+    if options.stability_score {
+        object.insert("stability_score".to_string(), stability_score(file, now).into());
+    }
+    if options.with_dates {
+        if let (Some(first), Some(last)) = (file.first_seen, file.last_seen) {
+            object.insert("first_seen".to_string(), churn::format_datetime(first).into());
+            object.insert("last_seen".to_string(), churn::format_datetime(last).into());
+        }
+    }
+    if options.show_origin {
+        let (sha, date) = origin_fields(file, churn::format_datetime);
+        object.insert("origin_sha".to_string(), sha.into());
+        object.insert("origin_date".to_string(), date.into());
+    }
+    if let Some(file_type) = labels.file_type {
+        object.insert("file_type".to_string(), file_type.into());
+    }
+    if let Some(ref owner) = labels.owner {
+        object.insert("owner".to_string(), owner.clone().into());
+    }
+    if let Some(ref source) = file.copy_of {
+        object.insert("copy_of".to_string(), source.clone().into());
+    }
+    object.insert("deleted".to_string(), file.deleted.into());
+    object
+}
+
+/// The results as the same document `--format json` writes, envelope and
+/// all, for the binary formats to encode.
+#[cfg(any(feature = "cbor", feature = "msgpack"))]
+fn results_document(repo: &Repository, all_files: &[FileResult], labels: &[PathLabels],
+                    options: &Options, commit_count: usize) -> serde_json::Value {
+    let now = now();
+    let files: Vec<serde_json::Value> = all_files.iter().zip(labels)
+        .map(|(file, labels)| {
+            json_object(file, file.path.clone(), labels, options, commit_count, now).into()
+        })
+        .collect();
+    let mut document: serde_json::Map<String, serde_json::Value> =
+        envelope_fields(repo, options, commit_count, now).into_iter()
+        .map(|(name, value)| (name.to_string(), value))
+        .collect();
+    document.insert("files".to_string(), files.into());
+    if options.total {
+        let total: usize = all_files.iter().map(|file| file.churn_count).sum();
+        document.insert("total_churn".to_string(), total.into());
+    }
+    document.into()
+}
+
+/// Write the results to stdout as CBOR, starting with the self-described
+/// CBOR tag (`d9 d9 f7`) so tools can tell it from JSON.
+#[cfg(feature = "cbor")]
+fn print_cbor(document: &serde_json::Value) -> Result<(), Error> {
+    let stdout = std::io::stdout();
+    let mut out = std::io::BufWriter::new(stdout.lock());
+    out.write_all(&[0xd9, 0xd9, 0xf7])
+        .map_err(|e| e.to_string())
+        .and_then(|()| ciborium::ser::into_writer(document, &mut out).map_err(|e| e.to_string()))
+        .and_then(|()| out.flush().map_err(|e| e.to_string()))
+        .map_err(|e| Error::from_str(&format!("can't write CBOR output: {}", e)))
+}
+
+/// Write the results to stdout as MessagePack. Objects are maps keyed by
+/// field name, as in the JSON, so readers don't need to know the field
+/// order.
+#[cfg(feature = "msgpack")]
+fn print_msgpack(document: &serde_json::Value) -> Result<(), Error> {
+    let stdout = std::io::stdout();
+    let mut out = std::io::BufWriter::new(stdout.lock());
+    rmp_serde::encode::write(&mut out, document)
+        .map_err(|e| e.to_string())
+        .and_then(|()| out.flush().map_err(|e| e.to_string()))
+        .map_err(|e| Error::from_str(&format!("can't write MessagePack output: {}", e)))
+}
+
+/// The `n` most recent of `commit_ids` by commit time, for `--hot-streak`.
+/// They aren't always the last ones walked: merged branches can have older
+/// commits after newer ones.
+fn most_recent_commits(repo: &Repository, commit_ids: &[Oid], n: usize)
+    -> Result<HashSet<Oid>, Error>
+{
+    let mut times = commit_ids.iter()
+        .map(|&id| repo.find_commit(id).map(|commit| (commit.time().seconds(), id)))
+        .collect::<Result<Vec<_>, Error>>()?;
+    times.sort_by(|a, b| b.cmp(a));
+    Ok(times.into_iter().take(n).map(|(_, id)| id).collect())
+}
+
+/// If a commit looks like a refactor going by the churn counts of the files
+/// it made new versions of, as they were afterwards: how many files there
+/// were and their average churn. A refactor touches more files than
+/// `--refactor-breadth-threshold`, few of which have changed much, like
+/// files that were just moved.
+fn refactor_stats(touched_churn: &[usize], options: &Options) -> Option<(usize, f64)> {
+    let files = touched_churn.len();
+    if files <= options.refactor_breadth {
+        return None;
+    }
+    let mean_churn = touched_churn.iter().sum::<usize>() as f64 / files as f64;
+    if mean_churn < options.refactor_depth { Some((files, mean_churn)) } else { None }
+}
+
+/// Walk `commit_ids` on a `DirData` of its own to find the refactors for
+/// `--exclude-refactoring`, with what `refactor_stats` says about each one.
+fn find_refactors(repo: &Repository, commit_ids: &[Oid], churn_options: &ChurnOptions,
+                  options: &Options) -> Result<HashMap<Oid, (usize, f64)>, Error> {
+    let mut root_dir = DirData::new();
+    let mut refactors = HashMap::new();
+    for &commit_id in commit_ids {
+        let commit = repo.find_commit(commit_id)?;
+        let mut touched_churn = vec![];
+        root_dir.update_for_commit_with(repo, &commit, churn_options,
+                                        |_, count| touched_churn.push(count))?;
+        if let Some(stats) = refactor_stats(&touched_churn, options) {
+            refactors.insert(commit_id, stats);
+        }
+    }
+    Ok(refactors)
+}
+
+/// What `--commit-stats` reports about each commit.
+struct CommitRecord {
+    id: Oid,
+    time: git2::Time,
+    summary: String,
+
+    /// How many files got new versions.
+    files: usize,
+
+    /// How many new versions were counted, which can be more than `files`
+    /// when paths are merged, with `--ignore-path-case`.
+    new_versions: usize,
+}
+
+impl CommitRecord {
+    /// A record of `commit`, which made new versions of the files in
+    /// `changed`, `new_versions` of which were counted.
+    fn new(commit: &git2::Commit, changed: &[String], new_versions: usize) -> CommitRecord {
+        let mut paths: Vec<&String> = changed.iter().collect();
+        paths.sort();
+        paths.dedup();
+        CommitRecord {
+            id: commit.id(),
+            time: commit.time(),
+            summary: commit.summary().unwrap_or("").to_string(),
+            files: paths.len(),
+            new_versions,
+        }
+    }
+}
 
-/// Get or create a HashMap entry.
-///
-/// If the given `map` does *not* already have an entry with the given `key`,
-/// this inserts the pair `(key, f())` into the map.
-///
-/// Returns a mut reference to `map[key]`.
-///
-fn get_mut_or_create_with<'a, V, F: FnOnce()->V>(
-    map: &'a mut HashMap<String, V>, key: &str, f: F) -> &'a mut V
+/// How many commits `--commit-stats` lists as the widest and deepest.
+const COMMIT_STATS_TOP: usize = 10;
+
+/// The `COMMIT_STATS_TOP` commits with the biggest `key`, biggest first, and
+/// newest first among equals.
+fn top_commits<F: Fn(&CommitRecord) -> usize>(commits: &[CommitRecord], key: F)
+    -> Vec<&CommitRecord>
 {
-    // Pure optimization: the one-liner below is correct, but since this path
-    // is hot, we indulge in a little unsafe code to avoid the expense of
-    // `key.to_string()` when the entry already exists (the common case for
-    // most repositories).
-    unsafe {
-        let p_map: *mut HashMap<String, V> = map;
-        if let Some(r) = (*p_map).get_mut(key) {
-            return r;
-        }
+    let mut top: Vec<&CommitRecord> = commits.iter().filter(|c| key(c) > 0).collect();
+    top.sort_by(|a, b| key(b).cmp(&key(a)).then(b.time.seconds().cmp(&a.time.seconds())));
+    top.truncate(COMMIT_STATS_TOP);
+    top
+}
+
+/// How many commits there were in each month, as `YYYY-MM`.
+fn commits_by_month(commits: &[CommitRecord]) -> BTreeMap<String, usize> {
+    let mut months = BTreeMap::new();
+    for commit in commits {
+        *months.entry(churn::format_date(commit.time)[..7].to_string()).or_insert(0) += 1;
     }
+    months
+}
 
-    map.entry(key.to_string()).or_insert_with(f)
+/// Print the `--commit-stats` section: the commits that changed the most
+/// files, the ones that made the most new versions, and commits per month.
+fn print_commit_stats(commits: &[CommitRecord]) {
+    print_top_commits("Widest commits (most files changed):", "files", commits, |c| c.files);
+    print_top_commits("Deepest commits (most new versions):", "versions", commits,
+                      |c| c.new_versions);
+    println!();
+    println!("Commits per month:");
+    for (month, count) in commits_by_month(commits) {
+        println!("{:7} {}", count, month);
+    }
 }
 
-/// Join a directory path `base` to a filename `name`.
-fn join(base: &str, name: &str) -> String {
-    match base {
-        "" => name.to_string(),
-        _ => base.to_string() + "/" + name
+/// Print `title`, then a line for each of the top commits by `key`, like
+/// `     12 files abc1234 2023-06-15 "Rename the parser"`.
+fn print_top_commits(title: &str, unit: &str, commits: &[CommitRecord],
+                     key: fn(&CommitRecord) -> usize) {
+    println!();
+    println!("{}", title);
+    for commit in top_commits(commits, key) {
+        println!("{:7} {} {} {} \"{}\"", key(commit), unit, &commit.id.to_string()[..7],
+                 churn::format_date(commit.time), commit.summary);
     }
 }
 
-/// Cumulative version counts for everything under one directory of a
-/// repository, including subdirectories.
-///
-/// The basic algorithm here is to create a root `DirData`, update it for every
-/// commit in the repository, then get the results out of the resulting tree of
-/// `DirData` records.
-struct DirData {
-    /// Set of all Git "tree" hashes ever seen for this directory.
-    ///
-    /// A Git "tree" is a snapshot of a directory. When we query Git for a
-    /// given commit, Git doesn't give us a patch telling what was changed in
-    /// that commit. Instead, it gives us a complete snapshot of *all* files
-    /// and directories in that commit, including files and directories that
-    /// did not change.
-    ///
-    /// Therefore we have to keep the set of all hashes we've seen for every
-    /// directory (this field) and every file (`DirData::files`) to avoid
-    /// overcounting directories or doing redundant work.
-    hashes: HashSet<Oid>,
-
-    /// Table of all blob hashes ever seen for each file in this directory.
-    files: HashMap<String, HashSet<Oid>>,
-
-    /// Each subdirectory that ever existed under this directory gets its own
-    /// `DirData` record.
-    dirs: HashMap<String, DirData>
-}
-
-impl DirData {
-    fn new() -> DirData {
-        DirData {
-            hashes: HashSet::new(),
-            files: HashMap::new(),
-            dirs: HashMap::new()
-        }
-    }
-
-    /// Get a `DirData` record for a subdirectory of this dir, creating a new
-    /// record if we don't already have one.
-    fn subdir(&mut self, name: &str) -> &mut DirData {
-        get_mut_or_create_with(&mut self.dirs, name, || DirData::new())
-    }
-
-    /// Add an entry to `out` for each file in this tree.
-    ///
-    /// This is like `find . -type f`: directories aren't included, but files
-    /// in subdirectories are. And the order of the output is pretty random.
-    fn get_all_files(&self, path: &str, out: &mut Vec<(String, usize)>) {
-        for (name, hashes) in &self.files {
-            let full_path = join(path, name);
-            out.push((full_path, hashes.len()));
-        }
-        for (name, subdir) in &self.dirs {
-            let full_path = join(path, name);
-            subdir.get_all_files(&full_path, out);
-        }
-    }
-
-    fn update_for_tree(&mut self, repo: &Repository, tree: &Tree) -> Result<(), Error> {
-        for entry in tree.iter() {
-            let name = entry.name().unwrap();
-            let sha = entry.id();
-            match entry.kind() {
-                Some(ObjectType::Tree) => {
-                    let subdir = self.subdir(name);
-                    if subdir.hashes.insert(sha) {
-                        let child_object = entry.to_object(repo)?;
-                        let subtree = child_object.as_tree().unwrap();
-                        subdir.update_for_tree(repo, subtree)?;
-                    }
+/// The `commit_stats` object for the JSON envelope: `widest` and `deepest`
+/// arrays of commits, and `by_month` counts.
+fn commit_stats_json(commits: &[CommitRecord]) -> serde_json::Value {
+    let list = |key: fn(&CommitRecord) -> usize| -> serde_json::Value {
+        top_commits(commits, key).into_iter()
+            .map(|commit| {
+                let mut object = serde_json::Map::new();
+                object.insert("sha".to_string(), commit.id.to_string().into());
+                object.insert("date".to_string(),
+                              churn::format_datetime(commit.time.seconds()).into());
+                object.insert("summary".to_string(), commit.summary.clone().into());
+                object.insert("files".to_string(), commit.files.into());
+                object.insert("new_versions".to_string(), commit.new_versions.into());
+                serde_json::Value::Object(object)
+            })
+            .collect::<Vec<_>>()
+            .into()
+    };
+    let mut stats = serde_json::Map::new();
+    stats.insert("widest".to_string(), list(|c| c.files));
+    stats.insert("deepest".to_string(), list(|c| c.new_versions));
+    let by_month: serde_json::Map<String, serde_json::Value> = commits_by_month(commits)
+        .into_iter()
+        .map(|(month, count)| (month, count.into()))
+        .collect();
+    stats.insert("by_month".to_string(), by_month.into());
+    serde_json::Value::Object(stats)
+}
+
+/// Print the results as a TOML document, with a `[[files]]` table for each
+/// file.
+fn print_toml(all_files: &[FileResult], labels: &[PathLabels], options: &Options,
+              commit_count: usize) {
+    let now = now();
+    let files = all_files.iter().enumerate()
+        .map(|(i, file)| {
+            let mut table = toml::value::Table::new();
+            table.insert("path".to_string(), toml::Value::String(file.path.clone()));
+            if options.truncate_path.is_some() {
+                let display = display_path(&file.path, options.truncate_path).into_owned();
+                table.insert("display_path".to_string(), toml::Value::String(display));
+            }
+            table.insert("churn".to_string(), toml::Value::Integer(file.churn_count as i64));
+            if let Some(bytes) = file.total_bytes_churned {
+                table.insert("bytes".to_string(), toml::Value::Integer(bytes as i64));
+            }
+            if options.with_daily_rate {
+                table.insert("per_day".to_string(), toml::Value::Float(daily_rate(file, now)));
+            }
+            if let Some(lambda) = options.decay {
+                table.insert("weighted_churn".to_string(),
+                             toml::Value::Float(weighted_churn(file, lambda, now)));
+            }
+            if options.change_ratio {
+                table.insert("change_ratio".to_string(),
+                             toml::Value::Float(change_ratio(file, commit_count)));
+            }
+            if options.stability_score {
+                table.insert("stability_score".to_string(),
+                             toml::Value::Float(stability_score(file, now)));
+            }
+            if options.with_dates {
+                if let (Some(first), Some(last)) = (file.first_seen, file.last_seen) {
+                    table.insert("first_seen".to_string(),
+                                 toml::Value::String(churn::format_datetime(first)));
+                    table.insert("last_seen".to_string(),
+                                 toml::Value::String(churn::format_datetime(last)));
                 }
-                Some(ObjectType::Blob) => {
-                    let hashes = get_mut_or_create_with(&mut self.files, name, || HashSet::new());
-                    hashes.insert(sha);
+            }
+            if options.show_origin {
+                let (sha, date) = origin_fields(file, churn::format_datetime);
+                table.insert("origin_sha".to_string(), toml::Value::String(sha));
+                table.insert("origin_date".to_string(), toml::Value::String(date));
+            }
+            if let Some(file_type) = labels[i].file_type {
+                table.insert("file_type".to_string(), toml::Value::String(file_type.to_string()));
+            }
+            if let Some(ref owner) = labels[i].owner {
+                table.insert("owner".to_string(), toml::Value::String(owner.clone()));
+            }
+            table.insert("deleted".to_string(), toml::Value::Boolean(file.deleted));
+            toml::Value::Table(table)
+        })
+        .collect();
+    let mut document = toml::value::Table::new();
+    if options.total {
+        let total: usize = all_files.iter().map(|file| file.churn_count).sum();
+        document.insert("total_churn".to_string(), toml::Value::Integer(total as i64));
+    }
+    document.insert("files".to_string(), toml::Value::Array(files));
+    print!("{}", toml::Value::Table(document));
+}
+
+/// Print SQL to create the `churn_results` table if it isn't there yet and
+/// insert a row for each file, all in one transaction. Every row gets the
+/// same `analyzed_at` time, so repeated runs can be told apart.
+fn print_sql(all_files: &[FileResult]) {
+    let analyzed_at = churn::format_datetime(now());
+    println!("CREATE TABLE IF NOT EXISTS churn_results (\n    \
+              path TEXT NOT NULL,\n    \
+              churn_count INTEGER NOT NULL,\n    \
+              analyzed_at TEXT NOT NULL\n);");
+    println!("BEGIN;");
+    for file in all_files {
+        println!("INSERT INTO churn_results (path, churn_count, analyzed_at) VALUES ('{}', {}, '{}');",
+                 file.path.replace('\'', "''"), file.churn_count, analyzed_at);
+    }
+    println!("COMMIT;");
+}
+
+/// The namespace of the properties in `--format turtle` output.
+const TURTLE_NAMESPACE: &str = "https://github.com/wilkes/churn-rs/ns#";
+
+/// The `file://` IRI for the absolute path `path`, with anything that can't
+/// go in an IRI as is percent-encoded.
+fn file_iri(path: &Path) -> String {
+    let mut iri = String::from("file://");
+    for &byte in path.to_string_lossy().as_bytes() {
+        match byte {
+            b'a'..=b'z' | b'A'..=b'Z' | b'0'..=b'9' | b'-' | b'.' | b'_' | b'~' | b'/' => {
+                iri.push(byte as char)
+            }
+            _ => iri += &format!("%{:02X}", byte),
+        }
+    }
+    iri
+}
+
+/// `text` as a Turtle string literal.
+fn turtle_string(text: &str) -> String {
+    let escaped = text.replace('\\', "\\\\").replace('"', "\\\"")
+        .replace('\n', "\\n").replace('\r', "\\r");
+    format!("\"{}\"", escaped)
+}
+
+/// Print the results as RDF in Turtle syntax: each file is the resource
+/// named by its entry in `iris`, with its path, churn count and whether
+/// it's deleted, plus its dates, type and owners when those were asked for.
+/// Each owner is a separate `churn:owner`, so SPARQL can match on one.
+fn print_turtle(all_files: &[FileResult], labels: &[PathLabels], iris: &[String],
+                options: &Options) {
+    println!("@prefix churn: <{}> .", TURTLE_NAMESPACE);
+    println!("@prefix xsd: <http://www.w3.org/2001/XMLSchema#> .");
+    for ((file, labels), iri) in all_files.iter().zip(labels).zip(iris) {
+        println!();
+        println!("<{}>", iri);
+        println!("    churn:path {} ;", turtle_string(&file.path));
+        println!("    churn:churnCount \"{}\"^^xsd:integer ;", file.churn_count);
+        if options.with_dates {
+            if let (Some(first), Some(last)) = (file.first_seen, file.last_seen) {
+                println!("    churn:firstSeen \"{}\"^^xsd:dateTime ;",
+                         churn::format_datetime(first));
+                println!("    churn:lastSeen \"{}\"^^xsd:dateTime ;",
+                         churn::format_datetime(last));
+            }
+        }
+        if let Some(file_type) = labels.file_type {
+            println!("    churn:fileType {} ;", turtle_string(file_type));
+        }
+        if let Some(ref owner) = labels.owner {
+            if owner != UNOWNED {
+                for owner in owner.split(' ') {
+                    println!("    churn:owner {} ;", turtle_string(owner));
                 }
-                _ => {}
             }
         }
+        println!("    churn:deleted \"{}\"^^xsd:boolean .", file.deleted);
+    }
+}
+
+/// Where `--write-notes` puts its notes.
+const NOTES_REF: &str = "refs/notes/churn";
+
+/// Note on each commit how many new file versions it introduced, replacing
+/// any note from an earlier run. `git log --notes=churn` shows them.
+fn write_notes(repo: &Repository, notes: &[(Oid, usize)]) -> Result<(), Error> {
+    let signature = repo.signature().map_err(|e| Error::from_str(&format!(
+        "--write-notes needs user.name and user.email set in git config to sign the notes: {}",
+        e.message())))?;
+    for &(commit_id, new_versions) in notes {
+        repo.note(&signature, &signature, Some(NOTES_REF), commit_id,
+                  &format!("new file versions: {}\n", new_versions), true)?;
+    }
+    Ok(())
+}
+
+/// Write the results to stdout as an Arrow IPC file, with a `path` and a
+/// `churn_count` column.
+#[cfg(feature = "arrow")]
+fn print_arrow(all_files: &[FileResult]) -> Result<(), Error> {
+    use arrow2::array::{Int64Array, Utf8Array};
+    use arrow2::chunk::Chunk;
+    use arrow2::datatypes::{DataType, Field, Schema};
+    use arrow2::io::ipc::write::{FileWriter, WriteOptions};
+
+    let schema = Schema::from(vec![
+        Field::new("path", DataType::Utf8, false),
+        Field::new("churn_count", DataType::Int64, false),
+    ]);
+    let paths: Vec<&str> = all_files.iter().map(|file| file.path.as_str()).collect();
+    let counts: Vec<i64> = all_files.iter().map(|file| file.churn_count as i64).collect();
+    let write = || -> arrow2::error::Result<()> {
+        let chunk = Chunk::try_new(vec![Utf8Array::<i32>::from_slice(&paths).boxed(),
+                                        Int64Array::from_vec(counts).boxed()])?;
+        let stdout = std::io::stdout();
+        let out = std::io::BufWriter::new(stdout.lock());
+        let mut writer = FileWriter::try_new(out, schema, None, WriteOptions { compression: None })?;
+        writer.write(&chunk, None)?;
+        writer.finish()
+    };
+    write().map_err(|e| Error::from_str(&format!("can't write Arrow output: {}", e)))
+}
+
+/// Write the results to stdout as a Feather v2 file, with `path` and
+/// `churn_count` columns, and `first_seen` and `last_seen` timestamps if
+/// `with_dates`.
+#[cfg(feature = "arrow")]
+fn print_feather(all_files: &[FileResult], with_dates: bool) -> Result<(), Error> {
+    use arrow2::array::{Array, Int64Array, Utf8Array};
+    use arrow2::chunk::Chunk;
+    use arrow2::datatypes::{DataType, Field, Schema, TimeUnit};
+    use arrow2::io::ipc::write::{FileWriter, WriteOptions};
+
+    let timestamp = DataType::Timestamp(TimeUnit::Second, None);
+    let mut fields = vec![
+        Field::new("path", DataType::LargeUtf8, false),
+        Field::new("churn_count", DataType::Int64, false),
+    ];
+    let paths: Vec<&str> = all_files.iter().map(|file| file.path.as_str()).collect();
+    let counts: Vec<i64> = all_files.iter().map(|file| file.churn_count as i64).collect();
+    let mut columns: Vec<Box<dyn Array>> = vec![Utf8Array::<i64>::from_slice(&paths).boxed(),
+                                                Int64Array::from_vec(counts).boxed()];
+    if with_dates {
+        fields.push(Field::new("first_seen", timestamp.clone(), true));
+        fields.push(Field::new("last_seen", timestamp.clone(), true));
+        let first_seen: Vec<Option<i64>> = all_files.iter().map(|file| file.first_seen).collect();
+        let last_seen: Vec<Option<i64>> = all_files.iter().map(|file| file.last_seen).collect();
+        columns.push(Int64Array::from(first_seen).to(timestamp.clone()).boxed());
+        columns.push(Int64Array::from(last_seen).to(timestamp).boxed());
+    }
+    let write = || -> arrow2::error::Result<()> {
+        let chunk = Chunk::try_new(columns)?;
+        let stdout = std::io::stdout();
+        let out = std::io::BufWriter::new(stdout.lock());
+        let mut writer = FileWriter::try_new(out, Schema::from(fields), None,
+                                             WriteOptions { compression: None })?;
+        writer.write(&chunk, None)?;
+        writer.finish()
+    };
+    write().map_err(|e| Error::from_str(&format!("can't write Feather output: {}", e)))
+}
+
+/// Write the results to stdout as a Parquet file, with `path`,
+/// `churn_count`, and `first_seen` and `last_seen` timestamp columns.
+#[cfg(feature = "parquet")]
+fn print_parquet(all_files: &[FileResult]) -> Result<(), Error> {
+    use arrow2::array::{Int64Array, Utf8Array};
+    use arrow2::chunk::Chunk;
+    use arrow2::datatypes::{DataType, Field, Schema, TimeUnit};
+    use arrow2::io::parquet::write::{transverse, CompressionOptions, Encoding, FileWriter,
+                                     RowGroupIterator, Version, WriteOptions};
+
+    // Parquet has no timestamps in seconds; arrow2 would refuse them.
+    let timestamp = DataType::Timestamp(TimeUnit::Millisecond, None);
+    let schema = Schema::from(vec![
+        Field::new("path", DataType::Utf8, false),
+        Field::new("churn_count", DataType::Int64, false),
+        Field::new("first_seen", timestamp.clone(), true),
+        Field::new("last_seen", timestamp.clone(), true),
+    ]);
+    let paths: Vec<&str> = all_files.iter().map(|file| file.path.as_str()).collect();
+    let counts: Vec<i64> = all_files.iter().map(|file| file.churn_count as i64).collect();
+    let first_seen: Vec<Option<i64>> =
+        all_files.iter().map(|file| file.first_seen.map(|t| t * 1000)).collect();
+    let last_seen: Vec<Option<i64>> =
+        all_files.iter().map(|file| file.last_seen.map(|t| t * 1000)).collect();
+    let write = || -> arrow2::error::Result<()> {
+        let chunk = Chunk::try_new(vec![
+            Utf8Array::<i32>::from_slice(&paths).boxed(),
+            Int64Array::from_vec(counts).boxed(),
+            Int64Array::from(first_seen).to(timestamp.clone()).boxed(),
+            Int64Array::from(last_seen).to(timestamp.clone()).boxed(),
+        ])?;
+        let options = WriteOptions {
+            write_statistics: true,
+            compression: CompressionOptions::Snappy,
+            version: Version::V2,
+            data_pagesize_limit: None,
+        };
+        let encodings = schema.fields.iter()
+            .map(|field| transverse(&field.data_type, |_| Encoding::Plain))
+            .collect();
+        let row_groups = RowGroupIterator::try_new(vec![Ok(chunk)].into_iter(), &schema,
+                                                   options, encodings)?;
+        let stdout = std::io::stdout();
+        let out = std::io::BufWriter::new(stdout.lock());
+        let mut writer = FileWriter::try_new(out, schema.clone(), options)?;
+        for group in row_groups {
+            writer.write(group?)?;
+        }
+        writer.end(None)?;
         Ok(())
+    };
+    write().map_err(|e| Error::from_str(&format!("can't write Parquet output: {}", e)))
+}
+
+/// A repository cloned into a temporary directory for `--clone`, which is
+/// deleted again when this is dropped.
+struct TempClone {
+    path: PathBuf,
+}
+
+impl Drop for TempClone {
+    fn drop(&mut self) {
+        let _ = std::fs::remove_dir_all(&self.path);
     }
 }
 
-const COMMITS_PER_DOT: usize = 1000;
+/// Clone `url` into a new temporary directory, with only the last `depth`
+/// commits if given. The clone is bare, since we only need the history.
+fn clone_repository(url: &str, depth: Option<usize>) -> Result<TempClone, String> {
+    let clone = TempClone {
+        path: std::env::temp_dir().join(format!("churn-clone-{}-{}", std::process::id(), now())),
+    };
+    if let Some(depth) = depth {
+        // libgit2 can't make shallow clones, but git can.
+        let status = std::process::Command::new("git")
+            .args(["clone", "--quiet", "--bare", "--depth", &depth.to_string(), url])
+            .arg(&clone.path)
+            .status()
+            .map_err(|e| format!("--depth needs git to be installed: {}", e))?;
+        if !status.success() {
+            return Err(format!("can't clone {}", url));
+        }
+        return Ok(clone);
+    }
 
-fn run(dirname: &str) -> Result<(), git2::Error> {
-    let repo = Repository::open(dirname)?;
-    let mut revwalk = repo.revwalk()?;
-    revwalk.set_sorting(git2::SORT_NONE);
-    let spec = "HEAD";
+    let config = git2::Config::open_default().map_err(|e| e.message().to_string())?;
+    let mut callbacks = git2::RemoteCallbacks::new();
+    // libgit2 asks again whenever credentials are refused; only try each
+    // kind once, so a bad key fails instead of looping.
+    let mut tried_ssh = false;
+    let mut tried_helper = false;
+    callbacks.credentials(move |url, username, allowed| {
+        if allowed.contains(git2::SSH_KEY) && !tried_ssh {
+            tried_ssh = true;
+            return git2::Cred::ssh_key_from_agent(username.unwrap_or("git"));
+        }
+        if allowed.contains(git2::USER_PASS_PLAINTEXT) && !tried_helper {
+            tried_helper = true;
+            return git2::Cred::credential_helper(&config, url, username);
+        }
+        if allowed.contains(git2::DEFAULT) {
+            return git2::Cred::default();
+        }
+        Err(Error::from_str("no credentials worked"))
+    });
+    let mut fetch_options = git2::FetchOptions::new();
+    fetch_options.remote_callbacks(callbacks);
+    git2::build::RepoBuilder::new()
+        .bare(true)
+        .fetch_options(fetch_options)
+        .clone(url, &clone.path)
+        .map_err(|e| match (e.class(), e.code()) {
+            (ErrorClass::Ssh, _) | (_, ErrorCode::Auth) => format!(
+                "authentication failed for {}: {}. For SSH URLs, add your key to ssh-agent; \
+                 for HTTPS, set up a git credential helper.", url, e.message()),
+            _ => format!("can't clone {}: {}", url, e.message()),
+        })?;
+    Ok(clone)
+}
 
-    let mut root_dir: DirData = DirData::new();
+/// The newest version of the csv, tsv, json, ndjson, toml and sql output
+/// formats, for `--output-format-version`. Version 2 put JSON output in an
+/// envelope.
+const OUTPUT_FORMAT_VERSION: usize = 2;
 
-    let id:Oid = repo.revparse_single(spec)?.id();
-    revwalk.push(id)?;
-    let mut n = 0;
-    for id in revwalk {
-        let commit = repo.find_commit(id?)?;
-        let tree = commit.tree()?;
-        root_dir.update_for_tree(&repo, &tree)?;
+/// The `--version` text, like `churn 0.1.0 (git: abc1234, built: 2024-01-15)`.
+/// `build.rs` supplies the commit and date.
+fn version() -> String {
+    format!("churn {} (git: {}, built: {})", env!("CARGO_PKG_VERSION"),
+            env!("CHURN_GIT_SHA"), env!("CHURN_BUILD_DATE"))
+}
 
-        n += 1;
-        if n % COMMITS_PER_DOT == 0 {
-            print!(".");
+/// Find the config file to read default options from: the one named by
+/// `--config` or `CHURN_CONFIG`, else `.churn.toml` at the top of the
+/// repository we're in, else `~/.config/churn/config.toml`.
+///
+/// This runs before the command line is parsed, because the defaults
+/// change the usage text, so it picks out `--config` by itself.
+fn config_path() -> Option<PathBuf> {
+    let mut args = std::env::args().skip(1);
+    while let Some(arg) = args.next() {
+        if arg == "--config" {
+            return args.next().map(PathBuf::from);
+        }
+        if let Some(path) = arg.strip_prefix("--config=") {
+            return Some(PathBuf::from(path));
         }
-        std::io::stdout().flush().unwrap();
     }
-    println!();
+    if let Some(path) = std::env::var_os(settings::env_var_name("--config")) {
+        return Some(PathBuf::from(path));
+    }
+    let in_repo = Repository::discover(".").ok()
+        .and_then(|repo| repo.workdir().map(|dir| dir.join(".churn.toml")));
+    let in_home = std::env::var_os("HOME")
+        .map(|home| Path::new(&home).join(".config").join("churn").join("config.toml"));
+    in_repo.into_iter().chain(in_home).find(|path| path.is_file())
+}
+
+/// Describe an error from libgit2, with a hint about what to do for the
+/// kinds that come up most, since libgit2's own messages can be cryptic.
+/// Errors `run` has already explained are left as they are.
+fn describe_error(e: &Error) -> String {
+    let hint = match (e.class(), e.code()) {
+        (ErrorClass::Odb, _) | (ErrorClass::Zlib, _) =>
+            "Part of the repository's object database couldn't be read. It may be \
+             corrupt, or a partial clone missing objects; `git fsck` can tell.",
+        (ErrorClass::Reference, ErrorCode::NotFound) =>
+            "A ref the analysis needs doesn't exist. Check the branch or tag name.",
+        (ErrorClass::Os, _) | (ErrorClass::Filesystem, _) =>
+            "Check that the repository's files exist and are readable.",
+        (_, ErrorCode::Locked) =>
+            "Another git process may be using the repository; try again when it's done.",
+        _ => return e.message().to_string(),
+    };
+    format!("{}\n{}", e.message(), hint)
+}
+
+/// Print an error about the command line and exit.
+fn usage_error(msg: &str) -> ! {
+    println!("error: {}", msg);
+    std::process::exit(1);
+}
 
-    let mut all_files = vec![];
-    root_dir.get_all_files("", &mut all_files);
-    all_files.sort_by(|a,b| a.1.cmp(&b.1).reverse());
-    for (filename, churn_count) in all_files {
-        println!("{:7} {}", churn_count, filename);
+/// Get the value of a numeric option, or `None` if it wasn't given.
+fn number_arg(args: &docopt::ArgvMap, flag: &str) -> Option<usize> {
+    match args.get_str(flag) {
+        "" => None,
+        n => match n.parse() {
+            Ok(n) => Some(n),
+            Err(_) => usage_error(&format!("{} expects a number, got '{}'", flag, n))
+        }
     }
+}
 
-    Ok(())
+/// Compile a `--group-by` pattern, which must have exactly one capture group.
+fn group_pattern(pattern: &str) -> Regex {
+    match Regex::new(pattern) {
+        Ok(ref re) if re.captures_len() == 2 => re.clone(),
+        Ok(_) => usage_error(&format!("--group-by pattern '{}' must have exactly one capture group", pattern)),
+        Err(e) => usage_error(&format!("bad --group-by pattern '{}': {}", pattern, e))
+    }
 }
 
 fn main() {
-    const USAGE: &'static str = "
-usage: gitlog [options] [<dir>]
+    // With the `tracing` feature, RUST_LOG picks which spans get reported,
+    // e.g. `RUST_LOG=churn=trace`.
+    #[cfg(feature = "tracing")]
+    tracing_subscriber::fmt()
+        .with_env_filter(tracing_subscriber::EnvFilter::from_default_env())
+        .with_span_events(tracing_subscriber::fmt::format::FmtSpan::CLOSE)
+        .with_writer(std::io::stderr)
+        .init();
 
-Options:
-    -h, --help          show this message
-";
+    // build.rs also reads this, to make the man page.
+    const USAGE: &str = include_str!("usage.txt");
 
+    let mut settings = match config_path() {
+        Some(path) => Settings::load(&path, USAGE).unwrap_or_else(|e| usage_error(&e)),
+        None => Settings::default(),
+    };
+    settings.add_env(USAGE).unwrap_or_else(|e| usage_error(&e));
+    let usage = settings.apply_to_usage(USAGE);
     let args =
-        Docopt::new(USAGE)
+        Docopt::new(usage)
+        .map(|d| d.version(Some(version())))
         .and_then(|d| d.parse())
         .unwrap_or_else(|e| e.exit());
-    let dir = match args.get_str("<dir>") {
-        "" => ".",
-        d => d
+    if args.get_str("--format") == "json-schema" {
+        // No repository needed: it describes what `--format json` writes.
+        print!("{}", include_str!("schema.json"));
+        return;
+    }
+    // Written by build.rs from the options in usage.txt.
+    match args.get_str("--generate-completion") {
+        "" => {}
+        "bash" => return print!("{}", include_str!(concat!(env!("OUT_DIR"), "/churn.bash"))),
+        "zsh" => return print!("{}", include_str!(concat!(env!("OUT_DIR"), "/churn.zsh"))),
+        "fish" => return print!("{}", include_str!(concat!(env!("OUT_DIR"), "/churn.fish"))),
+        shell => usage_error(&format!("can't generate completions for '{}'", shell)),
+    }
+    // For containers, where the repository is always in the same place.
+    let churn_repo = std::env::var("CHURN_REPO").unwrap_or_default();
+    let mut dirs = args.get_vec("<dir>");
+    if dirs.is_empty() {
+        dirs.push(if churn_repo.is_empty() { "." } else { &churn_repo });
+    }
+    let format = match Format::from_name(args.get_str("--format")) {
+        Some(f) => f,
+        None => usage_error(&format!("unknown format '{}'", args.get_str("--format")))
+    };
+    let headers = settings.flags(&args, &["--no-header", "--with-header"]);
+    if headers[0] && headers[1] {
+        usage_error("--no-header and --with-header can't be used together");
+    }
+    let deleted = settings.flags(&args, &["--show-deleted", "--hide-deleted"]);
+    if deleted[0] && deleted[1] {
+        usage_error("--show-deleted and --hide-deleted can't be used together");
+    }
+    let renames = settings.flags(&args, &["--detect-moves", "--no-renames"]);
+    if renames[0] && renames[1] {
+        usage_error("--detect-moves and --no-renames can't be used together");
+    }
+    let sort = match args.get_str("--sort") {
+        "churn" => SortKey::Churn,
+        "bytes" => SortKey::Bytes,
+        "stability" => SortKey::Stability,
+        other => usage_error(&format!("can't sort by '{}'", other))
+    };
+    let period = match args.get_str("--period") {
+        "week" => Period::Week,
+        "month" => Period::Month,
+        "quarter" => Period::Quarter,
+        "year" => Period::Year,
+        other => usage_error(&format!("--period must be week, month, quarter or year, not '{}'",
+                                      other)),
+    };
+    let path_flags = settings.flags(&args, &["--repo-relative", "--relative", "--absolute"]);
+    let path_style = match (path_flags[0], path_flags[1], path_flags[2]) {
+        (_, false, false) => PathStyle::RepoRelative,
+        (false, true, false) => PathStyle::Relative,
+        (false, false, true) => PathStyle::Absolute,
+        _ => usage_error("only one of --repo-relative, --relative and --absolute can be used")
+    };
+    let with_size = settings.flag(&args, "--with-size") || sort == SortKey::Bytes;
+    let stability_score = settings.flag(&args, "--stability-score") ||
+        sort == SortKey::Stability;
+    let with_daily_rate = settings.flag(&args, "--with-daily-rate") ||
+        settings.flag(&args, "--with-file-density");
+    let decay = match args.get_str("--decay") {
+        "" => None,
+        s => match s.parse::<f64>() {
+            // No decay is the same as plain churn.
+            Ok(lambda) if lambda >= 0.0 && lambda.is_finite() => Some(lambda).filter(|&l| l > 0.0),
+            _ => usage_error(&format!("--decay must be a number 0 or more, not '{}'", s)),
+        },
+    };
+    let with_dates = settings.flag(&args, "--with-dates");
+    let correlation = settings.flag(&args, "--correlation");
+    let cumulative = settings.flag(&args, "--cumulative");
+    let per_commit = settings.flag(&args, "--per-commit");
+    let stream = settings.flag(&args, "--stream");
+    let show_origin = settings.flag(&args, "--show-origin");
+    let type_config = args.get_str("--type-config");
+    let file_types = match (settings.flag(&args, "--show-file-type"), type_config) {
+        (false, "") => None,
+        (false, _) => usage_error("--type-config only works with --show-file-type"),
+        (true, "") => Some(FileTypes::default()),
+        (true, path) => Some(FileTypes::load(Path::new(path)).unwrap_or_else(|e| usage_error(&e))),
+    };
+    let sparkline_weeks = if settings.flag(&args, "--sparkline") {
+        match number_arg(&args, "--sparkline-weeks") {
+            Some(0) => usage_error("--sparkline-weeks must be at least 1"),
+            weeks => weeks,
+        }
+    } else {
+        None
+    };
+    let top_authors = number_arg(&args, "--top-authors");
+    let bus_factor_risk = if settings.flag(&args, "--bus-factor-risk") {
+        match args.get_str("--bus-factor-threshold").parse::<f64>() {
+            Ok(t) if t > 0.0 && t < 1.0 => Some(t),
+            _ => usage_error(&format!("--bus-factor-threshold must be a fraction between 0 and 1, \
+                                       not '{}'", args.get_str("--bus-factor-threshold"))),
+        }
+    } else {
+        None
+    };
+    let show_blame = settings.flag(&args, "--show-blame");
+    let blame_coupling = settings.flag(&args, "--blame-coupling");
+    let show_refactoring = settings.flag(&args, "--show-refactoring");
+    let exclude_refactoring = settings.flag(&args, "--exclude-refactoring");
+    let mut churn_options = ChurnOptions::new()
+        .include_deleted(deleted[0])
+        .sparse(settings.flag(&args, "--sparse"))
+        .include_stash(settings.flag(&args, "--include-stash"))
+        .detect_moves(renames[0])
+        .detect_copies(settings.flag(&args, "--detect-copies"))
+        .track_authors(bus_factor_risk.is_some() || top_authors.is_some() || stability_score ||
+                       blame_coupling)
+        .with_size(with_size)
+        .ignore_whitespace(settings.flag(&args, "--ignore-whitespace"))
+        .skip_errors(settings.flag(&args, "--skip-errors"))
+        .skip_generated(settings.flag(&args, "--skip-generated"))
+        .ignore_path_case(settings.flag(&args, "--ignore-path-case"))
+        .track_origin(show_origin)
+        .track_version_times(sparkline_weeks.is_some() || decay.is_some() || stability_score ||
+                             format == Format::Png || format == Format::DotTimeline ||
+                             format == Format::CsvWide)
+        .track_version_commits(show_blame)
+        // The cumulative totals, streamed lines, refactor checks and
+        // per-commit counts also need each version credited to the commit
+        // that introduced it, which tracking dates makes sure of.
+        .track_dates(with_daily_rate || with_dates || correlation || cumulative || stream ||
+                     show_refactoring || exclude_refactoring || per_commit ||
+                     format == Format::Parquet || format == Format::Xlsx ||
+                     format == Format::Logfmt);
+    if let Some(n) = number_arg(&args, "--since-n-commits") {
+        churn_options = churn_options.since_n_commits(n);
+    }
+    if let Some(n) = number_arg(&args, "--max-commits-per-ref") {
+        churn_options = churn_options.max_commits_per_start(n);
+    }
+    if let Some(n) = number_arg(&args, "--skip-first") {
+        churn_options = churn_options.skip_first(n);
+    }
+    match number_arg(&args, "--num-threads") {
+        Some(0) => usage_error("--num-threads must be at least 1"),
+        Some(n) => churn_options = churn_options.threads(n),
+        None => {
+            let cpus = std::thread::available_parallelism().map_or(1, |n| n.get());
+            churn_options = churn_options.threads(cpus);
+        }
+    }
+    let only = settings.flags(&args, &["--only-modified", "--only-new"]);
+    let min_churn = number_arg(&args, "--min-churn");
+    let show_unchanged = settings.flag(&args, "--show-unchanged");
+    if show_unchanged {
+        if only[0] {
+            usage_error("--show-unchanged and --only-modified can't be used together");
+        }
+        if only[1] {
+            usage_error("--show-unchanged and --only-new can't be used together");
+        }
+        // Every file it lists has exactly one version.
+        if min_churn.is_some_and(|n| n > 1) {
+            usage_error("--show-unchanged only lists files with one version, so --min-churn \
+                         can't be more than 1");
+        }
+        if number_arg(&args, "--max-churn") == Some(0) {
+            usage_error("--show-unchanged only lists files with one version, so --max-churn \
+                         can't be less than 1");
+        }
+    }
+    let flag_untested = settings.flag(&args, "--flag-untested");
+    match (min_churn, only[0], only[1] || show_unchanged) {
+        (_, true, true) => usage_error("--only-modified and --only-new can't be used together"),
+        (Some(_), true, _) => usage_error("--only-modified and --min-churn can't be used together"),
+        // A test with less churn still counts, so --flag-untested leaves out
+        // the source files below the minimum itself.
+        (Some(_), false, _) if flag_untested => {}
+        (Some(n), false, _) => churn_options = churn_options.min_churn(n),
+        (None, true, _) => churn_options = churn_options.min_churn(2),
+        (None, false, _) => {}
+    }
+    if only[1] || show_unchanged {
+        churn_options = churn_options.max_versions(1);
+    }
+    if let Some(n) = number_arg(&args, "--min-authors") {
+        churn_options = churn_options.min_authors(n);
+    }
+    if let Some(n) = number_arg(&args, "--min-lines") {
+        churn_options = churn_options.min_lines(n);
+    }
+    match number_arg(&args, "--max-depth") {
+        Some(0) => usage_error("--max-depth must be at least 1"),
+        Some(n) => churn_options = churn_options.max_depth(n),
+        None => {}
+    }
+    match args.get_str("--author") {
+        "" => {}
+        name => churn_options = churn_options.author(name),
+    }
+    match args.get_str("--author-email") {
+        "" => {
+            if settings.flag(&args, "--author-email-exact") {
+                usage_error("--author-email-exact only works with --author-email");
+            }
+        }
+        pattern => {
+            churn_options = churn_options.author_email(pattern)
+                .author_email_exact(settings.flag(&args, "--author-email-exact"));
+        }
+    }
+    match args.get_str("--since") {
+        "" => {}
+        date => churn_options = churn_options.since(date),
+    }
+    for pattern in settings.list(&args, "--grep") {
+        churn_options = churn_options.grep(pattern);
+    }
+    for path in settings.list(&args, "--exclude") {
+        churn_options = churn_options.exclude(path);
+    }
+    for path in settings.list(&args, "--path") {
+        churn_options = churn_options.only_path(path);
+    }
+    match args.get_str("--interesting-files") {
+        "" => {}
+        list => {
+            let text = std::fs::read_to_string(list)
+                .unwrap_or_else(|e| usage_error(&format!("can't read {}: {}", list, e)));
+            let paths = text.lines().map(|l| l.trim()).filter(|l| !l.is_empty());
+            churn_options = churn_options.interesting_files(paths);
+        }
+    }
+    let binary_extensions = match args.get_str("--binary-extensions-file") {
+        "" => churn::BINARY_EXTENSIONS.iter().map(|extension| extension.to_string()).collect(),
+        path => {
+            let text = std::fs::read_to_string(path)
+                .unwrap_or_else(|e| usage_error(&format!("can't read {}: {}", path, e)));
+            text.lines()
+                .map(|l| l.trim())
+                .filter(|l| !l.is_empty() && !l.starts_with('#'))
+                .map(|l| l.to_string())
+                .collect::<Vec<String>>()
+        }
+    };
+    if settings.flag(&args, "--exclude-binary-extensions") {
+        churn_options = churn_options.skip_extensions(binary_extensions);
+    } else if !args.get_str("--binary-extensions-file").is_empty() {
+        usage_error("--binary-extensions-file only works with --exclude-binary-extensions");
+    }
+    match args.get_str("--graft-file") {
+        "" => {}
+        path => churn_options = churn_options.graft_file(path),
+    }
+    match args.get_str("--ignore-commits-file") {
+        "" => {}
+        path => churn_options = churn_options.ignore_commits_file(path),
+    }
+    churn_options = churn_options
+        .grep_fixed_strings(settings.flag(&args, "--grep-fixed-strings"))
+        .all_match(settings.flag(&args, "--all-match"));
+    // Each version of the machine-readable formats keeps its fields as they
+    // were; fields added later only appear in newer versions.
+    let format_version = match number_arg(&args, "--output-format-version") {
+        None => OUTPUT_FORMAT_VERSION,
+        Some(n) if (1..=OUTPUT_FORMAT_VERSION).contains(&n) => n,
+        Some(n) => usage_error(&format!("unknown --output-format-version {}; the latest is {}",
+                                        n, OUTPUT_FORMAT_VERSION)),
+    };
+    let width = match (number_arg(&args, "--width"), number_arg(&args, "--output-width")) {
+        (Some(_), Some(_)) => usage_error("--width and --output-width can't be used together"),
+        (width, output_width) => width.or(output_width),
+    };
+    let options = Options {
+        churn: churn_options.build().unwrap_or_else(|e| usage_error(e.message())),
+        git_dir: match args.get_str("--git-dir") {
+            "" => None,
+            dir => Some(PathBuf::from(dir)),
+        },
+        work_tree: match args.get_str("--work-tree") {
+            "" => None,
+            dir => Some(PathBuf::from(dir)),
+        },
+        format,
+        max_churn: number_arg(&args, "--max-churn"),
+        sort,
+        group_by_extension: settings.flag(&args, "--group-by-extension"),
+        top_dirs: number_arg(&args, "--top-dirs"),
+        top: number_arg(&args, "--top"),
+        top_authors,
+        test_source_ratio: if settings.flag(&args, "--test-source-ratio") {
+            Some((args.get_str("--source-pattern").to_string(),
+                  args.get_str("--test-pattern").to_string()))
+        } else {
+            None
+        },
+        flag_untested: if flag_untested {
+            Some((args.get_str("--source-pattern").to_string(),
+                  args.get_str("--test-pattern").to_string(), min_churn.unwrap_or(1)))
+        } else {
+            None
+        },
+        coupling: settings.flag(&args, "--coupling"),
+        hot_streak: match args.get_str("--hot-streak") {
+            "" => None,
+            spec => match spec.split_once('/').map(|(k, n)| (k.parse(), n.parse())) {
+                Some((Ok(k), Ok(n))) if k >= 1 && k <= n => Some((k, n)),
+                _ => usage_error(&format!("--hot-streak should be K/N, like 8/10, with K from \
+                                           1 to N, not '{}'", spec)),
+            },
+        },
+        include_empty_dirs: settings.flag(&args, "--include-empty-dirs"),
+        report_memory: settings.flag(&args, "--report-memory"),
+        commit_stats: settings.flag(&args, "--commit-stats"),
+        repo_info: settings.flag(&args, "--repo-info"),
+        output: match args.get_str("--output") {
+            "" => None,
+            // Named for what it holds, so it's clear it needs gunzip.
+            path if settings.flag(&args, "--output-gzip") && !path.ends_with(".gz") => {
+                Some(PathBuf::from(format!("{}.gz", path)))
+            }
+            path => Some(PathBuf::from(path)),
+        },
+        output_append: settings.flag(&args, "--output-append"),
+        output_split_by_dir: match args.get_str("--output-split-by-dir") {
+            "" => None,
+            dir => Some(PathBuf::from(dir)),
+        },
+        output_gzip: if settings.flag(&args, "--output-gzip") {
+            match number_arg(&args, "--compression-level") {
+                Some(level) if (1..=9).contains(&level) => Some(level as u32),
+                _ => usage_error("--compression-level should be from 1 to 9"),
+            }
+        } else {
+            None
+        },
+        group_by: settings.list(&args, "--group-by").into_iter().map(group_pattern).collect(),
+        path_style,
+        strip_prefix: match args.get_str("--strip-prefix") {
+            "" => None,
+            _ if path_style != PathStyle::RepoRelative => {
+                usage_error("--strip-prefix only works with paths relative to the top of the \
+                             repository")
+            }
+            prefix if prefix.ends_with('/') => Some(prefix.to_string()),
+            prefix => Some(format!("{}/", prefix)),
+        },
+        truncate_path: match number_arg(&args, "--truncate-path") {
+            Some(0) => usage_error("--truncate-path must be at least 1"),
+            n => n,
+        },
+        verbose: settings.flag(&args, "--verbose"),
+        show_refactoring,
+        refactor_breadth: number_arg(&args, "--refactor-breadth-threshold")
+            .expect("has a default"),
+        refactor_depth: match args.get_str("--refactor-depth-threshold").parse::<f64>() {
+            Ok(depth) if depth > 0.0 => depth,
+            _ => usage_error(&format!("--refactor-depth-threshold must be a number above 0, \
+                                       not '{}'", args.get_str("--refactor-depth-threshold"))),
+        },
+        exclude_refactoring,
+        color: match args.get_str("--color") {
+            "always" => true,
+            "never" => false,
+            "auto" => atty::is(atty::Stream::Stdout),
+            other => usage_error(&format!("--color must be always, never or auto, not '{}'", other))
+        },
+        with_size,
+        with_daily_rate,
+        decay,
+        change_ratio: settings.flag(&args, "--change-ratio"),
+        stability_score,
+        period,
+        density: settings.flag(&args, "--density"),
+        with_dates,
+        show_origin,
+        file_types,
+        with_codeowners: settings.flag(&args, "--with-codeowners"),
+        sparkline_weeks,
+        correlation,
+        cumulative,
+        per_commit,
+        stream,
+        show_orphan_blobs: settings.flag(&args, "--show-orphan-blobs"),
+        bus_factor_risk,
+        blame_summary: settings.flag(&args, "--with-blame-summary"),
+        show_blame,
+        blame_coupling,
+        commit_count_only: settings.flag(&args, "--commit-count-only"),
+        write_notes: settings.flag(&args, "--write-notes"),
+        summary: settings.flag(&args, "--summary"),
+        summary_only: settings.flag(&args, "--summary-only"),
+        total: settings.flag(&args, "--total"),
+        since_release: settings.flag(&args, "--since-release"),
+        prometheus_push: match args.get_str("--prometheus-push") {
+            "" => None,
+            gateway => Some((
+                gateway.to_string(),
+                args.get_str("--prometheus-job").to_string(),
+                match args.get_str("--prometheus-instance") {
+                    "" => None,
+                    instance => Some(instance.to_string()),
+                },
+            )),
+        },
+        influx: match (args.get_str("--influx-url"), args.get_str("--influx-db")) {
+            ("", "") => None,
+            ("", _) | (_, "") => usage_error("--influx-url and --influx-db go together"),
+            (url, db) => Some((
+                url.to_string(),
+                db.to_string(),
+                match args.get_str("--influx-token") {
+                    "" => None,
+                    token => Some(token.to_string()),
+                },
+            )),
+        },
+        only_tagged: if settings.flag(&args, "--only-tagged") {
+            Some(number_arg(&args, "--tagged-within").unwrap_or(0))
+        } else {
+            None
+        },
+        merge_base: match args.get_str("--merge-base") {
+            "" => None,
+            branch => Some(branch.to_string()),
+        },
+        exclude_merges_from: match args.get_str("--exclude-merges-from") {
+            "" => None,
+            pattern => Some(pattern.to_string()),
+        },
+        with_stats: settings.flag(&args, "--with-stats"),
+        histogram: settings.flag(&args, "--histogram"),
+        distribution: settings.flag(&args, "--distribution"),
+        pretty: settings.flag(&args, "--pretty"),
+        envelope: !settings.flag(&args, "--no-envelope") && format_version >= 2,
+        check_integrity: settings.flag(&args, "--check-integrity"),
+        width: output_width(width),
+        header: match format {
+            Format::Csv | Format::CsvWide | Format::Tsv | Format::TsvExcel |
+                Format::GoogleSheets => !headers[0],
+            Format::Text => headers[1],
+            Format::TeamCity | Format::Pairs | Format::Kv | Format::Logfmt | Format::Json |
+                Format::NdJson | Format::Toml |
+                Format::Sql | Format::Arrow | Format::Feather | Format::Parquet | Format::Png |
+                Format::Xlsx | Format::Cbor | Format::MsgPack | Format::GraphMl |
+                Format::DotTimeline | Format::Turtle | Format::InfluxBatch => false,
+        },
+        compare: if args.get_bool("--compare") {
+            Some((args.get_str("<base>").to_string(), args.get_str("<branch>").to_string()))
+        } else {
+            None
+        },
+        tag: match args.get_str("--tag") {
+            "" => None,
+            tag => Some(tag.to_string()),
+        },
+        start_ref: match args.get_str("--ref") {
+            "" => None,
+            name => Some(name.to_string()),
+        },
+    };
+    if options.only_tagged.is_none() && !args.get_str("--tagged-within").is_empty() {
+        usage_error("--tagged-within only works with --only-tagged");
+    }
+    if options.tag.is_some() && options.start_ref.is_some() {
+        usage_error("--tag and --ref can't be used together");
+    }
+    if !args.get_str("--max-commits-per-ref").is_empty() &&
+        !options.start_ref.as_deref().is_some_and(is_glob)
+    {
+        usage_error("--max-commits-per-ref only works with a --ref pattern like 'refs/heads/*'");
+    }
+    if options.since_release && options.merge_base.is_some() {
+        usage_error("--since-release and --merge-base can't be used together");
+    }
+    if options.group_by_extension && !options.group_by.is_empty() {
+        usage_error("--group-by-extension and --group-by can't be used together");
+    }
+    if settings.flag(&args, "--no-envelope") && options.format != Format::Json {
+        usage_error("--no-envelope only works with --format json");
+    }
+    if options.total {
+        // It needs somewhere to go: a line of its own, or a field in the
+        // JSON envelope.
+        match options.format {
+            Format::NdJson | Format::Sql | Format::Arrow | Format::Feather | Format::Parquet |
+                Format::Png | Format::Xlsx | Format::GraphMl | Format::CsvWide |
+                Format::DotTimeline | Format::Turtle | Format::InfluxBatch => usage_error(&format!(
+                "--total can't be used with --format {}", args.get_str("--format"))),
+            Format::Json if !options.envelope => {
+                usage_error("--total can't be used with --no-envelope")
+            }
+            _ => {}
+        }
+    }
+    if options.include_empty_dirs && options.format.is_streamed() {
+        usage_error(&format!("--include-empty-dirs can't be used with --format {}",
+                             args.get_str("--format")));
+    }
+    if options.commit_stats {
+        match options.format {
+            Format::Text | Format::TeamCity => {}
+            Format::Json if options.envelope => {}
+            Format::Json => usage_error("--commit-stats can't be used with --no-envelope"),
+            _ => usage_error(&format!("--commit-stats can't be used with --format {}",
+                                      args.get_str("--format"))),
+        }
+    }
+    if options.format == Format::GraphMl && !options.coupling {
+        usage_error("--format graphml only works with --coupling");
+    }
+    if (options.format == Format::Arrow || options.format == Format::Feather) &&
+        !cfg!(feature = "arrow")
+    {
+        usage_error("this churn was built without Arrow support; rebuild it with \
+                     `cargo build --features arrow`");
+    }
+    if options.format == Format::Png {
+        if !cfg!(feature = "png") {
+            usage_error("this churn was built without PNG support; rebuild it with \
+                         `cargo build --features png`");
+        }
+        if options.output.is_none() {
+            usage_error("--format png needs --output <file> to save the image to");
+        }
+    } else if options.format == Format::Xlsx {
+        if !cfg!(feature = "xlsx") {
+            usage_error("this churn was built without Excel support; rebuild it with \
+                         `cargo build --features xlsx`");
+        }
+        if options.output.is_none() {
+            usage_error("--format xlsx needs --output <file> to save the workbook to");
+        }
+    } else if options.output.is_some() && !options.format.is_delimited() {
+        usage_error("--output only works with --format csv, tsv, tsv-excel, google-sheets, png \
+                     or xlsx");
+    }
+    if let Some(ref dir) = options.output_split_by_dir {
+        if !options.format.is_delimited() {
+            usage_error("--output-split-by-dir only works with --format csv, tsv, tsv-excel or \
+                         google-sheets");
+        }
+        if options.output.is_some() {
+            usage_error("--output and --output-split-by-dir can't be used together");
+        }
+        if dir.is_file() {
+            usage_error(&format!("--output-split-by-dir needs a directory, and {} is a file",
+                                 dir.display()));
+        }
+    }
+    if options.output_append {
+        if options.output.is_none() && options.output_split_by_dir.is_none() {
+            usage_error("--output-append only works with --output or --output-split-by-dir");
+        }
+        if !options.format.is_delimited() {
+            usage_error("--output-append only works with --format csv, tsv, tsv-excel or \
+                         google-sheets");
+        }
+    }
+    if options.output_gzip.is_some() {
+        if !cfg!(feature = "gzip") {
+            usage_error("this churn was built without gzip support; rebuild it with \
+                         `cargo build --features gzip`");
+        }
+        if !options.format.is_delimited() {
+            usage_error("--output-gzip only works with --format csv, tsv, tsv-excel or \
+                         google-sheets");
+        }
+        if options.output.is_none() && options.output_split_by_dir.is_none() &&
+            atty::is(atty::Stream::Stdout)
+        {
+            usage_error("--output-gzip writes compressed data; redirect it to a file or use \
+                         --output");
+        }
+    }
+    if let Some((_, ref job, ref instance)) = options.prometheus_push {
+        if !cfg!(feature = "push") {
+            usage_error("this churn was built without Pushgateway support; rebuild it with \
+                         `cargo build --features push`");
+        }
+        if options.format != Format::Text {
+            usage_error("--prometheus-push sends the results instead of printing them, so \
+                         it can't be used with --format");
+        }
+        // The Pushgateway takes labels from the URL path.
+        if job.is_empty() || job.contains('/') ||
+            instance.as_deref().is_some_and(|instance| instance.contains('/'))
+        {
+            usage_error("--prometheus-job and --prometheus-instance can't be empty or \
+                         contain '/'");
+        }
+    }
+    if options.format == Format::InfluxBatch {
+        if !cfg!(feature = "push") {
+            usage_error("this churn was built without InfluxDB support; rebuild it with \
+                         `cargo build --features push`");
+        }
+        if options.influx.is_none() {
+            usage_error("--format influx-batch needs --influx-url <url> and --influx-db <name> \
+                         to write to");
+        }
+    } else if options.influx.is_some() {
+        usage_error("--influx-url and --influx-db only work with --format influx-batch");
+    } else if !args.get_str("--influx-token").is_empty() {
+        usage_error("--influx-token only works with --format influx-batch");
+    }
+    if options.format == Format::Cbor && !cfg!(feature = "cbor") {
+        usage_error("this churn was built without CBOR support; rebuild it with \
+                     `cargo build --features cbor`");
+    }
+    if options.format == Format::MsgPack && !cfg!(feature = "msgpack") {
+        usage_error("this churn was built without MessagePack support; rebuild it with \
+                     `cargo build --features msgpack`");
+    }
+    if options.format == Format::Parquet && !cfg!(feature = "parquet") {
+        usage_error("this churn was built without Parquet support; rebuild it with \
+                     `cargo build --features parquet`");
+    }
+    if options.format.is_binary() && atty::is(atty::Stream::Stdout) {
+        usage_error(&format!("--format {} writes a binary file; redirect it to a file",
+                             args.get_str("--format")));
+    }
+    if options.hot_streak.is_some() && options.format != Format::Text {
+        usage_error("--hot-streak only works with text output");
+    }
+    if options.summary_only && options.format != Format::Text {
+        usage_error("--summary-only only works with text output");
+    }
+    if options.pretty && options.format != Format::Json {
+        usage_error("--pretty only works with --format json");
+    }
+    if options.repo_info && !(options.format == Format::Text ||
+                              (options.format == Format::Json && options.envelope)) {
+        usage_error("--repo-info only works with text output or the --format json envelope");
+    }
+    if options.format.is_streamed() {
+        // JSON output is written as the files are found, so there's no
+        // list of all of them to sort or add up.
+        let name = args.get_str("--format");
+        if options.sort != SortKey::Churn {
+            usage_error(&format!("--format {} lists files unsorted, so it can't be used with \
+                                  --sort {}", name, args.get_str("--sort")));
+        }
+        if options.summary || options.with_stats {
+            usage_error(&format!("--summary and --with-stats can't be used with --format {}",
+                                 name));
+        }
+        if options.top.is_some() {
+            usage_error(&format!("--format {} lists files unsorted, so it can't be used with \
+                                  --top", name));
+        }
+    }
+    let depth = number_arg(&args, "--depth");
+    let clone = match args.get_str("--clone") {
+        "" => {
+            if depth.is_some() {
+                usage_error("--depth only works with --clone");
+            }
+            None
+        }
+        url => {
+            if !args.get_vec("<dir>").is_empty() {
+                usage_error("--clone can't be used with a repository directory");
+            }
+            match clone_repository(url, depth) {
+                Ok(clone) => Some(clone),
+                Err(e) => usage_error(&e),
+            }
+        }
     };
-    match run(dir) {
-        Ok(()) => {}
-        Err(e) => println!("error: {}", e),
+    if let Some(ref clone) = clone {
+        dirs = vec![clone.path.to_str().unwrap()];
+    }
+    if let Some(ref git_dir) = options.git_dir {
+        if clone.is_some() || !args.get_vec("<dir>").is_empty() {
+            usage_error("--git-dir can't be used with --clone or a repository directory");
+        }
+        dirs = vec![git_dir.to_str().unwrap()];
+    } else if options.work_tree.is_some() {
+        usage_error("--work-tree only works with --git-dir");
+    }
+    for (i, dir) in dirs.iter().enumerate() {
+        // With several repositories, each one's results get a header, like
+        // `head` does with several files.
+        if dirs.len() > 1 {
+            if i > 0 {
+                println!();
+            }
+            println!("==> {} <==", dir);
+        }
+        match run(dir, &options) {
+            Ok(()) => {}
+            Err(e) => println!("error: {}", describe_error(&e)),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// docopt only checks the usage text when it parses the arguments, and a
+    /// mistake there, like a description line starting with a flag, stops
+    /// every run.
+    #[test]
+    fn usage_parses() {
+        let argvs: &[&[&str]] = &[
+            &["churn"],
+            &["churn", "--min-churn", "3", "repo"],
+            &["churn", "--grep", "fix", "--group-by", "^(src)/", "--exclude", "vendor", "repo"],
+            &["churn", "--compare", "main", "topic", "--path", "src"],
+            &["churn", "--generate-completion", "bash"],
+        ];
+        for &argv in argvs {
+            Docopt::new(include_str!("usage.txt")).unwrap()
+                .argv(argv)
+                .parse()
+                .unwrap_or_else(|e| panic!("{:?}: {}", argv, e));
+        }
+    }
+
+    /// build.rs makes the man page from usage.txt, so check nothing got lost
+    /// on the way, and that groff reads it without a warning if it's here.
+    #[test]
+    fn man_page() {
+        let path = concat!(env!("OUT_DIR"), "/churn.1");
+        let page = include_str!(concat!(env!("OUT_DIR"), "/churn.1"));
+        assert!(page.starts_with(".TH CHURN 1 "));
+        for section in &["NAME", "SYNOPSIS", "DESCRIPTION", "OPTIONS", "EXAMPLES"] {
+            assert!(page.contains(&format!("\n.SH {}\n", section)), "no {} section", section);
+        }
+        let usage = include_str!("usage.txt");
+        let options = &usage[usage.find("Options:").unwrap()..];
+        for line in options.lines().filter(|line| line.starts_with("    -")) {
+            let spec = line.trim().split("  ").next().unwrap();
+            assert!(page.contains(&format!("\n.B {}\n", spec.replace('-', "\\-"))),
+                    "{} isn't in the man page", spec);
+        }
+
+        let groff = std::process::Command::new("groff").args(["-man", "-z", "-ww", path]).output();
+        let output = match groff {
+            Ok(output) => output,
+            Err(_) => return,
+        };
+        assert!(output.status.success() && output.stderr.is_empty(),
+                "groff: {}", String::from_utf8_lossy(&output.stderr));
+    }
+
+    #[test]
+    fn quote_field_plain() {
+        assert_eq!(quote_field("src/main.rs", ','), "src/main.rs");
+        assert_eq!(quote_field("a,b", '\t'), "a,b");
+        assert_eq!(quote_field("", ','), "");
+    }
+
+    #[test]
+    fn quote_field_delimiter() {
+        assert_eq!(quote_field("a,b.rs", ','), "\"a,b.rs\"");
+        assert_eq!(quote_field("a\tb.rs", '\t'), "\"a\tb.rs\"");
+    }
+
+    #[test]
+    fn quote_field_quotes_and_line_breaks() {
+        assert_eq!(quote_field("say \"hi\".txt", ','), "\"say \"\"hi\"\".txt\"");
+        assert_eq!(quote_field("a\nb", '\t'), "\"a\nb\"");
+        assert_eq!(quote_field("a\rb", ','), "\"a\rb\"");
+    }
+
+    fn files(files: &[(&str, usize)]) -> Vec<FileResult> {
+        files.iter().map(|&(path, churn)| FileResult::from((path.to_string(), churn))).collect()
+    }
+
+    #[test]
+    fn histogram_bars() {
+        // Of the 30 columns, the paths, counts and separators take 10.
+        let lines = histogram(&files(&[("a.rs", 10), ("b.rs", 5), ("c.rs", 1)]), 30, None);
+        assert_eq!(lines, ["a.rs |████████████████████| 10",
+                           "b.rs |██████████| 5",
+                           "c.rs |██| 1"]);
+    }
+
+    #[test]
+    fn histogram_partial_blocks() {
+        // Too narrow, so the bars get the least they can: 10 columns.
+        let lines = histogram(&files(&[("a.rs", 20), ("b", 3)]), 0, None);
+        assert_eq!(lines, ["a.rs |██████████| 20", "b    |█▌| 3"]);
+    }
+
+    #[test]
+    fn histogram_empty() {
+        assert!(histogram(&[], 80, None).is_empty());
+        assert!(histogram(&files(&[("a.rs", 0)]), 80, None).is_empty());
+    }
+
+    #[test]
+    fn churn_distribution_rows() {
+        let all_files = files(&[("a", 1), ("b", 1), ("c", 3), ("d", 6), ("e", 10), ("f", 20),
+                                ("g", 21), ("h", 50), ("i", 51), ("j", 1000)]);
+        let rows = churn_distribution(&all_files);
+        let rows: Vec<(&str, usize)> = rows.iter()
+            .map(|(label, count)| (label.as_str(), *count))
+            .collect();
+        assert_eq!(rows, [("1", 2), ("2", 0), ("3", 1), ("4", 0), ("5", 0), ("6-10", 2),
+                          ("11-20", 1), ("21-50", 2), ("51+", 2)]);
+    }
+
+    #[test]
+    fn churn_distribution_empty() {
+        let rows = churn_distribution(&[]);
+        assert_eq!(rows.len(), 9);
+        assert!(rows.iter().all(|&(_, count)| count == 0));
+    }
+
+    fn pair_paths<'a>(pairs: &[(&'a FileResult, Option<&'a FileResult>)])
+        -> Vec<(&'a str, Option<&'a str>)>
+    {
+        pairs.iter()
+            .map(|&(source, test)| (source.path.as_str(), test.map(|test| test.path.as_str())))
+            .collect()
+    }
+
+    #[test]
+    fn test_source_pairs_ratio_order() {
+        let all_files = files(&[("src/lexer.rs", 4), ("src/parser.rs", 10), ("src/ast.rs", 3),
+                                ("tests/parser_test.rs", 2), ("tests/lexer_test.rs", 4)]);
+        let pairs = test_source_pairs(&all_files, "**", "**/*test*");
+        // Untested first, then the biggest ratios of source to test churn.
+        assert_eq!(pair_paths(&pairs), [("src/ast.rs", None),
+                                        ("src/parser.rs", Some("tests/parser_test.rs")),
+                                        ("src/lexer.rs", Some("tests/lexer_test.rs"))]);
+    }
+
+    #[test]
+    fn test_source_pairs_nearest_test() {
+        let all_files = files(&[("a/util.rs", 1), ("b/util.rs", 1), ("a/util_test.rs", 1),
+                                ("b/util_test.rs", 1)]);
+        let pairs = test_source_pairs(&all_files, "**", "**/*_test.rs");
+        assert_eq!(pair_paths(&pairs), [("a/util.rs", Some("a/util_test.rs")),
+                                        ("b/util.rs", Some("b/util_test.rs"))]);
+    }
+
+    #[test]
+    fn shell_quote_plain() {
+        assert_eq!(shell_quote("src/main.rs"), "src/main.rs");
+        assert_eq!(shell_quote("a-b_c,d+e@f%g:h"), "a-b_c,d+e@f%g:h");
+        assert!(matches!(shell_quote("src/main.rs"), Cow::Borrowed(_)));
+    }
+
+    #[test]
+    fn shell_quote_special() {
+        assert_eq!(shell_quote(""), "''");
+        assert_eq!(shell_quote("my file.txt"), "'my file.txt'");
+        assert_eq!(shell_quote("$HOME;`rm`*"), "'$HOME;`rm`*'");
+        assert_eq!(shell_quote("it's"), "'it'\\''s'");
+    }
+
+    #[test]
+    fn logfmt_quote_plain() {
+        assert_eq!(logfmt_quote("src/main.rs"), "src/main.rs");
+        assert_eq!(logfmt_quote("it's\\ok"), "it's\\ok");
+        assert!(matches!(logfmt_quote("src/main.rs"), Cow::Borrowed(_)));
+    }
+
+    #[test]
+    fn logfmt_quote_special() {
+        assert_eq!(logfmt_quote(""), "\"\"");
+        assert_eq!(logfmt_quote("my file.txt"), "\"my file.txt\"");
+        assert_eq!(logfmt_quote("a=b"), "\"a=b\"");
+        assert_eq!(logfmt_quote("say \"hi\""), "\"say \\\"hi\\\"\"");
+    }
+
+    #[test]
+    fn logfmt_quote_escapes() {
+        assert_eq!(logfmt_quote("a\nb\tc\rd"), "\"a\\nb\\tc\\rd\"");
+        assert_eq!(logfmt_quote("a b\\c"), "\"a b\\\\c\"");
+        assert_eq!(logfmt_quote("a\u{1b}b"), "\"a\u{1b}b\"");
+    }
+
+    #[test]
+    fn top_dir_of_path() {
+        assert_eq!(top_dir("src/main.rs"), "src");
+        assert_eq!(top_dir("src/bin/churn.rs"), "src");
+        assert_eq!(top_dir("README.md"), ROOT_DIR);
+    }
+
+    #[test]
+    fn untested_files() {
+        let all_files = files(&[("src/a.rs", 3), ("src/b.rs", 9), ("src/c.rs", 1),
+                                ("src/d.rs", 5), ("tests/b_test.rs", 1)]);
+        let paths: Vec<&str> = untested(&all_files, "src/**", "tests/**", 2).iter()
+            .map(|file| file.path.as_str())
+            .collect();
+        // Most churned first; b.rs has a test, and c.rs is below the minimum.
+        assert_eq!(paths, ["src/d.rs", "src/a.rs"]);
+    }
+
+    #[test]
+    fn sheets_date_format() {
+        assert_eq!(sheets_date(0), "01/01/1970");
+        assert_eq!(sheets_date(1_705_276_800), "01/15/2024");
+        // Still the same day just before midnight UTC.
+        assert_eq!(sheets_date(1_709_251_199), "02/29/2024");
     }
 }
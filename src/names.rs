@@ -0,0 +1,134 @@
+//! The table of file and directory names that `DirData` records are keyed
+//! by. The same names, like `src` or `mod.rs`, turn up in directory after
+//! directory, so each is stored once, here, and the records hold a small
+//! id for it instead of a `String` of their own.
+//!
+//! The table is shared by the whole process and never shrinks: names are
+//! leaked into it, which is what lets `name` hand out a `&'static str`
+//! without holding the lock. Each thread also keeps its own copy of the
+//! part of the table it has used, so looking up a name it has seen before
+//! doesn't take the lock at all; only new names do.
+
+use std::cell::RefCell;
+use std::collections::HashMap;
+use std::convert::TryFrom;
+use std::hash::{BuildHasherDefault, Hasher};
+use std::num::NonZeroU32;
+use std::sync::{OnceLock, RwLock};
+
+use indexmap::IndexMap;
+
+/// A map keyed by name ids.
+pub type NameMap<V> = IndexMap<NonZeroU32, V, BuildHasherDefault<NameHasher>>;
+
+/// Hashes names and their ids for the table and `NameMap`s, a word at a
+/// time. It's a lot quicker than the `HashMap` default, which matters since
+/// every name in every tree walked is looked up, but it does nothing to
+/// stop a repository from picking names that collide on purpose.
+#[derive(Default)]
+pub struct NameHasher(u64);
+
+impl Hasher for NameHasher {
+    fn finish(&self) -> u64 {
+        self.0
+    }
+
+    fn write(&mut self, bytes: &[u8]) {
+        let mut words = bytes.chunks_exact(8);
+        for word in &mut words {
+            let mut buf = [0; 8];
+            buf.copy_from_slice(word);
+            self.write_u64(u64::from_le_bytes(buf));
+        }
+        for &byte in words.remainder() {
+            self.write_u64(byte as u64);
+        }
+    }
+
+    fn write_u8(&mut self, n: u8) {
+        self.write_u64(n as u64);
+    }
+
+    fn write_u32(&mut self, n: u32) {
+        self.write_u64(n as u64);
+    }
+
+    fn write_u64(&mut self, n: u64) {
+        self.0 = (self.0.rotate_left(5) ^ n).wrapping_mul(0x517c_c1b7_2722_0a95);
+    }
+}
+
+#[derive(Default)]
+struct Names {
+    ids: HashMap<&'static str, NonZeroU32, BuildHasherDefault<NameHasher>>,
+
+    /// Every name in the table; id `n` is at index `n - 1`.
+    names: Vec<&'static str>,
+}
+
+fn table() -> &'static RwLock<Names> {
+    static TABLE: OnceLock<RwLock<Names>> = OnceLock::new();
+    TABLE.get_or_init(Default::default)
+}
+
+thread_local! {
+    /// This thread's copy of the table: the ids of the names it has looked
+    /// up, and the names of the first however many ids.
+    static SEEN: RefCell<Names> = RefCell::new(Names::default());
+}
+
+/// The id for `name`, adding it to the table if it isn't there yet.
+pub fn intern(name: &str) -> NonZeroU32 {
+    SEEN.with(|seen| {
+        if let Some(&id) = seen.borrow().ids.get(name) {
+            return id;
+        }
+        // Nothing leaves the table half updated, so it's fine to carry on
+        // after another thread panicked holding the lock.
+        let found = table().read().unwrap_or_else(|e| e.into_inner()).ids.get_key_value(name)
+            .map(|(&name, &id)| (name, id));
+        let (name, id) = found.unwrap_or_else(|| {
+            let mut table = table().write().unwrap_or_else(|e| e.into_inner());
+            if let Some((&name, &id)) = table.ids.get_key_value(name) {
+                return (name, id);
+            }
+            let id = u32::try_from(table.names.len() + 1).ok()
+                .and_then(NonZeroU32::new)
+                .expect("more names than fit in a u32");
+            let name: &'static str = Box::leak(name.to_string().into_boxed_str());
+            table.names.push(name);
+            table.ids.insert(name, id);
+            (name, id)
+        });
+        seen.borrow_mut().ids.insert(name, id);
+        id
+    })
+}
+
+/// The id for `name`, if it's in the table. Unlike `intern`, this doesn't
+/// add it: a name that was never interned can't be a key anywhere.
+pub fn find(name: &str) -> Option<NonZeroU32> {
+    SEEN.with(|seen| {
+        if let Some(&id) = seen.borrow().ids.get(name) {
+            return Some(id);
+        }
+        let table = table().read().unwrap_or_else(|e| e.into_inner());
+        let (&name, &id) = table.ids.get_key_value(name)?;
+        seen.borrow_mut().ids.insert(name, id);
+        Some(id)
+    })
+}
+
+/// The name with id `id`.
+pub fn name(id: NonZeroU32) -> &'static str {
+    let i = id.get() as usize - 1;
+    SEEN.with(|seen| {
+        let mut seen = seen.borrow_mut();
+        if i >= seen.names.len() {
+            let table = table().read().unwrap_or_else(|e| e.into_inner());
+            let from = seen.names.len();
+            seen.names.extend_from_slice(&table.names[from..]);
+        }
+        seen.names[i]
+    })
+}
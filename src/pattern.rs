@@ -0,0 +1,281 @@
+//! Git-style path patterns, as used in `.gitignore`, `.gitattributes` and
+//! sparse-checkout files.
+
+/// Match `text` against a glob `pattern`.
+///
+/// `*` and `?` don't match `/`, `**` matches across directories, and
+/// `[...]` is a character class. A backslash makes the next character
+/// literal.
+pub fn glob_match(pattern: &str, text: &str) -> bool {
+    glob_match_bytes(pattern.as_bytes(), text.as_bytes(), true)
+}
+
+/// Match a branch name against a glob `pattern` the way `git branch --list`
+/// does: like `glob_match`, except that `*` and `?` match `/` too, so
+/// `dependabot/*` matches `dependabot/npm_and_yarn/lodash`.
+pub fn ref_glob_match(pattern: &str, name: &str) -> bool {
+    glob_match_bytes(pattern.as_bytes(), name.as_bytes(), false)
+}
+
+/// `pathname` is false for `ref_glob_match`, where `/` is nothing special.
+fn glob_match_bytes(pat: &[u8], text: &[u8], pathname: bool) -> bool {
+    if pat.is_empty() {
+        return text.is_empty();
+    }
+    if pat.starts_with(b"**") {
+        let rest = &pat[2..];
+        if rest.starts_with(b"/") {
+            // `**/` matches zero or more whole directories.
+            let rest = &rest[1..];
+            if glob_match_bytes(rest, text, pathname) {
+                return true;
+            }
+            return text.iter().enumerate()
+                .any(|(i, &c)| c == b'/' && glob_match_bytes(rest, &text[i + 1..], pathname));
+        }
+        return (0..text.len() + 1).any(|i| glob_match_bytes(rest, &text[i..], pathname));
+    }
+    match pat[0] {
+        b'*' => {
+            let rest = &pat[1..];
+            for i in 0..text.len() + 1 {
+                if glob_match_bytes(rest, &text[i..], pathname) {
+                    return true;
+                }
+                if pathname && i < text.len() && text[i] == b'/' {
+                    break;
+                }
+            }
+            false
+        }
+        b'?' => {
+            !text.is_empty() && (!pathname || text[0] != b'/') &&
+                glob_match_bytes(&pat[1..], &text[1..], pathname)
+        }
+        b'[' => match match_class(&pat[1..], text.first().cloned()) {
+            Some((true, len)) => glob_match_bytes(&pat[1 + len..], &text[1..], pathname),
+            Some((false, _)) => false,
+            // No closing bracket: treat the `[` literally.
+            None => text.first() == Some(&b'[') && glob_match_bytes(&pat[1..], &text[1..], pathname),
+        },
+        b'\\' if pat.len() > 1 => {
+            text.first() == Some(&pat[1]) && glob_match_bytes(&pat[2..], &text[1..], pathname)
+        }
+        c => text.first() == Some(&c) && glob_match_bytes(&pat[1..], &text[1..], pathname),
+    }
+}
+
+/// Match character `c` against the character class at the start of `pat`,
+/// just past the opening `[`. Returns whether it matched and the length of
+/// the class including the closing `]`, or `None` if the class isn't closed.
+fn match_class(pat: &[u8], c: Option<u8>) -> Option<(bool, usize)> {
+    let mut i = 0;
+    let negated = i < pat.len() && (pat[i] == b'!' || pat[i] == b'^');
+    if negated {
+        i += 1;
+    }
+    let mut matched = false;
+    let mut first = true;
+    while i < pat.len() {
+        if pat[i] == b']' && !first {
+            let matched = c.is_some() && c != Some(b'/') && matched != negated;
+            return Some((matched, i + 1));
+        }
+        first = false;
+        let lo = pat[i];
+        if i + 2 < pat.len() && pat[i + 1] == b'-' && pat[i + 2] != b']' {
+            let hi = pat[i + 2];
+            if let Some(c) = c {
+                matched |= lo <= c && c <= hi;
+            }
+            i += 3;
+        } else {
+            matched |= c == Some(lo);
+            i += 1;
+        }
+    }
+    None
+}
+
+/// One line of a `.gitignore`-style file.
+#[derive(Clone, Debug)]
+struct Pattern {
+    glob: String,
+
+    /// The line started with `!`.
+    negated: bool,
+
+    /// The line ended with `/`, so it only matches directories.
+    dir_only: bool,
+
+    /// The pattern contains a `/` other than at the end, so it is matched
+    /// against the whole path rather than just the last component.
+    anchored: bool,
+}
+
+impl Pattern {
+    fn parse(line: &str) -> Option<Pattern> {
+        let line = line.trim_end();
+        if line.is_empty() || line.starts_with('#') {
+            return None;
+        }
+        let (negated, line) = match line.strip_prefix('!') {
+            Some(rest) => (true, rest),
+            None => (false, line),
+        };
+        let (dir_only, line) = match line.strip_suffix('/') {
+            Some(rest) => (true, rest),
+            None => (false, line),
+        };
+        let anchored = line.contains('/');
+        let glob = line.trim_start_matches('/');
+        if glob.is_empty() {
+            return None;
+        }
+        Some(Pattern {
+            glob: glob.to_string(),
+            negated,
+            dir_only,
+            anchored,
+        })
+    }
+
+    fn matches(&self, path: &str, is_dir: bool) -> bool {
+        if self.dir_only && !is_dir {
+            return false;
+        }
+        if self.anchored {
+            glob_match(&self.glob, path)
+        } else {
+            let name = path.rsplit('/').next().unwrap();
+            glob_match(&self.glob, name)
+        }
+    }
+}
+
+/// A list of patterns, like the contents of a `.gitignore` file. Later
+/// patterns override earlier ones.
+#[derive(Clone, Debug, Default)]
+pub struct PatternList {
+    patterns: Vec<Pattern>,
+}
+
+impl PatternList {
+    /// Parse the text of a pattern file. Blank lines and `#` comments are
+    /// ignored.
+    pub fn parse(text: &str) -> PatternList {
+        PatternList {
+            patterns: text.lines().filter_map(Pattern::parse).collect()
+        }
+    }
+
+    /// Read the lines of a `.gitattributes` file that set or unset
+    /// `attribute`, as patterns that are positive where it's set and `!`
+    /// patterns where it's unset, so `match_path` says whether a path has
+    /// it. Lines about other attributes are ignored, and so are values other
+    /// than `true` and `false`.
+    pub fn from_attribute(text: &str, attribute: &str) -> PatternList {
+        let mut patterns = vec![];
+        for line in text.lines() {
+            let mut fields = line.split_whitespace();
+            let glob = match fields.next() {
+                // `[attr]` lines define macros, not patterns.
+                Some(glob) if !glob.starts_with("[attr]") => glob,
+                _ => continue,
+            };
+            let set = fields.filter_map(|field| attribute_state(field, attribute)).next_back();
+            if let (Some(set), Some(mut pattern)) = (set, Pattern::parse(glob)) {
+                pattern.negated = !set;
+                patterns.push(pattern);
+            }
+        }
+        PatternList { patterns }
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.patterns.is_empty()
+    }
+
+    /// Find the last pattern that matches `path` itself. Returns `Some(true)`
+    /// if it's a positive pattern, `Some(false)` if it's a `!` pattern, or
+    /// `None` if nothing matches.
+    pub fn match_path(&self, path: &str, is_dir: bool) -> Option<bool> {
+        self.patterns.iter().rev()
+            .find(|p| p.matches(path, is_dir))
+            .map(|p| !p.negated)
+    }
+
+    /// True if the file at `path` is selected by these patterns, either
+    /// directly or because one of the directories containing it is.
+    ///
+    /// This is how git reads sparse-checkout files. Cone-mode files are
+    /// written in the same syntax, so they work too.
+    pub fn includes(&self, path: &str) -> bool {
+        if let Some(result) = self.match_path(path, false) {
+            return result;
+        }
+        let mut dir = path;
+        while let Some(i) = dir.rfind('/') {
+            dir = &dir[..i];
+            if let Some(result) = self.match_path(dir, true) {
+                return result;
+            }
+        }
+        false
+    }
+}
+
+/// The rules in a `CODEOWNERS` file: a pattern per line, then the teams or
+/// people who own the files it matches. The last matching line wins, and a
+/// line with no owners leaves its files unowned.
+#[derive(Clone, Debug, Default)]
+pub struct CodeOwners {
+    rules: Vec<(Pattern, Vec<String>)>,
+}
+
+impl CodeOwners {
+    pub fn parse(text: &str) -> CodeOwners {
+        let mut rules = vec![];
+        for line in text.lines() {
+            let mut fields = line.split_whitespace();
+            let pattern = match fields.next().and_then(Pattern::parse) {
+                // CODEOWNERS has no `!` lines.
+                Some(pattern) if !pattern.negated => pattern,
+                _ => continue,
+            };
+            let owners = fields.take_while(|field| !field.starts_with('#'))
+                .map(|owner| owner.to_string())
+                .collect();
+            rules.push((pattern, owners));
+        }
+        CodeOwners { rules }
+    }
+
+    /// The owners of the file at `path`, from the last line that matches it
+    /// or a directory it's in. `None` if no line does; empty if the line
+    /// that does has no owners.
+    pub fn owners(&self, path: &str) -> Option<&[String]> {
+        self.rules.iter().rev()
+            .find(|(pattern, _)| {
+                pattern.matches(path, false) ||
+                    path.match_indices('/').any(|(i, _)| pattern.matches(&path[..i], true))
+            })
+            .map(|(_, owners)| &owners[..])
+    }
+}
+
+/// Whether `field`, one of the attributes on a `.gitattributes` line, sets
+/// `attribute` (`attr` or `attr=true`) or unsets it (`-attr`, `!attr` or
+/// `attr=false`). `None` if it's about something else.
+fn attribute_state(field: &str, attribute: &str) -> Option<bool> {
+    if let Some(name) = field.strip_prefix('-').or_else(|| field.strip_prefix('!')) {
+        return if name == attribute { Some(false) } else { None };
+    }
+    match field.split_once('=') {
+        Some((name, "true")) if name == attribute => Some(true),
+        Some((name, "false")) if name == attribute => Some(false),
+        Some(_) => None,
+        None if field == attribute => Some(true),
+        None => None,
+    }
+}
@@ -0,0 +1,257 @@
+// Defaults for command line options, read from a config file and from
+// environment variables.
+
+use docopt::ArgvMap;
+use std::collections::HashMap;
+use std::path::Path;
+
+/// Config file keys that aren't just the option name with `_` for `-`.
+const ALIASES: &[(&str, &str)] = &[("exclude_paths", "exclude")];
+
+/// Where option descriptions start in the usage text, and how wide it is.
+const DESCRIPTION_INDENT: usize = 24;
+const USAGE_WIDTH: usize = 80;
+
+/// A default value for one option.
+#[derive(Clone, Debug)]
+enum Value {
+    Flag(bool),
+    Str(String),
+    List(Vec<String>),
+}
+
+/// What kind of value an option in the usage text takes.
+#[derive(Clone, Copy, PartialEq)]
+enum Kind {
+    Flag,
+    Str,
+
+    /// An option that can be given more than once, like `--grep`.
+    List,
+}
+
+/// Defaults for command line options, keyed by long option name, like
+/// `--min-churn`. Options given on the command line win.
+#[derive(Default)]
+pub struct Settings {
+    /// Each default, with a description of where it came from for `--help`.
+    values: HashMap<String, (Value, String)>,
+}
+
+impl Settings {
+    /// Read defaults from the `[defaults]` table of the TOML file at `path`.
+    /// Keys are option names with `_` in place of `-`, like `min_churn = 2`.
+    /// `usage` is the docopt usage text, to check the keys against.
+    pub fn load(path: &Path, usage: &str) -> Result<Settings, String> {
+        let text = std::fs::read_to_string(path)
+            .map_err(|e| format!("can't read {}: {}", path.display(), e))?;
+        let config = text.parse::<toml::Value>()
+            .map_err(|e| format!("{}: {}", path.display(), e))?;
+        let kinds = option_kinds(usage);
+        let mut settings = Settings::default();
+        for (section, table) in config.as_table().into_iter().flatten() {
+            if section != "defaults" {
+                return Err(format!("{}: unknown section [{}]", path.display(), section));
+            }
+            let table = match table.as_table() {
+                Some(table) => table,
+                None => return Err(format!("{}: `defaults` should be a table", path.display())),
+            };
+            for (key, value) in table {
+                let name = ALIASES.iter()
+                    .find(|&&(alias, _)| alias == key)
+                    .map_or(key.as_str(), |&(_, name)| name);
+                let flag = format!("--{}", name.replace('_', "-"));
+                let kind = match kinds.get(&flag) {
+                    Some(&kind) => kind,
+                    None => return Err(format!("{}: unknown setting '{}'", path.display(), key)),
+                };
+                let value = match (kind, value) {
+                    (Kind::Flag, &toml::Value::Boolean(b)) => Value::Flag(b),
+                    (Kind::Str, toml::Value::String(s)) => Value::Str(s.clone()),
+                    (Kind::Str, &toml::Value::Integer(n)) => Value::Str(n.to_string()),
+                    (Kind::List, toml::Value::String(s)) => Value::List(vec![s.clone()]),
+                    (Kind::List, toml::Value::Array(items)) => {
+                        let strings: Option<Vec<String>> = items.iter()
+                            .map(|v| v.as_str().map(|s| s.to_string()))
+                            .collect();
+                        match strings {
+                            Some(strings) => Value::List(strings),
+                            None => return Err(format!("{}: '{}' should be a list of strings",
+                                                       path.display(), key)),
+                        }
+                    }
+                    (kind, _) => {
+                        let expected = match kind {
+                            Kind::Flag => "true or false",
+                            Kind::Str => "a string or a number",
+                            Kind::List => "a list of strings",
+                        };
+                        return Err(format!("{}: '{}' should be {}", path.display(), key, expected));
+                    }
+                };
+                settings.values.insert(flag, (value, path.display().to_string()));
+            }
+        }
+        Ok(settings)
+    }
+
+    /// Override defaults with environment variables. Each option `--foo-bar`
+    /// can be set with `CHURN_FOO_BAR`. Flags take `1`, `true` or `yes` (or
+    /// `0`, `false`, `no`), and repeatable options take a comma-separated
+    /// list.
+    pub fn add_env(&mut self, usage: &str) -> Result<(), String> {
+        for (name, kind) in option_kinds(usage) {
+            if name == "--help" || name == "--config" {
+                continue;
+            }
+            let var = env_var_name(&name);
+            let text = match std::env::var(&var) {
+                Ok(text) => text,
+                Err(std::env::VarError::NotPresent) => continue,
+                Err(e) => return Err(format!("{}: {}", var, e)),
+            };
+            let value = match kind {
+                Kind::Flag => match text.to_lowercase().as_str() {
+                    "1" | "true" | "yes" => Value::Flag(true),
+                    "0" | "false" | "no" | "" => Value::Flag(false),
+                    _ => return Err(format!("{} should be true or false, not '{}'", var, text)),
+                },
+                Kind::Str => Value::Str(text),
+                Kind::List => Value::List(text.split(',')
+                                          .filter(|s| !s.is_empty())
+                                          .map(|s| s.to_string())
+                                          .collect()),
+            };
+            self.values.insert(name, (value, var));
+        }
+        Ok(())
+    }
+
+    /// Rewrite `usage` so that `--help` shows these defaults, and so docopt
+    /// uses them for options that take a value.
+    pub fn apply_to_usage(&self, usage: &str) -> String {
+        let mut lines: Vec<String> = usage.lines().map(|l| l.to_string()).collect();
+        let mut i = 0;
+        while i < lines.len() {
+            let name = match option_line(&lines[i]) {
+                Some((name, _)) => name,
+                None => {
+                    i += 1;
+                    continue;
+                }
+            };
+            // The option's description runs until the next option or a
+            // blank line.
+            let mut end = i + 1;
+            while end < lines.len() && !lines[end].trim().is_empty() &&
+                option_line(&lines[end]).is_none()
+            {
+                end += 1;
+            }
+            let note = match self.values.get(&name) {
+                Some(&(Value::Str(ref value), _)) => {
+                    match (i..end).find(|&j| lines[j].contains("[default: ")) {
+                        Some(j) => {
+                            let start = lines[j].find("[default: ").unwrap();
+                            let close = start + lines[j][start..].find(']').unwrap();
+                            let new = format!("[default: {}]", value);
+                            lines[j].replace_range(start..close + 1, &new);
+                            None
+                        }
+                        None => Some(format!("[default: {}]", value)),
+                    }
+                }
+                Some(&(Value::Flag(true), ref source)) => Some(format!("(on, from {})", source)),
+                Some(&(Value::List(ref values), ref source)) => {
+                    Some(format!("(from {}: {})", source, values.join(", ")))
+                }
+                _ => None,
+            };
+            if let Some(note) = note {
+                if lines[end - 1].len() + 1 + note.len() <= USAGE_WIDTH {
+                    lines[end - 1] += &format!(" {}", note);
+                } else {
+                    lines.insert(end, format!("{:1$}{2}", "", DESCRIPTION_INDENT, note));
+                    end += 1;
+                }
+            }
+            i = end;
+        }
+        lines.join("\n") + "\n"
+    }
+
+    /// True if the flag `name` was given on the command line or is on by
+    /// default.
+    pub fn flag(&self, args: &ArgvMap, name: &str) -> bool {
+        self.flags(args, &[name])[0]
+    }
+
+    /// Look up a group of flags that override each other, like
+    /// `--show-deleted` and `--hide-deleted`. If any of them was given on
+    /// the command line, the defaults for all of them are ignored.
+    pub fn flags(&self, args: &ArgvMap, names: &[&str]) -> Vec<bool> {
+        let given: Vec<bool> = names.iter().map(|name| args.get_bool(name)).collect();
+        if given.iter().any(|&b| b) {
+            return given;
+        }
+        names.iter()
+            .map(|name| match self.values.get(*name) {
+                Some(&(Value::Flag(b), _)) => b,
+                _ => false,
+            })
+            .collect()
+    }
+
+    /// The values of a repeatable option: the ones given on the command
+    /// line, or else the defaults.
+    pub fn list<'a>(&'a self, args: &'a ArgvMap, name: &str) -> Vec<&'a str> {
+        let given = args.get_vec(name);
+        if !given.is_empty() {
+            return given;
+        }
+        match self.values.get(name) {
+            Some(&(Value::List(ref values), _)) => values.iter().map(|s| s.as_str()).collect(),
+            _ => vec![],
+        }
+    }
+}
+
+/// The environment variable for the option `name`: `--min-churn` is
+/// `CHURN_MIN_CHURN`.
+pub fn env_var_name(name: &str) -> String {
+    format!("CHURN_{}", name.trim_start_matches('-').replace('-', "_").to_uppercase())
+}
+
+/// If `line` starts the description of an option in docopt usage text,
+/// return the option's long name and whether it takes a value.
+fn option_line(line: &str) -> Option<(String, bool)> {
+    if !line.starts_with("    -") {
+        return None;
+    }
+    // The option and its description are separated by two spaces.
+    let spec = line.trim().split("  ").next().unwrap();
+    let name = spec.split([' ', ',', '='])
+        .find(|word| word.starts_with("--"))?;
+    Some((name.to_string(), spec.contains('<')))
+}
+
+/// Find every long option in docopt usage text and what it takes.
+fn option_kinds(usage: &str) -> HashMap<String, Kind> {
+    let mut kinds = HashMap::new();
+    for line in usage.lines() {
+        if let Some((name, takes_value)) = option_line(line) {
+            // Repeatable options appear in the usage line as `[--name <x>]...`.
+            let repeated = usage.find(&format!("[{} <", name))
+                .and_then(|start| usage[start..].find(']').map(|end| start + end + 1))
+                .is_some_and(|end| usage[end..].starts_with("..."));
+            let kind = match (takes_value, repeated) {
+                (false, _) => Kind::Flag,
+                (true, false) => Kind::Str,
+                (true, true) => Kind::List,
+            };
+            kinds.insert(name, kind);
+        }
+    }
+    kinds
+}
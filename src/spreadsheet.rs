@@ -0,0 +1,103 @@
+// `--format xlsx`: an Excel workbook with the files, a summary of the
+// analysis, and a chart of the most churned files.
+
+use churn::FileResult;
+use rust_xlsxwriter::{Chart, ChartType, Color, ConditionalFormatTop, ConditionalFormatTopRule,
+                      ExcelDateTime, Format, Workbook, XlsxError};
+use std::path::Path;
+
+/// How many files the `Top 20` sheet and its chart show.
+const TOP: usize = 20;
+
+/// Churn counts in the top this many percent are highlighted.
+const HIGHLIGHT_PERCENT: u16 = 10;
+
+/// Write a workbook for `all_files`, most churned first, to `path`. The
+/// `Summary` sheet lists `summary`, then totals for the files.
+pub fn write(all_files: &[FileResult], summary: &[(&str, serde_json::Value)], path: &Path)
+    -> Result<(), String>
+{
+    write_workbook(all_files, summary, path)
+        .map_err(|e| format!("can't write {}: {}", path.display(), e))
+}
+
+fn write_workbook(all_files: &[FileResult], summary: &[(&str, serde_json::Value)], path: &Path)
+    -> Result<(), XlsxError>
+{
+    let bold = Format::new().set_bold();
+    let date = Format::new().set_num_format("yyyy-mm-dd hh:mm");
+    // Excel's own "light red fill with dark red text".
+    let red = Format::new()
+        .set_font_color(Color::RGB(0x9C0006))
+        .set_background_color(Color::RGB(0xFFC7CE));
+    let mut workbook = Workbook::new();
+
+    let files = workbook.add_worksheet().set_name("Files")?;
+    for (column, name) in ["path", "churn", "first_seen", "last_seen", "deleted"].iter()
+        .enumerate()
+    {
+        files.write_string_with_format(0, column as u16, *name, &bold)?;
+    }
+    for (i, file) in all_files.iter().enumerate() {
+        let row = i as u32 + 1;
+        files.write_string(row, 0, &file.path)?;
+        files.write_number(row, 1, file.churn_count as f64)?;
+        for (column, time) in [(2, file.first_seen), (3, file.last_seen)].iter() {
+            if let Some(time) = *time {
+                let time = ExcelDateTime::from_timestamp(time)?;
+                files.write_datetime_with_format(row, *column, &time, &date)?;
+            }
+        }
+        files.write_boolean(row, 4, file.deleted)?;
+    }
+    if !all_files.is_empty() {
+        let high = ConditionalFormatTop::new()
+            .set_rule(ConditionalFormatTopRule::TopPercent(HIGHLIGHT_PERCENT))
+            .set_format(&red);
+        files.add_conditional_format(1, 1, all_files.len() as u32, 1, &high)?;
+    }
+    files.set_freeze_panes(1, 0)?;
+    files.autofit();
+
+    let totals = workbook.add_worksheet().set_name("Summary")?;
+    let total_churn: usize = all_files.iter().map(|file| file.churn_count).sum();
+    let deleted = all_files.iter().filter(|file| file.deleted).count();
+    let counts = [("file_count", all_files.len()), ("total_churn", total_churn),
+                  ("deleted_count", deleted)];
+    let values = summary.iter().map(|(name, value)| (*name, value.clone()))
+        .chain(counts.iter().map(|&(name, count)| (name, count.into())));
+    for (i, (name, value)) in values.enumerate() {
+        let row = i as u32;
+        totals.write_string_with_format(row, 0, name, &bold)?;
+        match value {
+            serde_json::Value::Number(n) => {
+                totals.write_number(row, 1, n.as_f64().unwrap_or(0.0))?
+            }
+            serde_json::Value::String(s) => totals.write_string(row, 1, s)?,
+            other => totals.write_string(row, 1, other.to_string())?,
+        };
+    }
+    totals.autofit();
+
+    let top = workbook.add_worksheet().set_name("Top 20")?;
+    top.write_string_with_format(0, 0, "path", &bold)?;
+    top.write_string_with_format(0, 1, "churn", &bold)?;
+    let top_files = &all_files[..all_files.len().min(TOP)];
+    for (i, file) in top_files.iter().enumerate() {
+        top.write_string(i as u32 + 1, 0, &file.path)?;
+        top.write_number(i as u32 + 1, 1, file.churn_count as f64)?;
+    }
+    top.autofit();
+    if !top_files.is_empty() {
+        let last = top_files.len() as u32;
+        let mut chart = Chart::new(ChartType::Bar);
+        chart.add_series()
+            .set_categories(("Top 20", 1, 0, last, 0))
+            .set_values(("Top 20", 1, 1, last, 1));
+        chart.title().set_name("Most churned files");
+        chart.legend().set_hidden();
+        top.insert_chart(1, 3, &chart)?;
+    }
+
+    workbook.save(path)
+}
@@ -0,0 +1,715 @@
+// Run the analysis on small repositories built from scratch in a temporary
+// directory, through the library and through the `churn` command.
+
+extern crate churn;
+extern crate git2;
+extern crate tempfile;
+
+use churn::ChurnOptions;
+use git2::{Oid, Repository, Signature, Time};
+use std::collections::BTreeMap;
+use tempfile::TempDir;
+
+/// A new repository, and the time and author to give the next commit.
+struct TestRepo {
+    /// Holds the repository; deleted when the test is done.
+    dir: TempDir,
+    repo: Repository,
+    time: i64,
+    author: &'static str,
+}
+
+impl TestRepo {
+    fn new() -> TestRepo {
+        let dir = TempDir::new().unwrap();
+        let repo = Repository::init(dir.path()).unwrap();
+        TestRepo { dir, repo, time: 1_500_000_000, author: "Test" }
+    }
+
+    /// Commit a snapshot with these `(path, contents)` files on top of
+    /// `HEAD`.
+    fn commit(&mut self, files: &[(&str, &str)]) -> Oid {
+        let parents = match self.repo.head() {
+            Ok(head) => vec![head.target().unwrap()],
+            Err(_) => vec![],
+        };
+        let tree = write_tree(&self.repo, files);
+        self.commit_tree(tree, &parents, Some("HEAD"))
+    }
+
+    /// Commit `tree` with these parents, moving `update_ref` to it if given.
+    /// Each commit is a day after the one before.
+    fn commit_tree(&mut self, tree: Oid, parents: &[Oid], update_ref: Option<&str>) -> Oid {
+        self.time += 86400;
+        let signature = Signature::new(self.author, "test@example.com", &Time::new(self.time, 0))
+            .unwrap();
+        let tree = self.repo.find_tree(tree).unwrap();
+        let parents: Vec<_> = parents.iter().map(|&id| self.repo.find_commit(id).unwrap()).collect();
+        let parents: Vec<_> = parents.iter().collect();
+        self.repo.commit(update_ref, &signature, &signature, "test", &tree, &parents).unwrap()
+    }
+
+    fn head(&self) -> Oid {
+        self.repo.head().unwrap().target().unwrap()
+    }
+
+    /// Run the `churn` command on the repository with `args` and get what it
+    /// printed.
+    fn run(&self, args: &[&str]) -> String {
+        let output = std::process::Command::new(env!("CARGO_BIN_EXE_churn"))
+            .args(args)
+            .arg(self.dir.path())
+            .output()
+            .unwrap();
+        assert!(output.status.success(), "churn {:?} failed: {}", args,
+                String::from_utf8_lossy(&output.stdout));
+        String::from_utf8(output.stdout).unwrap()
+    }
+
+    /// Run the `churn` command like `run`, but expect it to fail, and get the
+    /// error it printed.
+    fn run_error(&self, args: &[&str]) -> String {
+        let output = std::process::Command::new(env!("CARGO_BIN_EXE_churn"))
+            .args(args)
+            .arg(self.dir.path())
+            .output()
+            .unwrap();
+        assert!(!output.status.success(), "churn {:?} succeeded", args);
+        String::from_utf8(output.stdout).unwrap()
+    }
+
+    /// Run the analysis from `HEAD` and get `(path, churn count)` for each
+    /// file, sorted by path.
+    fn churn(&self, options: &ChurnOptions) -> Vec<(String, usize)> {
+        let mut results: Vec<_> = churn::analyze_with_options(&self.repo, self.head(), options)
+            .unwrap()
+            .into_iter()
+            .map(|file| (file.path, file.churn_count))
+            .collect();
+        results.sort();
+        results
+    }
+}
+
+/// Write a tree holding these `(path, contents)` files. Paths can contain
+/// `/` to put a file in a subdirectory.
+fn write_tree(repo: &Repository, files: &[(&str, &str)]) -> Oid {
+    let mut dirs = BTreeMap::new();
+    let mut builder = repo.treebuilder(None).unwrap();
+    for &(path, contents) in files {
+        match path.find('/') {
+            Some(i) => dirs.entry(&path[..i]).or_insert_with(Vec::new)
+                .push((&path[i + 1..], contents)),
+            None => {
+                let blob = repo.blob(contents.as_bytes()).unwrap();
+                builder.insert(path, blob, 0o100644).unwrap();
+            }
+        }
+    }
+    for (name, files) in dirs {
+        let subtree = write_tree(repo, &files);
+        builder.insert(name, subtree, 0o040000).unwrap();
+    }
+    builder.write().unwrap()
+}
+
+fn options() -> ChurnOptions {
+    ChurnOptions::new().build().unwrap()
+}
+
+fn expected(files: &[(&str, usize)]) -> Vec<(String, usize)> {
+    files.iter().map(|&(path, count)| (path.to_string(), count)).collect()
+}
+
+#[test]
+fn single_file() {
+    let mut test = TestRepo::new();
+    test.commit(&[("README", "one")]);
+    test.commit(&[("README", "two")]);
+    test.commit(&[("README", "three")]);
+    assert_eq!(test.churn(&options()), expected(&[("README", 3)]));
+}
+
+#[test]
+fn unchanged_version_counts_once() {
+    let mut test = TestRepo::new();
+    test.commit(&[("README", "one")]);
+    test.commit(&[("README", "two")]);
+    test.commit(&[("README", "one")]);
+    assert_eq!(test.churn(&options()), expected(&[("README", 2)]));
+}
+
+#[test]
+fn multiple_files() {
+    let mut test = TestRepo::new();
+    test.commit(&[("a.txt", "one"), ("b.txt", "one")]);
+    test.commit(&[("a.txt", "two"), ("b.txt", "one")]);
+    test.commit(&[("a.txt", "three"), ("b.txt", "two"), ("c.txt", "one")]);
+    assert_eq!(test.churn(&options()),
+               expected(&[("a.txt", 3), ("b.txt", 2), ("c.txt", 1)]));
+}
+
+#[test]
+fn subdirectories() {
+    let mut test = TestRepo::new();
+    test.commit(&[("src/main.rs", "one"), ("src/util/mod.rs", "one"), ("README", "one")]);
+    test.commit(&[("src/main.rs", "two"), ("src/util/mod.rs", "one"), ("README", "one")]);
+    test.commit(&[("src/main.rs", "two"), ("src/util/mod.rs", "two"), ("README", "one")]);
+    assert_eq!(test.churn(&options()),
+               expected(&[("README", 1), ("src/main.rs", 2), ("src/util/mod.rs", 2)]));
+}
+
+#[test]
+fn rename() {
+    let mut test = TestRepo::new();
+    test.commit(&[("old.txt", "one")]);
+    test.commit(&[("old.txt", "two")]);
+    test.commit(&[("new.txt", "two")]);
+    assert_eq!(test.churn(&options()), expected(&[("new.txt", 1)]));
+
+    let with_deleted = ChurnOptions::new().include_deleted(true).build().unwrap();
+    assert_eq!(test.churn(&with_deleted), expected(&[("new.txt", 1), ("old.txt", 2)]));
+}
+
+#[test]
+fn merge_commit() {
+    let mut test = TestRepo::new();
+    let base = test.commit(&[("a.txt", "one")]);
+    // A side branch adds a file while the main branch changes another.
+    let side_tree = write_tree(&test.repo, &[("a.txt", "one"), ("b.txt", "one")]);
+    let side = test.commit_tree(side_tree, &[base], None);
+    let main = test.commit(&[("a.txt", "two")]);
+    let merged_tree = write_tree(&test.repo, &[("a.txt", "two"), ("b.txt", "one")]);
+    test.commit_tree(merged_tree, &[main, side], Some("HEAD"));
+    assert_eq!(test.churn(&options()), expected(&[("a.txt", 2), ("b.txt", 1)]));
+}
+
+#[test]
+fn initial_empty_commit() {
+    let mut test = TestRepo::new();
+    test.commit(&[]);
+    test.commit(&[("a.txt", "one")]);
+    test.commit(&[("a.txt", "two")]);
+    assert_eq!(test.churn(&options()), expected(&[("a.txt", 2)]));
+}
+
+#[test]
+fn no_files() {
+    let mut test = TestRepo::new();
+    test.commit(&[]);
+    test.commit(&[]);
+    assert_eq!(test.churn(&options()), expected(&[]));
+}
+
+#[test]
+fn shallow_repository() {
+    let mut test = TestRepo::new();
+    let first = test.commit(&[("a.txt", "one")]);
+    let second = test.commit(&[("a.txt", "two")]);
+    test.commit(&[("a.txt", "three")]);
+
+    // Make it look like a shallow clone that starts at the second commit:
+    // list it in `.git/shallow` and remove the first commit's object.
+    let git_dir = test.repo.path().to_path_buf();
+    std::fs::write(git_dir.join("shallow"), format!("{}\n", second)).unwrap();
+    let hex = first.to_string();
+    std::fs::remove_file(git_dir.join("objects").join(&hex[..2]).join(&hex[2..])).unwrap();
+
+    let test = TestRepo { repo: Repository::open(&git_dir).unwrap(), ..test };
+    assert!(test.repo.is_shallow());
+    assert_eq!(test.churn(&options()), expected(&[("a.txt", 2)]));
+}
+
+#[test]
+fn non_utf8_file_name() {
+    let mut test = TestRepo::new();
+    let tree = {
+        let mut builder = test.repo.treebuilder(None).unwrap();
+        let blob = test.repo.blob(b"one").unwrap();
+        builder.insert(b"caf\xe9.txt".to_vec(), blob, 0o100644).unwrap();
+        builder.write().unwrap()
+    };
+    test.commit_tree(tree, &[], Some("HEAD"));
+    assert_eq!(test.churn(&options()), expected(&[("caf\u{fffd}.txt", 1)]));
+}
+
+#[test]
+fn graft_file() {
+    let mut test = TestRepo::new();
+    test.commit(&[("a.txt", "one")]);
+    let second = test.commit(&[("a.txt", "two")]);
+    test.commit(&[("a.txt", "three")]);
+
+    // Graft the second commit to have no parents, cutting off the first.
+    let path = test.dir.path().join("grafts");
+    std::fs::write(&path, format!("{}\n", second)).unwrap();
+    let options = ChurnOptions::new().graft_file(&path).build().unwrap();
+    assert_eq!(test.churn(&options), expected(&[("a.txt", 2)]));
+}
+
+#[test]
+fn ignore_commits_file() {
+    let mut test = TestRepo::new();
+    test.commit(&[("a.txt", "one")]);
+    let second = test.commit(&[("a.txt", "two"), ("b.txt", "one")]);
+    test.commit(&[("a.txt", "three"), ("b.txt", "one")]);
+    let path = test.dir.path().join("ignore-commits");
+    std::fs::write(&path, format!("# the big reformat\n{}  # again\n\n", second)).unwrap();
+    let options = ChurnOptions::new().ignore_commits_file(&path).build().unwrap();
+    assert_eq!(test.churn(&options), expected(&[("a.txt", 2), ("b.txt", 1)]));
+
+    std::fs::write(&path, format!("{}\n", &second.to_string()[..7])).unwrap();
+    assert!(ChurnOptions::new().ignore_commits_file(&path).build().is_err());
+}
+
+#[test]
+fn min_authors() {
+    let mut test = TestRepo::new();
+    test.author = "Ann";
+    test.commit(&[("a.txt", "one"), ("b.txt", "one")]);
+    test.author = "Bob";
+    test.commit(&[("a.txt", "two"), ("b.txt", "one")]);
+    let options = ChurnOptions::new().min_authors(2).build().unwrap();
+    assert_eq!(test.churn(&options), expected(&[("a.txt", 2)]));
+}
+
+#[test]
+fn author_email() {
+    let mut test = TestRepo::new();
+    test.commit(&[("a.txt", "one")]);
+    test.commit(&[("a.txt", "two")]);
+    let part = ChurnOptions::new().author_email("example.com").build().unwrap();
+    assert_eq!(test.churn(&part), expected(&[("a.txt", 2)]));
+    let exact = ChurnOptions::new().author_email("example.com").author_email_exact(true)
+        .build().unwrap();
+    assert_eq!(test.churn(&exact), expected(&[]));
+    let both = ChurnOptions::new().author("Test").author_email("test@example.com")
+        .author_email_exact(true).build().unwrap();
+    assert_eq!(test.churn(&both), expected(&[("a.txt", 2)]));
+}
+
+#[test]
+fn min_lines() {
+    let mut test = TestRepo::new();
+    test.commit(&[("a.txt", "one\ntwo\nthree"), ("b.txt", "one\n")]);
+    test.commit(&[("a.txt", "one\ntwo"), ("b.txt", "one\ntwo\n")]);
+    let options = ChurnOptions::new().min_lines(2).build().unwrap();
+    assert_eq!(test.churn(&options), expected(&[("a.txt", 2), ("b.txt", 2)]));
+    let options = ChurnOptions::new().min_lines(3).build().unwrap();
+    assert_eq!(test.churn(&options), expected(&[]));
+}
+
+#[test]
+fn interesting_files() {
+    let mut test = TestRepo::new();
+    test.commit(&[("a.txt", "one"), ("src/b.txt", "one"), ("src/c.txt", "one")]);
+    test.commit(&[("a.txt", "two"), ("src/b.txt", "two"), ("src/c.txt", "two")]);
+    let options = ChurnOptions::new().interesting_files(vec!["src/b.txt"]).build().unwrap();
+    assert_eq!(test.churn(&options), expected(&[("src/b.txt", 2)]));
+
+    let nothing = ChurnOptions::new().interesting_files(Vec::<String>::new()).build().unwrap();
+    assert_eq!(test.churn(&nothing), expected(&[]));
+}
+
+#[test]
+fn skip_errors() {
+    let mut test = TestRepo::new();
+    let first = test.commit(&[("README", "one"), ("src/a.txt", "one")]);
+    test.commit(&[("README", "two"), ("src/a.txt", "two")]);
+
+    // Remove the first commit's `src` tree, as if the repository were corrupt.
+    let tree = test.repo.find_commit(first).unwrap().tree().unwrap();
+    let hex = tree.get_path(std::path::Path::new("src")).unwrap().id().to_string();
+    let objects = test.repo.path().join("objects");
+    std::fs::remove_file(objects.join(&hex[..2]).join(&hex[2..])).unwrap();
+
+    assert!(churn::analyze_with_options(&test.repo, test.head(), &options()).is_err());
+    let options = ChurnOptions::new().skip_errors(true).build().unwrap();
+    assert_eq!(test.churn(&options), expected(&[("README", 2), ("src/a.txt", 1)]));
+}
+
+#[test]
+fn origin() {
+    let mut test = TestRepo::new();
+    let first = test.commit(&[("a.txt", "one")]);
+    let second = test.commit(&[("a.txt", "two"), ("b.txt", "one")]);
+    test.commit(&[("a.txt", "three"), ("b.txt", "two")]);
+    let options = ChurnOptions::new().track_origin(true).build().unwrap();
+    let mut origins: Vec<_> = churn::analyze_with_options(&test.repo, test.head(), &options)
+        .unwrap()
+        .into_iter()
+        .map(|file| (file.path, file.origin.unwrap().0))
+        .collect();
+    origins.sort();
+    assert_eq!(origins, vec![("a.txt".to_string(), first), ("b.txt".to_string(), second)]);
+}
+
+#[test]
+fn version_commits() {
+    let mut test = TestRepo::new();
+    let first = test.commit(&[("a.txt", "one")]);
+    test.commit(&[("a.txt", "one"), ("b.txt", "one")]);
+    let third = test.commit(&[("a.txt", "two"), ("b.txt", "one")]);
+    let options = ChurnOptions::new().track_version_commits(true).build().unwrap();
+    let file = churn::analyze_with_options(&test.repo, test.head(), &options)
+        .unwrap()
+        .into_iter()
+        .find(|file| file.path == "a.txt")
+        .unwrap();
+    assert_eq!(file.version_commits, vec![first, third]);
+}
+
+#[test]
+fn skip_generated() {
+    let mut test = TestRepo::new();
+    let attributes = "*.pb.go linguist-generated=true\nvendor/** linguist-vendored\n";
+    test.commit(&[(".gitattributes", attributes), ("api.pb.go", "one"), ("main.go", "one"),
+                  ("vendor/lib.go", "one")]);
+    let options = ChurnOptions::new().skip_generated(true).build().unwrap();
+    assert_eq!(test.churn(&options), expected(&[(".gitattributes", 1), ("main.go", 1)]));
+}
+
+#[test]
+fn skip_first() {
+    let mut test = TestRepo::new();
+    test.commit(&[("a.txt", "one")]);
+    test.commit(&[("a.txt", "two"), ("b.txt", "one")]);
+    test.commit(&[("a.txt", "three"), ("b.txt", "one")]);
+    test.commit(&[("a.txt", "four"), ("b.txt", "two")]);
+    // The middle two commits: the newest is skipped, and the oldest is past
+    // the window.
+    let options = ChurnOptions::new().skip_first(1).since_n_commits(2).build().unwrap();
+    assert_eq!(test.churn(&options), expected(&[("a.txt", 2), ("b.txt", 1)]));
+}
+
+#[test]
+fn extra_starts() {
+    let mut test = TestRepo::new();
+    let base = test.commit(&[("a.txt", "one")]);
+    // A branch that was never merged.
+    let side_tree = write_tree(&test.repo, &[("a.txt", "three")]);
+    let side = test.commit_tree(side_tree, &[base], None);
+    test.commit(&[("a.txt", "two")]);
+    let options = options().with_extra_starts(vec![side]);
+    assert_eq!(test.churn(&options), expected(&[("a.txt", 3)]));
+    let options = ChurnOptions::new().max_commits_per_start(1).build().unwrap()
+        .with_extra_starts(vec![side]);
+    assert_eq!(test.churn(&options), expected(&[("a.txt", 2)]));
+}
+
+#[test]
+fn walk_in_parallel() {
+    let mut test = TestRepo::new();
+    let base = test.commit(&[("a.txt", "one"), ("src/b.rs", "one")]);
+    let mut tips = vec![];
+    for (i, name) in ["x", "y", "z"].iter().enumerate() {
+        let tree = write_tree(&test.repo, &[("a.txt", name), ("src/b.rs", "one"),
+                                            (&format!("src/{}.rs", name), "one")]);
+        let tip = test.commit_tree(tree, &[base], None);
+        let tree = write_tree(&test.repo, &[("a.txt", name), ("src/b.rs", &i.to_string())]);
+        tips.push(test.commit_tree(tree, &[tip], None));
+    }
+    test.commit(&[("a.txt", "two"), ("src/b.rs", "two")]);
+    let options = ChurnOptions::new().include_deleted(true).threads(4).build().unwrap()
+        .with_extra_starts(tips);
+    assert!(options.walks_in_parallel());
+
+    let commit_ids = churn::find_commits(&test.repo, test.head(), &options).unwrap();
+    let mut root_dir = churn::DirData::new();
+    for &commit_id in &commit_ids {
+        let commit = test.repo.find_commit(commit_id).unwrap();
+        root_dir.update_for_commit(&test.repo, &commit, &options).unwrap();
+    }
+    let results = |root_dir: churn::DirData| {
+        let mut results: Vec<_> = root_dir.results(&test.repo, test.head(), &options).unwrap()
+            .into_iter()
+            .map(|file| (file.path, file.churn_count, file.deleted))
+            .collect();
+        results.sort();
+        results
+    };
+    let parallel = churn::walk_in_parallel(&test.repo, test.head(), &commit_ids, &options)
+        .unwrap();
+    assert_eq!(results(parallel), results(root_dir));
+    let one_thread = ChurnOptions::new().threads(1).build().unwrap().with_extra_starts(vec![base]);
+    assert!(!one_thread.walks_in_parallel());
+    assert_eq!(test.churn(&options), expected(&[("a.txt", 5), ("src/b.rs", 5), ("src/x.rs", 1),
+                                                  ("src/y.rs", 1), ("src/z.rs", 1)]));
+}
+
+#[test]
+fn only_path() {
+    let mut test = TestRepo::new();
+    test.commit(&[("src/a.rs", "one"), ("src2/b.rs", "one"), ("c.txt", "one")]);
+    test.commit(&[("src/a.rs", "two"), ("src2/b.rs", "one"), ("c.txt", "one")]);
+    let options = ChurnOptions::new().only_path("src/").only_path("c.txt").build().unwrap();
+    assert_eq!(test.churn(&options), expected(&[("c.txt", 1), ("src/a.rs", 2)]));
+}
+
+#[test]
+fn skip_extensions() {
+    let mut test = TestRepo::new();
+    test.commit(&[("logo.PNG", "one"), ("a.rs", "one"), (".so", "one")]);
+    let options = ChurnOptions::new().skip_extensions(churn::BINARY_EXTENSIONS).build().unwrap();
+    assert_eq!(test.churn(&options), expected(&[(".so", 1), ("a.rs", 1)]));
+}
+
+#[test]
+fn only_commits() {
+    let mut test = TestRepo::new();
+    let first = test.commit(&[("a.txt", "one"), ("b.txt", "one")]);
+    test.commit(&[("a.txt", "two"), ("b.txt", "one")]);
+    let third = test.commit(&[("a.txt", "three"), ("b.txt", "two")]);
+    let options = options().with_only_commits(vec![first, third].into_iter().collect());
+    assert_eq!(test.churn(&options), expected(&[("a.txt", 2), ("b.txt", 2)]));
+}
+
+#[test]
+fn ignore_path_case() {
+    let mut test = TestRepo::new();
+    test.commit(&[("src/Main.rs", "one")]);
+    test.commit(&[("SRC/main.rs", "two")]);
+    let options = ChurnOptions::new().ignore_path_case(true).build().unwrap();
+    assert_eq!(test.churn(&options), expected(&[("src/main.rs", 2)]));
+}
+
+#[test]
+fn merge() {
+    let mut test = TestRepo::new();
+    let first = test.commit(&[("a.txt", "one"), ("b.txt", "one")]);
+    let second = test.commit(&[("a.txt", "two"), ("b.txt", "one"), ("c/d.txt", "one")]);
+    let options = options();
+    let mut older = churn::DirData::new();
+    older.update_for_commit(&test.repo, &test.repo.find_commit(first).unwrap(), &options)
+        .unwrap();
+    let mut newer = churn::DirData::new();
+    newer.update_for_commit(&test.repo, &test.repo.find_commit(second).unwrap(), &options)
+        .unwrap();
+    older.merge(newer);
+    let mut results: Vec<_> = older.results(&test.repo, test.head(), &options).unwrap()
+        .into_iter()
+        .map(|file| (file.path, file.churn_count))
+        .collect();
+    results.sort();
+    assert_eq!(results, expected(&[("a.txt", 2), ("b.txt", 1), ("c/d.txt", 1)]));
+}
+
+#[test]
+fn dir_results() {
+    let mut test = TestRepo::new();
+    test.commit(&[("src/a.txt", "one"), ("docs/b.txt", "one")]);
+    test.commit(&[("src/a.txt", "two"), ("docs/b.txt", "one")]);
+    test.commit(&[("src/a.txt", "three")]);
+    let mut walk = test.repo.revwalk().unwrap();
+    walk.push_head().unwrap();
+    let mut root = churn::DirData::new();
+    for oid in walk {
+        let commit = test.repo.find_commit(oid.unwrap()).unwrap();
+        root.update_for_commit(&test.repo, &commit, &options()).unwrap();
+    }
+    let dirs: Vec<_> = root.dir_results(&test.repo, test.head()).unwrap()
+        .into_iter()
+        .map(|dir| (dir.path, dir.churn_count))
+        .collect();
+    assert_eq!(dirs, expected(&[("src/", 3)]));
+}
+
+#[test]
+fn merged_branch_commits() {
+    let mut test = TestRepo::new();
+    let base = test.commit(&[("a.txt", "one"), ("lock.txt", "one")]);
+    let tree = write_tree(&test.repo, &[("a.txt", "one"), ("lock.txt", "two")]);
+    let bump = test.commit_tree(tree, &[base], Some("refs/heads/dependabot/npm/lodash"));
+    let main = test.commit(&[("a.txt", "two"), ("lock.txt", "one")]);
+    let tree = write_tree(&test.repo, &[("a.txt", "two"), ("lock.txt", "two")]);
+    test.commit_tree(tree, &[main, bump], Some("HEAD"));
+    assert_eq!(test.churn(&options()), expected(&[("a.txt", 2), ("lock.txt", 2)]));
+
+    let skipped = churn::merged_branch_commits(&test.repo, test.head(), "dependabot/*").unwrap();
+    assert_eq!(skipped.len(), 2);
+    let options = options().with_skipped_commits(skipped);
+    assert_eq!(test.churn(&options), expected(&[("a.txt", 2), ("lock.txt", 1)]));
+}
+
+#[test]
+fn code_owners() {
+    let mut test = TestRepo::new();
+    test.commit(&[("README", "one")]);
+    assert!(churn::code_owners(&test.repo, test.head()).unwrap().is_none());
+
+    let rules = "* @org/everyone\n/src/ @org/core # the library\nsrc/vendor/\n*.md @docs\n";
+    test.commit(&[(".github/CODEOWNERS", rules), ("CODEOWNERS", "* @ignored")]);
+    let owners = churn::code_owners(&test.repo, test.head()).unwrap().unwrap();
+    assert_eq!(owners.owners("README").unwrap(), ["@org/everyone"]);
+    assert_eq!(owners.owners("src/lib.rs").unwrap(), ["@org/core"]);
+    assert_eq!(owners.owners("src/vendor/zlib.c").unwrap(), [] as [String; 0]);
+    assert_eq!(owners.owners("src/guide.md").unwrap(), ["@docs"]);
+}
+
+#[test]
+fn churn_result() {
+    let mut test = TestRepo::new();
+    test.commit(&[("a.txt", "one"), ("b.txt", "one")]);
+    test.commit(&[("a.txt", "two"), ("b.txt", "one")]);
+    test.commit(&[("a.txt", "three"), ("b.txt", "two")]);
+    let result = churn::analyze_with_options(&test.repo, test.head(), &options()).unwrap();
+    assert_eq!(result.len(), 2);
+    assert_eq!(result.total(), 5);
+    assert_eq!(result.max().unwrap().path, "a.txt");
+    assert_eq!(result[0].path, "a.txt");
+    let paths: Vec<_> = result.filter_min_churn(3).into_iter().map(|file| file.path).collect();
+    assert_eq!(paths, ["a.txt"]);
+}
+
+#[test]
+fn detect_copies() {
+    let mut test = TestRepo::new();
+    test.commit(&[("lib.c", "one"), ("old.c", "x")]);
+    test.commit(&[("lib.c", "two"), ("old.c", "x")]);
+    // A copy of lib.c's latest version, and old.c moved rather than copied.
+    test.commit(&[("lib.c", "two"), ("vendor/lib.c", "two"), ("new.c", "x")]);
+    let options = ChurnOptions::new().detect_copies(true).build().unwrap();
+    let mut copies: Vec<_> = churn::analyze_with_options(&test.repo, test.head(), &options)
+        .unwrap()
+        .into_iter()
+        .map(|file| (file.path, file.copy_of))
+        .collect();
+    copies.sort();
+    assert_eq!(copies, vec![("lib.c".to_string(), None), ("new.c".to_string(), None),
+                            ("vendor/lib.c".to_string(), Some("lib.c".to_string()))]);
+}
+
+#[test]
+fn separate_git_dir() {
+    let dir = TempDir::new().unwrap();
+    let repo = Repository::init_bare(dir.path().join("repo.git")).unwrap();
+    let mut test = TestRepo { dir, repo, time: 1_500_000_000, author: "Test" };
+    test.commit(&[("a.txt", "one")]);
+    test.commit(&[("a.txt", "two")]);
+
+    let git_dir = test.repo.path().to_path_buf();
+    let work_tree = test.dir.path().join("src");
+    std::fs::create_dir(&work_tree).unwrap();
+    let repo = churn::open_git_dir(&git_dir, Some(&work_tree)).unwrap();
+    assert_eq!(repo.workdir().unwrap().canonicalize().unwrap(),
+               work_tree.canonicalize().unwrap());
+    let test = TestRepo { repo, ..test };
+    assert_eq!(test.churn(&options()), expected(&[("a.txt", 2)]));
+}
+
+#[test]
+fn csv_quoting() {
+    let mut test = TestRepo::new();
+    test.commit(&[("a,b.txt", "one"), ("say \"hi\".txt", "one"), ("tab\there.txt", "one")]);
+    test.commit(&[("a,b.txt", "two"), ("say \"hi\".txt", "one"), ("tab\there.txt", "one")]);
+    assert_eq!(test.run(&["--format", "csv"]), "path,churn,deleted\n\
+                                                \"a,b.txt\",2,false\n\
+                                                \"say \"\"hi\"\".txt\",1,false\n\
+                                                tab\there.txt,1,false\n");
+    assert_eq!(test.run(&["--format", "tsv", "--no-header"]), "a,b.txt\t2\tfalse\n\
+                                                               \"say \"\"hi\"\".txt\"\t1\tfalse\n\
+                                                               \"tab\there.txt\"\t1\tfalse\n");
+}
+
+#[test]
+fn google_sheets_dates() {
+    let mut test = TestRepo::new();
+    test.commit(&[("a.txt", "one")]);
+    test.commit(&[("a.txt", "two")]);
+    // Month first, and the booleans in capitals.
+    assert_eq!(test.run(&["--format", "google-sheets", "--with-dates"]),
+               "path,churn,first_seen,last_seen,deleted\n\
+                a.txt,2,07/15/2017,07/16/2017,FALSE\n");
+}
+
+#[test]
+fn output_split_by_dir() {
+    let mut test = TestRepo::new();
+    test.commit(&[("README", "one"), ("src/a.rs", "one"), ("docs/b.md", "one")]);
+    test.commit(&[("README", "one"), ("src/a.rs", "two"), ("docs/b.md", "one")]);
+    let out = TempDir::new().unwrap();
+    let out_dir = out.path().join("split");
+    test.run(&["--format", "csv", "--output-split-by-dir", out_dir.to_str().unwrap()]);
+    let read = |name: &str| std::fs::read_to_string(out_dir.join(name)).unwrap();
+    assert_eq!(read("src.csv"), "path,churn,deleted\nsrc/a.rs,2,false\n");
+    assert_eq!(read("docs.csv"), "path,churn,deleted\ndocs/b.md,1,false\n");
+    assert_eq!(read("ROOT.csv"), "path,churn,deleted\nREADME,1,false\n");
+    assert_eq!(std::fs::read_dir(&out_dir).unwrap().count(), 3);
+}
+
+#[test]
+fn histogram() {
+    let mut test = TestRepo::new();
+    test.commit(&[("a.txt", "one"), ("b.txt", "one")]);
+    test.commit(&[("a.txt", "two"), ("b.txt", "one")]);
+    test.commit(&[("a.txt", "three"), ("b.txt", "one")]);
+    test.commit(&[("a.txt", "four"), ("b.txt", "two")]);
+    assert_eq!(test.run(&["--histogram", "--width", "30"]),
+               "a.txt |████████████████████| 4\n\
+                b.txt |██████████| 2\n");
+}
+
+#[test]
+fn distribution() {
+    let mut test = TestRepo::new();
+    test.commit(&[("a.txt", "one"), ("b.txt", "one"), ("c.txt", "one")]);
+    test.commit(&[("a.txt", "two"), ("b.txt", "two"), ("c.txt", "one")]);
+    assert_eq!(test.run(&["--distribution"]),
+               "1, 1\n2, 2\n3, 0\n4, 0\n5, 0\n6-10, 0\n11-20, 0\n21-50, 0\n51+, 0\n");
+}
+
+#[test]
+fn summary_only_with_threads() {
+    let mut test = TestRepo::new();
+    let base = test.commit(&[("a.txt", "one"), ("b.txt", "one")]);
+    let tree = write_tree(&test.repo, &[("a.txt", "two"), ("b.txt", "one")]);
+    test.commit_tree(tree, &[base], Some("refs/heads/x"));
+    let tree = write_tree(&test.repo, &[("a.txt", "one"), ("b.txt", "two")]);
+    test.commit_tree(tree, &[base], Some("refs/heads/y"));
+    assert_eq!(test.run(&["--ref", "refs/heads/*", "--num-threads", "2", "--summary-only"]),
+               "Commits analyzed: 3\n\
+                Date range:       2017-07-15 to 2017-07-17\n\
+                Files analyzed:   2\n\
+                Total churn:      4\n\
+                Hottest file:     a.txt (2)\n");
+}
+
+#[test]
+fn show_unchanged() {
+    let mut test = TestRepo::new();
+    test.commit(&[("a.txt", "one"), ("b.txt", "one")]);
+    test.commit(&[("a.txt", "two"), ("b.txt", "one")]);
+    assert_eq!(test.run(&["--show-unchanged"]), "      1 b.txt\n");
+    assert_eq!(test.run(&["--show-unchanged", "--min-churn", "1"]), "      1 b.txt\n");
+    assert_eq!(test.run_error(&["--show-unchanged", "--only-new"]),
+               "error: --show-unchanged and --only-new can't be used together\n");
+    assert_eq!(test.run_error(&["--show-unchanged", "--min-churn", "3"]),
+               "error: --show-unchanged only lists files with one version, so --min-churn \
+                can't be more than 1\n");
+    assert_eq!(test.run_error(&["--show-unchanged", "--max-churn", "0"]),
+               "error: --show-unchanged only lists files with one version, so --max-churn \
+                can't be less than 1\n");
+}
+
+#[test]
+fn flag_untested() {
+    let mut test = TestRepo::new();
+    test.commit(&[("src/lexer.rs", "one"), ("src/parser.rs", "one"),
+                  ("tests/parser_test.rs", "one")]);
+    test.commit(&[("src/lexer.rs", "two"), ("src/parser.rs", "two"),
+                  ("tests/parser_test.rs", "one")]);
+    assert_eq!(test.run(&["--flag-untested", "--source-pattern", "src/**"]),
+               "[NO TEST] src/lexer.rs, churn=2\n");
+}
+
+#[test]
+fn test_source_ratio() {
+    let mut test = TestRepo::new();
+    test.commit(&[("src/lexer.rs", "one"), ("src/parser.rs", "one"),
+                  ("tests/parser_test.rs", "one")]);
+    test.commit(&[("src/lexer.rs", "two"), ("src/parser.rs", "two"),
+                  ("tests/parser_test.rs", "one")]);
+    assert_eq!(test.run(&["--test-source-ratio", "--source-pattern", "src/**"]),
+               "src/lexer.rs, 2, , 0, no-test\n\
+                src/parser.rs, 2, tests/parser_test.rs, 1, 2.00\n");
+}